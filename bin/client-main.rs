@@ -1,88 +1,553 @@
-use std::rc::Rc;
-use std::time::Duration;
-use std::thread::sleep;
+use std::cmp;
+use std::env;
+use std::sync::Arc;
+use std::process;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use std::os::unix::io::RawFd;
 
 #[macro_use]
 extern crate log;
+use log::LogLevelFilter;
 extern crate env_logger;
 
+extern crate byteorder;
+use byteorder::{BigEndian, ByteOrder};
+
 extern crate mio;
 use mio::*;
 use mio::unix::EventedFd;
-//use mio::tcp::{TcpListener};
+use mio::tcp::{TcpListener, TcpStream};
 
 extern crate nix;
 use nix::libc;
 use nix::unistd;
 
 extern crate icmp_communicator;
-use icmp_communicator::IcmpCommunicator;
+use icmp_communicator::{EchoRole, ICError, IcmpCommunicator, InetAddr};
 
 extern crate icmp_tunnel;
 use icmp_tunnel::odp::ODP;
 use icmp_tunnel::odp::ODPError;
 use icmp_tunnel::privs;
+use icmp_tunnel::signals;
+use icmp_tunnel::crc32::Crc32;
 
 static STDIN: RawFd = libc::STDIN_FILENO;
 
 const SERV: Token = Token(0);
 const ICMP: Token = Token(1);
+const TCP:  Token = Token(2);
+
+const USAGE: &str = "usage: client [--id ID] [--peer HOST] [--prefer-v6] [--stats]
+              [--bufsize BYTES] [--probe COUNT] [--idle-timeout SECS] [--max-lifetime SECS]
+              [--local ADDR:PORT | --send FILE | --infile FILE]
+
+Send stdin to the server, or the content of FILE with --infile. HOST is an address or a name,
+whose addresses are tried in turn, IPv4 first unless --prefer-v6. With --local, tunnel a TCP
+connection accepted on ADDR:PORT instead, with --send, send FILE to `server --recv`. With
+--stats, print traffic counters on exit. --bufsize sets how much is read from the source of the
+data at once, by default enough to fill the window. With --probe, measure the loss and RTT of the
+path with COUNT probes first, and try the next address of HOST if it looks unusable.
+--idle-timeout and --max-lifetime close the tunnel once no data went through it for SECS, or once
+it lasted for SECS.";
+
+// Loss from which `probe` warns that the path is unusable, in percent
+const PROBE_MAX_LOSS: f64 = 50.;
+
+// Largest read from the source of the data by default, see `default_bufsize`
+const BUFSIZE_MAX: usize = 1 << 20;
+
+struct Args {
+    id:      u8,
+    peer:    String,
+    prefer6: bool,
+    local:   Option<SocketAddr>,
+    send:    Option<PathBuf>,
+    infile:  Option<PathBuf>,
+    stats:   bool,
+    bufsize: Option<usize>,
+    probe:   usize,
+    idle:    Option<Duration>,
+    life:    Option<Duration>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// Log to stderr at the levels set by RUST_LOG, or else from `info`. Logging may already be set
+// up, e.g. when this code runs under a harness: that logger is kept.
+fn init_logging() {
+    let mut builder = env_logger::LogBuilder::new();
+    match env::var("RUST_LOG") {
+        Ok(filters) => { builder.parse(&filters); }
+        Err(_)      => { builder.filter(None, LogLevelFilter::Info); }
+    }
+    builder.init().ok();
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        id:      1,
+        peer:    "127.0.0.1".to_string(),
+        prefer6: false,
+        local:   None,
+        send:    None,
+        infile:  None,
+        stats:   false,
+        bufsize: None,
+        probe:   0,
+        idle:    None,
+        life:    None,
+    };
+
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let value = match arg.as_str() {
+            "--stats"     => { args.stats   = true; continue; }
+            "--prefer-v6" => { args.prefer6 = true; continue; }
+            "--id" | "--peer" | "--local" | "--send" | "--infile" | "--bufsize" | "--probe" |
+            "--idle-timeout" | "--max-lifetime" => argv.next().unwrap_or_else(|| usage()),
+            _ => usage(),
+        };
+        match arg.as_str() {
+            "--id"     => args.id     = value.parse().unwrap_or_else(|_| usage()),
+            "--peer"   => args.peer   = value,
+            "--local"  => args.local  = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--send"   => args.send   = Some(PathBuf::from(value)),
+            "--infile" => args.infile = Some(PathBuf::from(value)),
+            "--probe"  => args.probe  = value.parse().unwrap_or_else(|_| usage()),
+            "--idle-timeout" => args.idle = Some(parse_secs(&value)),
+            "--max-lifetime" => args.life = Some(parse_secs(&value)),
+            _          => {
+                let size = value.parse().ok().filter(|&size| size > 0);
+                args.bufsize = Some(size.unwrap_or_else(|| usage()));
+            }
+        }
+    }
+    let modes = [args.local.is_some(), args.send.is_some(), args.infile.is_some()];
+    if modes.iter().filter(|&&m| m).count() > 1 {
+        usage();
+    }
+    args
+}
+
+fn parse_secs(value: &str) -> Duration {
+    Duration::from_secs(value.parse().unwrap_or_else(|_| usage()))
+}
 
 fn main() {
-    let com = Rc::new(IcmpCommunicator::new(1).unwrap());
-    privs::drop_privs();
+    let args = parse_args();
+
+    init_logging();
+
+    // one communicator per address family, opened while we still may
+    let addrs = resolve(&args.peer, args.prefer6);
+    let open  = |v6: bool| Arc::new(open_communicator(args.id, v6));
+    let v4    = addrs.iter().any(|ip| ip.is_ipv4()).then(|| open(false));
+    let v6    = addrs.iter().any(|ip| ip.is_ipv6()).then(|| open(true));
+    #[cfg(all(feature = "caps", target_os = "linux"))]
+    privs::drop_net_raw().expect("Could not drop privileges");
+    #[cfg(not(all(feature = "caps", target_os = "linux")))]
+    privs::drop_privs().expect("Could not drop privileges");
+
+    let (mut odp, com) = connect_any(&args, &addrs, v4, v6);
+    signals::catch_termination().expect("Could not catch signals");
+
+    let bufsize = args.bufsize.unwrap_or_else(|| default_bufsize(&odp));
+    let start   = Instant::now();
+    match (args.local, args.send, args.infile) {
+        (Some(local), _, _) => relay(&mut odp, &local, bufsize),
+        (_, Some(path), _)  => send_file(&mut odp, &path, bufsize),
+        (_, _, Some(path))  => send_raw(&mut odp, &path, bufsize),
+        _                   => pipe_stdin(&mut odp, bufsize),
+    }
+    if args.stats {
+        print_stats(&odp, &com, start);
+    }
+}
+
+// Print the counters of the connection and of the communicator, see --stats
+fn print_stats(odp: &ODP, com: &IcmpCommunicator, start: Instant) {
+    let stats = odp.stats();
+    let comm  = com.stats();
+    let rtt   = odp.rtt_estimate()
+        .map_or("unknown".to_string(), |rtt| format!("{:.1}ms", rtt.as_secs_f64() * 1000.));
+    eprintln!("{} bytes sent, {} received in {:.2}s",
+              stats.bytes_sent, stats.bytes_received, start.elapsed().as_secs_f64());
+    eprintln!("{} packets sent, {} received, {} dropped, {} retransmits, RTT {}",
+              comm.packets_sent, comm.packets_received, comm.packets_dropped, stats.retransmits,
+              rtt);
+}
+
+// The addresses of `host`, of the preferred family first
+fn resolve(host: &str, prefer6: bool) -> Vec<IpAddr> {
+    let found = (host, 0).to_socket_addrs().unwrap_or_else(|e| {
+        error!("Could not resolve {}: {}", host, e);
+        process::exit(1);
+    });
+    let mut addrs: Vec<IpAddr> = Vec::new();
+    for ip in found.map(|addr| addr.ip()) {
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+    if addrs.is_empty() {
+        error!("Could not resolve {}: no address", host);
+        process::exit(1);
+    }
+    addrs.sort_by_key(|ip| ip.is_ipv6() != prefer6);
+    addrs
+}
+
+// Connect to the first of `addrs` that answers, and whose path is usable if --probe is given
+fn connect_any(args: &Args, addrs: &[IpAddr], v4: Option<Arc<IcmpCommunicator>>,
+               v6: Option<Arc<IcmpCommunicator>>) -> (ODP, Arc<IcmpCommunicator>) {
+    for (i, &ip) in addrs.iter().enumerate() {
+        let com = if ip.is_ipv4() { &v4 } else { &v6 };
+        let com = com.clone().unwrap();
+        let mut odp = ODP::new(com.clone(), InetAddr::from_std(&SocketAddr::new(ip, 0)));
+        odp.set_connection_id(true);
+        odp.set_message_crc(true);
+        odp.set_idle_timeout(args.idle);
+        odp.set_max_lifetime(args.life);
+        if let Err(e) = odp.connect() {
+            warn!("Could not connect to {}: {}", ip, e);
+            continue;
+        }
+        debug!("connected to {}", ip);
+        if args.probe > 0 && !probe(&mut odp, args.probe) && i + 1 < addrs.len() {
+            info!("Trying the next address");
+            if let Err(e) = odp.close() {
+                debug!("close: {:?}", e);
+            }
+            continue;
+        }
+        return (odp, com);
+    }
+    error!("Could not connect to the server");
+    process::exit(1);
+}
+
+// Report the loss and RTT of the path, see --probe. Return false if it looks unusable.
+fn probe(odp: &mut ODP, count: usize) -> bool {
+    let stats = odp.probe(count).unwrap_or_else(|e| fail("Probe failed", e.into()));
+    let ms    = |rtt: Option<Duration>| rtt.map_or(0., |rtt| rtt.as_secs_f64() * 1000.);
+    info!("{} probes, {:.0}% loss, rtt min/median/max {:.1}/{:.1}/{:.1}ms",
+          stats.sent, stats.loss(), ms(stats.min_rtt()), ms(stats.median_rtt()),
+          ms(stats.max_rtt()));
+    if stats.loss() >= PROBE_MAX_LOSS {
+        warn!("The path to the server looks unusable");
+        return false;
+    }
+    true
+}
+
+fn peer_unreachable() -> ! {
+    error!("Peer unreachable");
+    process::exit(1);
+}
+
+fn session_expired() -> ! {
+    warn!("Session expired, the tunnel is closed");
+    process::exit(1);
+}
+
+// Retransmit what needs to be, give up if the server is gone
+fn on_timeout(odp: &mut ODP) {
+    match odp.on_timeout(Instant::now()) {
+        Ok(_) => {}
+        Err(ODPError::ConnectionLost) => peer_unreachable(),
+        Err(ODPError::SessionExpired) => session_expired(),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+fn open_communicator(id: u8, v6: bool) -> IcmpCommunicator {
+    let com = if v6 { IcmpCommunicator::new_v6(id) } else { IcmpCommunicator::new(id) };
+    match com {
+        Ok(com) => com.with_echo_role(EchoRole::Client),
+        Err(ICError::PermissionDenied) => {
+            error!("Not allowed to open a raw ICMP socket: run as root, or give the binary the \
+                    CAP_NET_RAW capability (setcap cap_net_raw+ep {})",
+                   env::args().next().unwrap_or_default());
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("Could not open a raw ICMP socket: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn fail(what: &str, e: io::Error) -> ! {
+    error!("{}: {}", what, e);
+    process::exit(1);
+}
+
+// Wait for events up to `timeout`, a signal may cut it short
+fn wait(poll: &Poll, events: &mut Events, timeout: Duration) {
+    match poll.poll(events, Some(timeout)) {
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+        res => { res.unwrap(); }
+    }
+}
+
+// On SIGINT or SIGTERM: send what is left of `tosend` then close the tunnel
+fn terminate(odp: &mut ODP, tosend: &[u8]) {
+    info!("Interrupted, closing the tunnel");
+    if let Err(e) = odp.write_all(tosend) {
+        warn!("Could not send the rest of the data: {}", e);
+    }
+    if let Err(e) = odp.shutdown() {
+        warn!("Could not close the tunnel: {}", e);
+    }
+}
+
+// Send the file at `path` to `server --recv`: its length on 8 bytes, the content and its CRC-32
+// on 4 bytes, all big endian
+fn send_file(odp: &mut ODP, path: &PathBuf, bufsize: usize) {
+    let mut file = File::open(path).unwrap_or_else(|e| fail("Could not open the file", e));
+    let len = file.metadata().unwrap_or_else(|e| fail("Could not open the file", e)).len();
+    let start = Instant::now();
+
+    let mut hdr = [0; 8];
+    BigEndian::write_u64(&mut hdr, len);
+    odp.write_all(&hdr).unwrap_or_else(|e| fail("Transfer failed", e));
+
+    let mut crc  = Crc32::new();
+    let mut left = len;
+    let mut buf  = vec![0; bufsize];
+    while left > 0 {
+        if signals::terminating() {
+            terminate(odp, &[]);
+            error!("Transfer interrupted");
+            process::exit(1);
+        }
+        let n = (left as usize).min(buf.len());
+        file.read_exact(&mut buf[..n]).unwrap_or_else(|e| fail("Could not read the file", e));
+        odp.write_all(&buf[..n]).unwrap_or_else(|e| fail("Transfer failed", e));
+        crc.update(&buf[..n]);
+        left -= n as u64;
+    }
 
-    env_logger::init().unwrap();
+    let mut sum = [0; 4];
+    BigEndian::write_u32(&mut sum, crc.sum());
+    odp.write_all(&sum).unwrap_or_else(|e| fail("Transfer failed", e));
+    odp.shutdown().unwrap_or_else(|e| fail("Transfer failed", e.into()));
 
-    let addr = "127.0.0.1:0".parse().unwrap();
-    let peer = icmp_communicator::InetAddr::from_std(&addr);
+    report(odp, len, start);
+    println!("Checksum: {:08x}", crc.sum());
+}
+
+fn report(odp: &ODP, len: u64, start: Instant) {
+    let secs  = start.elapsed().as_secs_f64();
+    let stats = odp.stats();
+    println!("{} bytes in {:.2}s ({:.1} KiB/s)", len, secs, len as f64 / 1024. / secs);
+    println!("{} packets sent, {} retransmits, {} out of order, {} AGN received",
+             stats.packets_sent, stats.retransmits, stats.out_of_order, stats.agn_received);
+}
 
-    let mut odp = ODP::new(com, peer);
+// Bytes to read at once from the source of the data: enough for a message to fill the window
+fn default_bufsize(odp: &ODP) -> usize {
+    cmp::min(odp.max_packet_size() * odp.window(), BUFSIZE_MAX)
+}
 
-    // Setup the server socket
-    //let addr = "127.0.0.1:4242".parse().unwrap();
-    //let srv  = TcpListener::bind(&addr).unwrap();
+// Send the content of the file at `path` as is, then close the tunnel
+fn send_raw(odp: &mut ODP, path: &PathBuf, bufsize: usize) {
+    let mut file = File::open(path).unwrap_or_else(|e| fail("Could not open the file", e));
+    let mut buf  = vec![0; bufsize];
+    loop {
+        if signals::terminating() {
+            terminate(odp, &[]);
+            error!("Transfer interrupted");
+            process::exit(1);
+        }
+        let n = match file.read(&mut buf) {
+            Ok(0)  => break,
+            Ok(n)  => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => fail("Could not read the file", e),
+        };
+        odp.write_all(&buf[..n]).unwrap_or_else(|e| fail("Transfer failed", e));
+    }
+    odp.shutdown().unwrap_or_else(|e| fail("Transfer failed", e.into()));
+}
+
+fn pipe_stdin(odp: &mut ODP, bufsize: usize) {
     let srv = EventedFd(&STDIN);
 
     let poll = Poll::new().unwrap();
-    //poll.register(&srv, SERV, Ready::readable(), PollOpt::level()).unwrap();
-    poll.register(&odp, ICMP, Ready::readable(), PollOpt::level()).unwrap();
+    poll.register(&*odp, ICMP, Ready::readable(), PollOpt::level()).unwrap();
     poll.register(&srv, SERV, Ready::readable(), PollOpt::level()).unwrap();
 
-    let mut tosend = 0;
-    let mut buf    = [0; 10];
-    let mut events = Events::with_capacity(1024);
+    let mut sent    = 0; // buf[sent..tosend] is read from stdin, waiting for room in the window
+    let mut tosend  = 0;
+    let mut buf     = vec![0; bufsize];
+    let mut discard = [0; 4096]; // what the server sends, not to overwrite what is left to send
+    let mut events  = Events::with_capacity(1024);
 
     loop {
-        poll.poll(&mut events, None).unwrap();
+        let rto = odp.rto();
+        wait(&poll, &mut events, rto);
+        on_timeout(odp);
+        if signals::terminating() {
+            terminate(odp, &buf[sent..tosend]);
+            return;
+        }
 
         for event in events.iter() {
             match event.token() {
                 ICMP => {
-                    let ret = odp.recv(&mut buf);
-                    //debug!("{:?}", ret);
+                    match odp.recv(&mut discard) {
+                        Ok(None) if odp.is_closed() => {
+                            info!("The server closed the tunnel");
+                            return;
+                        }
+                        Err(ODPError::ConnectionLost) => peer_unreachable(),
+                        Err(ODPError::SessionExpired) => session_expired(),
+                        _ => {}
+                    }
                 }
                 SERV => {
-                    if tosend == 0 {
+                    if odp.is_closed() {
+                        info!("The server closed the tunnel");
+                        return;
+                    }
+                    if sent == tosend {
+                        sent   = 0;
                         tosend = unistd::read(STDIN, &mut buf).unwrap();
                     }
                     if tosend == 0 {
+                        odp.shutdown().unwrap();
                         return;
                     }
-                    match odp.send(&buf[..tosend]) {
-                        Ok(_) => {
-                            tosend = 0;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        if sent < tosend {
+            match odp.send_all(&buf[sent..tosend]) {
+                Ok(n) => sent += n,
+                Err(ODPError::RemoteWindowFull) => debug!("Queue full!"),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        // only read what we can send
+        let interest = if sent < tosend { Ready::empty() } else { Ready::readable() };
+        poll.reregister(&srv, SERV, interest, PollOpt::level()).unwrap();
+    }
+}
+
+// Tunnel the first TCP connection accepted on `local` through `odp`, until either side closes it
+fn relay(odp: &mut ODP, local: &SocketAddr, bufsize: usize) {
+    let srv = TcpListener::bind(local).expect("Could not listen");
+
+    let poll = Poll::new().unwrap();
+    poll.register(&*odp, ICMP, Ready::readable(), PollOpt::level()).unwrap();
+    poll.register(&srv, SERV, Ready::readable(), PollOpt::level()).unwrap();
+
+    let mut stream: Option<TcpStream> = None;
+    let mut tosend  = Vec::new(); // from the TCP connection, waiting for room in the window
+    let mut towrite = Vec::new(); // from the tunnel, waiting for the TCP connection to take it
+    let mut buf     = vec![0; bufsize];
+    let mut events  = Events::with_capacity(1024);
+
+    loop {
+        let rto = odp.rto();
+        wait(&poll, &mut events, rto);
+        on_timeout(odp);
+        if signals::terminating() {
+            terminate(odp, &tosend);
+            return;
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                SERV => {
+                    let (conn, addr) = match srv.accept() {
+                        Ok(conn) => conn,
+                        Err(e)   => { debug!("accept: {}", e); continue; }
+                    };
+                    if stream.is_some() {
+                        info!("Refusing {}, the tunnel is busy", addr);
+                        continue;
+                    }
+                    info!("Tunneling {}", addr);
+                    poll.register(&conn, TCP, Ready::readable(), PollOpt::level()).unwrap();
+                    stream = Some(conn);
+                }
+                ICMP => {
+                    match odp.recv(&mut buf) {
+                        Ok(Some(n)) => towrite.extend_from_slice(&buf[..n]),
+                        Ok(None) if odp.is_closed() => {
+                            info!("The server closed the tunnel");
+                            return;
+                        }
+                        Ok(None) => {}
+                        Err(ODPError::ConnectionLost) => peer_unreachable(),
+                        Err(ODPError::SessionExpired) => session_expired(),
+                        Err(e) => debug!("{:?}", e),
+                    }
+                }
+                TCP => {
+                    if !tosend.is_empty() || !event.readiness().is_readable() {
+                        continue;
+                    }
+                    match stream.as_mut().unwrap().read(&mut buf) {
+                        Ok(0) => {
+                            info!("Connection closed");
+                            odp.shutdown().unwrap();
+                            return;
                         }
-                        Err(ODPError::RemoteWindowFull) => {
-                            debug!("Queue full!");
-                            sleep(Duration::new(0, 1000000));
+                        Ok(n) => tosend.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => {
+                            info!("Connection lost: {}", e);
+                            odp.shutdown().unwrap();
+                            return;
                         }
-                        Err(e) => panic!("{:?}", e),
                     }
                 }
                 _ => unreachable!(),
             }
         }
+
+        if !tosend.is_empty() {
+            match odp.send_all(&tosend) {
+                Ok(n) => { tosend.drain(..n); }
+                Err(ODPError::RemoteWindowFull) => debug!("Queue full!"),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        if let Some(ref mut conn) = stream {
+            match conn.write(&towrite) {
+                Ok(n) => { towrite.drain(..n); }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    info!("Connection lost: {}", e);
+                    odp.shutdown().unwrap();
+                    return;
+                }
+            }
+
+            // only wait for what we can forward
+            let mut interest = Ready::empty();
+            if tosend.is_empty() {
+                interest.insert(Ready::readable());
+            }
+            if !towrite.is_empty() {
+                interest.insert(Ready::writable());
+            }
+            poll.reregister(conn, TCP, interest, PollOpt::level()).unwrap();
+        }
     }
 }
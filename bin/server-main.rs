@@ -1,38 +1,397 @@
-use std::io::{self, Write};
-use std::rc::Rc;
+use std::env;
+use std::sync::Arc;
+use std::process;
+use std::net::{self, SocketAddr};
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate log;
+use log::LogLevelFilter;
 extern crate env_logger;
 
+extern crate byteorder;
+use byteorder::{BigEndian, ByteOrder};
+
+extern crate mio;
+use mio::*;
+use mio::tcp::TcpStream;
+
 extern crate icmp_communicator;
-use icmp_communicator::IcmpCommunicator;
+use icmp_communicator::{EchoRole, ICError, IcmpCommunicator};
 
 extern crate icmp_tunnel;
-use icmp_tunnel::odp::ODP;
+use icmp_tunnel::odp::{ODP, ODPError, OdpMux};
 use icmp_tunnel::privs;
+use icmp_tunnel::signals;
+use icmp_tunnel::crc32::Crc32;
+
+const ICMP: Token = Token(1);
+const TCP:  Token = Token(2);
+
+const USAGE: &str = "usage: server [--id ID] [--stats] [--idle-timeout SECS] [--max-lifetime SECS]
+              [--forward ADDR:PORT | --recv FILE]
+
+Write what the first client to connect sends to stdout. With --forward, tunnel it to a TCP
+connection opened to ADDR:PORT instead, with --recv, save the file sent by `client --send`.
+With --stats, print traffic counters on exit. --idle-timeout and --max-lifetime close the tunnel
+once no data went through it for SECS, or once it lasted for SECS.";
+
+struct Args {
+    id:      u8,
+    forward: Option<SocketAddr>,
+    recv:    Option<PathBuf>,
+    stats:   bool,
+    idle:    Option<Duration>,
+    life:    Option<Duration>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// Log to stderr at the levels set by RUST_LOG, or else from `info`. Logging may already be set
+// up, e.g. when this code runs under a harness: that logger is kept.
+fn init_logging() {
+    let mut builder = env_logger::LogBuilder::new();
+    match env::var("RUST_LOG") {
+        Ok(filters) => { builder.parse(&filters); }
+        Err(_)      => { builder.filter(None, LogLevelFilter::Info); }
+    }
+    builder.init().ok();
+}
 
+fn parse_args() -> Args {
+    let mut args = Args {
+        id:      2,
+        forward: None,
+        recv:    None,
+        stats:   false,
+        idle:    None,
+        life:    None,
+    };
+
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let value = match arg.as_str() {
+            "--stats" => { args.stats = true; continue; }
+            "--id" | "--forward" | "--recv" | "--idle-timeout" | "--max-lifetime" => {
+                argv.next().unwrap_or_else(|| usage())
+            }
+            _ => usage(),
+        };
+        match arg.as_str() {
+            "--id"           => args.id      = value.parse().unwrap_or_else(|_| usage()),
+            "--idle-timeout" => args.idle    = Some(parse_secs(&value)),
+            "--max-lifetime" => args.life    = Some(parse_secs(&value)),
+            "--forward"      => args.forward = Some(value.parse().unwrap_or_else(|_| usage())),
+            _                => args.recv    = Some(PathBuf::from(value)),
+        }
+    }
+    if args.forward.is_some() && args.recv.is_some() {
+        usage();
+    }
+    args
+}
+
+fn parse_secs(value: &str) -> Duration {
+    Duration::from_secs(value.parse().unwrap_or_else(|_| usage()))
+}
 
 fn main() {
-    let com = Rc::new(IcmpCommunicator::new(2).expect("Make sure you have the necessary permissions"));
-    privs::drop_privs();
+    let args = parse_args();
 
-    env_logger::init().unwrap();
+    init_logging();
 
-    let addr = "127.0.0.1:0".parse().unwrap();
-    let peer = icmp_communicator::InetAddr::from_std(&addr);
+    let com = Arc::new(open_communicator(args.id).with_echo_role(EchoRole::Server));
+    #[cfg(all(feature = "caps", target_os = "linux"))]
+    privs::drop_net_raw().expect("Could not drop privileges");
+    #[cfg(not(all(feature = "caps", target_os = "linux")))]
+    privs::drop_privs().expect("Could not drop privileges");
 
-    let mut odp = ODP::new(com, peer);
+    let mut odp = accept_first(com.clone());
+    odp.set_idle_timeout(args.idle);
+    odp.set_max_lifetime(args.life);
+    signals::catch_termination().expect("Could not catch signals");
 
+    let start = Instant::now();
+    match (args.forward, args.recv) {
+        (Some(backend), _) => forward(&mut odp, &backend),
+        (_, Some(path))    => recv_file(&mut odp, &path),
+        _                  => pipe_stdout(&mut odp),
+    }
+    if args.stats {
+        print_stats(&odp, &com, start);
+    }
+}
+
+// Print the counters of the connection and of the communicator, see --stats
+fn print_stats(odp: &ODP, com: &IcmpCommunicator, start: Instant) {
+    let stats = odp.stats();
+    let comm  = com.stats();
+    let rtt   = odp.rtt_estimate()
+        .map_or("unknown".to_string(), |rtt| format!("{:.1}ms", rtt.as_secs_f64() * 1000.));
+    eprintln!("{} bytes sent, {} received in {:.2}s",
+              stats.bytes_sent, stats.bytes_received, start.elapsed().as_secs_f64());
+    eprintln!("{} packets sent, {} received, {} dropped, {} retransmits, RTT {}",
+              comm.packets_sent, comm.packets_received, comm.packets_dropped, stats.retransmits,
+              rtt);
+}
+
+// Wait for a client to connect, whoever it is. The connection follows the client if its address
+// changes: without a key, anyone seeing the traffic can move it as well.
+fn accept_first(com: Arc<IcmpCommunicator>) -> ODP {
+    let mut mux = OdpMux::new(com.clone(), move |peer| {
+        let mut odp = ODP::new(com.clone(), peer);
+        odp.set_connection_id(true);
+        odp.set_message_crc(true);
+        Some(odp)
+    });
+    let mut buf = [0; 4096];
+    loop {
+        if let Some(peer) = mux.peers().pop() {
+            info!("Connection from {}", peer.to_std());
+            return mux.remove(&peer).unwrap();
+        }
+        if let Err(e) = mux.recv(&mut buf) {
+            debug!("{:?}", e);
+        }
+    }
+}
+
+fn peer_unreachable() -> ! {
+    error!("Peer unreachable");
+    process::exit(1);
+}
+
+fn session_expired() -> ! {
+    warn!("Session expired, the tunnel is closed");
+    process::exit(1);
+}
+
+fn pipe_stdout(odp: &mut ODP) {
     let mut buf = [0; 4096];
     loop {
-        match odp.recv(&mut buf) {
+        let rto = odp.rto();
+        match odp.recv_timeout(&mut buf, rto) {
             Ok(Some(n)) => {
                 //println!("{:?}", String::from_utf8(buf[..n].to_vec()));
                 io::stdout().write_all(&buf[..n]).unwrap();
             }
+            Ok(None) if odp.is_closed() => return,
+            Err(ODPError::ConnectionLost) => peer_unreachable(),
+            Err(ODPError::SessionExpired) => session_expired(),
+            Err(ODPError::IntegrityError) => warn!("Dropped a corrupted message"),
+            Err(_) if signals::terminating() => {}
             Err(e) => panic!("{:?}", e),
             _ => {} //println!("{:?}", e),
         }
+        match odp.on_timeout(Instant::now()) {
+            Err(ODPError::ConnectionLost) => peer_unreachable(),
+            Err(ODPError::SessionExpired) => session_expired(),
+            _                             => {}
+        }
+        if signals::terminating() {
+            terminate(odp, &[]);
+            return;
+        }
+    }
+}
+
+fn open_communicator(id: u8) -> IcmpCommunicator {
+    match IcmpCommunicator::new(id) {
+        Ok(com) => com,
+        Err(ICError::PermissionDenied) => {
+            error!("Not allowed to open a raw ICMP socket: run as root, or give the binary the \
+                    CAP_NET_RAW capability (setcap cap_net_raw+ep {})",
+                   env::args().next().unwrap_or_default());
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("Could not open a raw ICMP socket: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn fail(what: &str, e: io::Error) -> ! {
+    error!("{}: {}", what, e);
+    process::exit(1);
+}
+
+// Wait for events up to `timeout`, a signal may cut it short
+fn wait(poll: &Poll, events: &mut Events, timeout: Duration) {
+    match poll.poll(events, Some(timeout)) {
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+        res => { res.unwrap(); }
+    }
+}
+
+// On SIGINT or SIGTERM: send what is left of `tosend` then close the tunnel
+fn terminate(odp: &mut ODP, tosend: &[u8]) {
+    info!("Interrupted, closing the tunnel");
+    if let Err(e) = odp.write_all(tosend) {
+        warn!("Could not send the rest of the data: {}", e);
+    }
+    if let Err(e) = odp.shutdown() {
+        warn!("Could not close the tunnel: {}", e);
+    }
+}
+
+// Save the file sent by `client --send` to `path`: its length on 8 bytes, the content and its
+// CRC-32 on 4 bytes, all big endian. Then check the CRC.
+fn recv_file(odp: &mut ODP, path: &PathBuf) {
+    let mut file = File::create(path).unwrap_or_else(|e| fail("Could not create the file", e));
+    let start = Instant::now();
+
+    let mut hdr = [0; 8];
+    odp.read_exact(&mut hdr).unwrap_or_else(|e| fail("Transfer failed", e));
+    let len = BigEndian::read_u64(&hdr);
+
+    let mut crc  = Crc32::new();
+    let mut left = len;
+    let mut buf  = [0; 4096];
+    while left > 0 {
+        if signals::terminating() {
+            terminate(odp, &[]);
+            error!("Transfer interrupted");
+            process::exit(1);
+        }
+        let n = (left as usize).min(buf.len());
+        odp.read_exact(&mut buf[..n]).unwrap_or_else(|e| fail("Transfer failed", e));
+        file.write_all(&buf[..n]).unwrap_or_else(|e| fail("Could not write the file", e));
+        crc.update(&buf[..n]);
+        left -= n as u64;
+    }
+
+    let mut sum = [0; 4];
+    odp.read_exact(&mut sum).unwrap_or_else(|e| fail("Transfer failed", e));
+    // wait for the client to close so that it knows everything was received
+    if odp.read(&mut buf).unwrap_or_else(|e| fail("Transfer failed", e)) != 0 {
+        warn!("Ignoring data sent after the file");
+    }
+
+    report(odp, len, start);
+    if BigEndian::read_u32(&sum) != crc.sum() {
+        error!("Checksum mismatch: got {:08x}, expected {:08x}",
+               crc.sum(), BigEndian::read_u32(&sum));
+        process::exit(1);
+    }
+    println!("Checksum ok: {:08x}", crc.sum());
+}
+
+fn report(odp: &ODP, len: u64, start: Instant) {
+    let secs  = start.elapsed().as_secs_f64();
+    let stats = odp.stats();
+    println!("{} bytes in {:.2}s ({:.1} KiB/s)", len, secs, len as f64 / 1024. / secs);
+    println!("{} packets sent, {} retransmits, {} out of order, {} AGN sent",
+             stats.packets_sent, stats.retransmits, stats.out_of_order, stats.agn_sent);
+}
+
+// Tunnel `odp` to a new TCP connection to `backend`, until either side closes it
+fn forward(odp: &mut ODP, backend: &SocketAddr) {
+    let mut conn = match net::TcpStream::connect(backend).and_then(TcpStream::from_stream) {
+        Ok(conn) => conn,
+        Err(e)   => {
+            error!("Could not connect to {}: {}", backend, e);
+            if let Err(e) = odp.shutdown() {
+                debug!("shutdown: {:?}", e);
+            }
+            process::exit(1);
+        }
+    };
+    info!("Forwarding to {}", backend);
+
+    let poll = Poll::new().unwrap();
+    poll.register(&*odp, ICMP, Ready::readable(), PollOpt::level()).unwrap();
+    poll.register(&conn, TCP, Ready::readable(), PollOpt::level()).unwrap();
+
+    let mut tosend  = Vec::new(); // from the backend, waiting for room in the window
+    let mut towrite = Vec::new(); // from the tunnel, waiting for the backend to take it
+    let mut buf     = [0; 4096];
+    let mut events  = Events::with_capacity(1024);
+
+    loop {
+        let rto = odp.rto();
+        wait(&poll, &mut events, rto);
+        match odp.on_timeout(Instant::now()) {
+            Ok(_) => {}
+            Err(ODPError::ConnectionLost) => peer_unreachable(),
+            Err(ODPError::SessionExpired) => session_expired(),
+            Err(e) => panic!("{:?}", e),
+        }
+        if signals::terminating() {
+            terminate(odp, &tosend);
+            return;
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                ICMP => {
+                    match odp.recv(&mut buf) {
+                        Ok(Some(n)) => towrite.extend_from_slice(&buf[..n]),
+                        Ok(None) if odp.is_closed() => {
+                            info!("The client closed the tunnel");
+                            return;
+                        }
+                        Ok(None) => {}
+                        Err(ODPError::ConnectionLost) => peer_unreachable(),
+                        Err(ODPError::SessionExpired) => session_expired(),
+                        Err(e) => debug!("{:?}", e),
+                    }
+                }
+                TCP => {
+                    if !tosend.is_empty() || !event.readiness().is_readable() {
+                        continue;
+                    }
+                    match conn.read(&mut buf) {
+                        Ok(0) => {
+                            info!("The backend closed the connection");
+                            odp.shutdown().unwrap();
+                            return;
+                        }
+                        Ok(n) => tosend.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => {
+                            info!("Connection to the backend lost: {}", e);
+                            odp.shutdown().unwrap();
+                            return;
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        if !tosend.is_empty() {
+            match odp.send_all(&tosend) {
+                Ok(n) => { tosend.drain(..n); }
+                Err(ODPError::RemoteWindowFull) => debug!("Queue full!"),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        match conn.write(&towrite) {
+            Ok(n) => { towrite.drain(..n); }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                info!("Connection to the backend lost: {}", e);
+                odp.shutdown().unwrap();
+                return;
+            }
+        }
+
+        // only wait for what we can forward
+        let mut interest = Ready::empty();
+        if tosend.is_empty() {
+            interest.insert(Ready::readable());
+        }
+        if !towrite.is_empty() {
+            interest.insert(Ready::writable());
+        }
+        poll.reregister(&conn, TCP, interest, PollOpt::level()).unwrap();
     }
 }
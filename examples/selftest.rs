@@ -0,0 +1,128 @@
+//! Check that ODP works on this build without raw sockets nor privileges: two ODPs talk over an
+//! in-memory `Loopback` link, which loses and reorders a few datagrams always at the same place so
+//! that retransmissions and reassembly run every time. Prints PASS, or FAIL and exits with 1. Run
+//! it with `cargo run --example selftest`.
+
+use std::io::{Read, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+extern crate icmp_communicator;
+use icmp_communicator::{InetAddr, RawFd};
+
+extern crate icmp_tunnel;
+use icmp_tunnel::odp::{OdpStats, ODP};
+use icmp_tunnel::transport::{Loopback, Result, Transport};
+
+// Datagrams sent by the client that `Scripted` loses, and holds back until the next one was sent.
+// The first one is the SYN.
+const DROPPED:   [usize; 2] = [3, 20];
+const REORDERED: [usize; 2] = [6, 12];
+
+// Size of the message sent, many packets long so that it is split and reassembled
+const MSG_SIZE: usize = 64 * 1024;
+
+// Bound on the whole run, in case the connection gets stuck
+const DEADLINE: Duration = Duration::from_secs(30);
+
+/// A `Transport` needs little more than sending and receiving datagrams: this one goes over a
+/// `Loopback`, but misbehaves on purpose at given datagrams.
+struct Scripted {
+    inner: Loopback,
+    sent:  Mutex<usize>,
+    held:  Mutex<Option<(Vec<u8>, InetAddr)>>, // sent after the next datagram
+}
+
+impl Scripted {
+    fn new(inner: Loopback) -> Scripted {
+        Scripted { inner, sent: Mutex::new(0), held: Mutex::new(None) }
+    }
+}
+
+impl Transport for Scripted {
+    fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        let n = {
+            let mut sent = self.sent.lock().unwrap();
+            *sent += 1;
+            *sent - 1
+        };
+        if DROPPED.contains(&n) {
+            return Ok(buf.len());
+        }
+        if REORDERED.contains(&n) {
+            *self.held.lock().unwrap() = Some((buf.to_vec(), peer));
+            return Ok(buf.len());
+        }
+        let res = self.inner.sendto(buf, peer);
+        if let Some((held, peer)) = self.held.lock().unwrap().take() {
+            self.inner.sendto(&held, peer)?;
+        }
+        res
+    }
+
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        self.inner.recvfrom(buf)
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        self.inner.rawfd()
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        self.inner.is_nonblocking()
+    }
+
+    fn max_payload(&self) -> usize {
+        self.inner.max_payload()
+    }
+}
+
+fn fail(what: &str) -> ! {
+    println!("FAIL: {}", what);
+    process::exit(1);
+}
+
+// Send `msg` over `link` then close, return the counters of the connection
+fn client(link: Scripted, msg: &[u8]) -> OdpStats {
+    let peer = link.inner.peer();
+    let mut odp = ODP::with_window(Arc::new(link), peer, 8).unwrap();
+    odp.set_rto(Duration::from_millis(50));
+    odp.connect().unwrap_or_else(|e| fail(&format!("connect: {}", e)));
+    odp.write_all(msg).unwrap_or_else(|e| fail(&format!("send: {}", e)));
+    odp.shutdown().unwrap_or_else(|e| fail(&format!("shutdown: {}", e)));
+    odp.stats()
+}
+
+fn main() {
+    thread::spawn(|| {
+        thread::sleep(DEADLINE);
+        fail("timed out");
+    });
+
+    let msg: Vec<u8> = (0..MSG_SIZE).map(|i| (i % 251) as u8).collect();
+    let (a, b) = Loopback::pair().unwrap_or_else(|e| fail(&format!("loopback: {}", e)));
+
+    let sent   = msg.clone();
+    let sender = thread::spawn(move || client(Scripted::new(a), &sent));
+
+    let peer = b.peer();
+    let mut odp = ODP::new(Arc::new(b), peer);
+    odp.accept().unwrap_or_else(|e| fail(&format!("accept: {}", e)));
+    let mut received = Vec::new();
+    odp.read_to_end(&mut received).unwrap_or_else(|e| fail(&format!("recv: {}", e)));
+
+    let stats = sender.join().unwrap_or_else(|_| fail("the client panicked"));
+    if received != msg {
+        fail(&format!("received {} bytes, not the {} sent", received.len(), msg.len()));
+    }
+    if stats.retransmits == 0 {
+        fail("nothing was retransmitted");
+    }
+    if odp.stats().out_of_order == 0 {
+        fail("nothing arrived out of order");
+    }
+    println!("PASS: {} bytes in {} packets, {} retransmits, {} out of order",
+             received.len(), stats.packets_sent, stats.retransmits, odp.stats().out_of_order);
+}
@@ -1,51 +1,723 @@
 use std::io;
 use std::cmp;
+use std::mem;
+use std::ptr;
+use std::fmt;
+use std::error;
+use std::net;
 use std::result;
+use std::process;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 pub use std::os::unix::io::RawFd;
+use std::os::unix::io::AsRawFd;
 
 extern crate nix;
 pub use self::nix::unistd;
+use self::nix::fcntl::{fcntl, FcntlArg, OFlag, O_NONBLOCK};
+use self::nix::libc::{self, c_int, c_void, socklen_t};
 pub use self::nix::sys::socket::*;
 
 extern crate mio;
 use self::mio::*;
 use mio::unix::EventedFd;
 
-// The header to include in all packets. It is 4 bytes long:
-// * \x00: ICMP echo reply
-// * \x00: a byte we choose not totally at random to separate our packets from the rest of the
-// ICMP trafic
+// The header to include in all packets. It is a regular 8 bytes echo header:
+// * \x00: ICMP echo reply (129 for ICMPv6), or request, see `EchoRole`
+// * \x00: code, 0 as echo messages must have unless told otherwise, see `with_icmp_code`
 // * \x00\x00: place holder for the checksum
-const PKT_HEADER: &[u8; 4] = b"\x00\x00\x00\x00";
+// * \x00\x00: identifier, made of a byte we choose not totally at random (the magic) to separate
+// our packets from the rest of the ICMP trafic followed by the id of the emitting communicator
+// * \x00\x00: sequence number, incremented with each packet like ping does
+// It is followed by the nonce of the emitting communicator, see NONCE_SIZE.
+const PKT_HEADER: &[u8; 8] = b"\x00\x00\x00\x00\x00\x00\x00\x00";
+
+// The header used by datagram communicators. The kernel only lets unprivileged sockets emit
+// well-formed echo requests, so it is the full 8 bytes echo header:
+// * \x08: ICMP echo request
+// * \x00: code, must be 0
+// * \x00\x00: checksum, computed by the kernel
+// * \x00\x00: identifier, overwritten by the kernel with the socket's own identifier
+// * \x00\x00: sequence number, holding our id and magic
+const DGRAM_HEADER: &[u8; 8] = b"\x08\x00\x00\x00\x00\x00\x00\x00";
+
+// Every communicator picks a random nonce when created and puts it right after the echo header,
+// so that it recognizes its own packets (looped back, or answered by the kernel) whatever the
+// ids in use. Ids are then free to collide between hosts.
+const NONCE_SIZE: usize = 4;
+
+// Size of the buffer packets are received into, unless told otherwise
+const DEFAULT_RECV_BUFSIZE: usize = 4096;
+
+// The magic used unless told otherwise
+const DEFAULT_MAGIC: u8 = 0xC4;
+
+// ICMP echo types
+const ICMP_ECHO_REPLY:     u8 = 0;
+const ICMP_ECHO_REQUEST:   u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY:   u8 = 129;
 
 // IP packet header is 20 bytes long
 const IP_SIZE: usize = 20;
+const IPV6_SIZE: usize = 40;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 pub enum ICError {
     /// Error reported by nix
     Nix(nix::Error),
+    /// I/O error on the underlying socket
+    Io(io::Error),
+    /// Communicator ids must be non zero
+    InvalidId,
+    /// Not allowed to open the socket: raw sockets need root or CAP_NET_RAW, datagram sockets a
+    /// group listed in the `net.ipv4.ping_group_range` sysctl
+    PermissionDenied,
+    /// There is no network interface with that name
+    NoSuchDevice(String),
+    /// A packet of the given size did not fit in the receive buffer, see `with_recv_bufsize`
+    Truncated(usize),
+    /// Sending now would exceed the limit set with `set_pace_limit`, see `pace_delay`
+    PaceLimited,
+    /// The socket took only `sent` bytes of a packet of `expected` bytes. Datagrams are sent
+    /// whole, this is not supposed to happen.
+    ShortWrite { sent: usize, expected: usize },
     /// Other error
     Unknown,
 }
 
 type Result<T> = result::Result<T, ICError>;
 
+/// Traffic counters of a communicator, see `IcmpCommunicator::stats`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CommStats {
+    /// Packets sent
+    pub packets_sent:      u64,
+    /// Bytes of user data sent
+    pub bytes_sent:        u64,
+    /// Packets received and handed to the caller
+    pub packets_received:  u64,
+    /// Bytes of user data received and handed to the caller
+    pub bytes_received:    u64,
+    /// Packets read from the socket but dropped as not ours, including checksum failures
+    pub packets_dropped:   u64,
+    /// Packets dropped because of a bad checksum
+    pub checksum_failures: u64,
+}
+
+// The live counterpart of CommStats. These are only statistics so relaxed ordering is enough.
+#[derive(Default)]
+struct Counters {
+    packets_sent:      AtomicU64,
+    bytes_sent:        AtomicU64,
+    packets_received:  AtomicU64,
+    bytes_received:    AtomicU64,
+    packets_dropped:   AtomicU64,
+    checksum_failures: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CommStats {
+        CommStats {
+            packets_sent:      self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent:        self.bytes_sent.load(Ordering::Relaxed),
+            packets_received:  self.packets_received.load(Ordering::Relaxed),
+            bytes_received:    self.bytes_received.load(Ordering::Relaxed),
+            packets_dropped:   self.packets_dropped.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// A token bucket refilled at `rate` tokens per second, holding up to one second worth of them.
+// Sending costs a token per packet or per byte.
+struct Bucket {
+    rate:   f64,
+    tokens: f64,
+    last:   Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32, now: Instant) -> Bucket {
+        Bucket { rate: rate as f64, tokens: rate as f64, last: now }
+    }
+
+    fn allows(&mut self, cost: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.needed(cost)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last   = now;
+    }
+
+    // what has to be in the bucket to pay `cost`: a packet larger than what the bucket holds is
+    // let through once it is full, and leaves it in debt
+    fn needed(&self, cost: usize) -> f64 {
+        (cost as f64).min(self.rate)
+    }
+
+    fn delay(&self, cost: usize) -> Duration {
+        let missing = self.needed(cost) - self.tokens;
+        Duration::from_secs_f64(missing.max(0.) / self.rate)
+    }
+}
+
+// Random gaps between packets, see `set_jitter`
+struct Jitter {
+    gap:  Range<Duration>,
+    next: Instant, // when the next packet may leave
+    rng:  u64,     // xorshift64 state, never 0
+}
+
+impl Jitter {
+    fn new(gap: Range<Duration>, now: Instant) -> Jitter {
+        let (a, b) = (random_nonce(), random_nonce());
+        let seed = a.iter().chain(b.iter()).fold(0, |seed, &byte| seed << 8 | byte as u64);
+        Jitter { gap, next: now, rng: seed | 1 }
+    }
+
+    fn next_gap(&mut self) -> Duration {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let span = self.gap.end.saturating_sub(self.gap.start).as_nanos() as u64;
+        if span == 0 {
+            return self.gap.start;
+        }
+        self.gap.start + Duration::from_nanos(self.rng % span)
+    }
+}
+
+// The rate limits set with `set_pace_limit` and `set_jitter`
+#[derive(Default)]
+struct Pacer {
+    packets: Option<Bucket>,
+    bytes:   Option<Bucket>,
+    jitter:  Option<Jitter>,
+}
+
+impl Pacer {
+    // Take what sending a packet of `size` bytes costs, if there is enough
+    fn take(&mut self, size: usize, now: Instant) -> bool {
+        let allowed = self.packets.as_mut().is_none_or(|b| b.allows(1, now))
+            && self.bytes.as_mut().is_none_or(|b| b.allows(size, now))
+            && self.jitter.as_ref().is_none_or(|j| now >= j.next);
+        if allowed {
+            if let Some(ref mut bucket) = self.packets {
+                bucket.tokens -= 1.;
+            }
+            if let Some(ref mut bucket) = self.bytes {
+                bucket.tokens -= size as f64;
+            }
+            if let Some(ref mut jitter) = self.jitter {
+                jitter.next = now + jitter.next_gap();
+            }
+        }
+        allowed
+    }
+
+    // Give back what `take` took for a packet of `size` bytes that didn't leave after all
+    fn refund(&mut self, size: usize, now: Instant) {
+        if let Some(ref mut bucket) = self.packets {
+            bucket.tokens = (bucket.tokens + 1.).min(bucket.rate);
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.tokens = (bucket.tokens + size as f64).min(bucket.rate);
+        }
+        if let Some(ref mut jitter) = self.jitter {
+            jitter.next = now;
+        }
+    }
+
+    fn delay(&mut self, now: Instant) -> Duration {
+        let mut delay = Duration::from_secs(0);
+        for bucket in self.packets.iter_mut().chain(self.bytes.iter_mut()) {
+            bucket.refill(now);
+            delay = cmp::max(delay, bucket.delay(1));
+        }
+        if let Some(ref jitter) = self.jitter {
+            delay = cmp::max(delay, jitter.next.saturating_duration_since(now));
+        }
+        delay
+    }
+}
+
+// What the kernel hands us with each received packet: its size, origin and TTL
+type RawPacket = (usize, Option<InetAddr>, Option<u8>);
+
+// A message we accepted: its size, origin and what we know about its packet
+type Message = (usize, InetAddr, PacketMeta);
+
+/// What we know about the packet that carried a received message.
+#[derive(Debug, Copy, Clone)]
+pub struct PacketMeta {
+    /// TTL (hop limit for IPv6) of the packet when it reached us
+    pub ttl:       Option<u8>,
+    /// ICMP type of the packet
+    pub icmp_type: u8,
+    /// ICMP code of the packet
+    pub icmp_code: u8,
+}
+
+/// Which ICMP echo messages a raw communicator sends and expects. Both ends of a tunnel use
+/// echo replies unless told otherwise; a `Client` facing a `Server` looks like ping instead,
+/// requests one way and replies the other. The peer's kernel answers the requests as well, these
+/// answers are dropped like the rest of our own packets. Datagram communicators always send
+/// requests and get replies.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EchoRole {
+    /// Send and expect echo replies
+    Reply,
+    /// Send echo requests and expect replies
+    Client,
+    /// Send echo replies and expect requests
+    Server,
+}
+
+/// Why a received packet was not handed over as a message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// Too short to hold our header
+    TooShort,
+    /// The origin of the packet is unknown
+    NoSource,
+    /// Not the ICMP echo message we expect, see `EchoRole`
+    NotEcho,
+    /// Our signature is not there, this is some other ICMP traffic
+    NotOurs,
+    /// Sent by this communicator
+    OwnPacket,
+    /// The ICMP checksum is wrong
+    BadChecksum,
+    /// Not from the peer given to `set_peer_filter`
+    NotPeer,
+}
+
+/// What an ICMP error message reports, see `IcmpReport`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IcmpErrorKind {
+    /// Destination unreachable (type 3, 1 for ICMPv6): no route, the host is down, or a firewall
+    /// rejected the packet
+    Unreachable,
+    /// Time exceeded (type 11, 3 for ICMPv6): the TTL ran out on the way, see `set_ttl`
+    TimeExceeded,
+}
+
+/// An ICMP error message about a packet we sent.
+#[derive(Copy, Clone)]
+pub struct IcmpReport {
+    pub kind: IcmpErrorKind,
+    /// ICMP code of the message, e.g. 1 for an unreachable host
+    pub code: u8,
+    /// Who sent the message: a router on the way, or the destination itself
+    pub from: InetAddr,
+    /// Where our packet was going
+    pub dest: InetAddr,
+}
+
+/// Outcome of `recvfrom_reason`.
+#[derive(Copy, Clone)]
+pub enum RecvOutcome {
+    /// A message of this length (regardless of the buffer's size) was received from this address
+    Message(usize, InetAddr),
+    /// A packet was received but dropped
+    Dropped(DropReason),
+    /// An ICMP error about a packet this communicator sent was received. Only raw communicators
+    /// see them, the kernel keeps them from datagram ones.
+    IcmpError(IcmpReport),
+}
+
+impl fmt::Display for ICError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ICError::Nix(ref e) => write!(f, "{}", e),
+            ICError::Io(ref e)  => write!(f, "{}", e),
+            ICError::InvalidId  => write!(f, "communicator id must be non zero"),
+            ICError::PermissionDenied => write!(f, "not allowed to open an ICMP socket"),
+            ICError::NoSuchDevice(ref name) => write!(f, "no such network interface: {}", name),
+            ICError::Truncated(size) => write!(f, "received a packet too large ({} bytes)", size),
+            ICError::PaceLimited => write!(f, "send rate limit reached"),
+            ICError::ShortWrite { sent, expected } => {
+                write!(f, "packet only partly sent ({} of {} bytes)", sent, expected)
+            }
+            ICError::Unknown    => write!(f, "unknown error"),
+        }
+    }
+}
+
+impl error::Error for ICError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ICError::Nix(ref e) => Some(e),
+            ICError::Io(ref e)  => Some(e),
+            _                   => None,
+        }
+    }
+}
+
+impl From<nix::Error> for ICError {
+    fn from(e: nix::Error) -> ICError {
+        ICError::Nix(e)
+    }
+}
+
+impl From<io::Error> for ICError {
+    fn from(e: io::Error) -> ICError {
+        ICError::Io(e)
+    }
+}
 
+
+/// A communicator is `Send` and `Sync`, it can be shared between threads (e.g. in an `Arc`).
+/// Concurrent calls are safe: each `sendto` emits a whole packet with a single system call, and
+/// each received packet goes to exactly one of the threads blocked in `recvfrom`, which then
+/// decides whether it is ours. A packet read by a thread is not seen by the others.
 pub struct IcmpCommunicator {
-    id:   u8,
-    sock: RawFd,
+    id:              u8,
+    magic:           u8,
+    sock:            RawFd,
+    family:          AddressFamily,
+    socktype:        SockType,
+    role:            EchoRole,
+    icmp_code:       u8,
+    nonce:           [u8; NONCE_SIZE],
+    echo_seq:        AtomicU16,
+    verify_checksum: AtomicBool,
+    peer_filter:     Mutex<Option<net::IpAddr>>,
+    recv_bufsize:    usize,
+    // reused by recvfrom unless another thread is receiving
+    recv_buf:        Mutex<Vec<u8>>,
+    // same for sendto, so that sending a packet does not allocate
+    send_buf:        Mutex<Vec<u8>>,
+    pacer:           Mutex<Pacer>,
+    // source address of the IP header we build, see `with_hdrincl`
+    #[cfg(feature = "hdrincl")]
+    spoof_src:       Option<net::Ipv4Addr>,
+    counters:        Counters,
 }
 
 impl IcmpCommunicator {
 
+    /// Create a communicator sending and receiving ICMP over IPv4.
     pub fn new(id: u8) -> Result<IcmpCommunicator> {
-        assert!(id != 0, "id must be non zero");
-        socket(AddressFamily::Inet, SockType::Raw, SockFlag::empty(), 0x01 /* IPPROTO_ICMP */)
-            .map_err(ICError::Nix)
-            .map    (|s| IcmpCommunicator { id: id, sock: s })
+        IcmpCommunicator::with_magic(id, DEFAULT_MAGIC)
+    }
+
+    /// Same as `new` but only exchange packets with communicators using the same `magic`, so that
+    /// several unrelated tunnels can coexist.
+    pub fn with_magic(id: u8, magic: u8) -> Result<IcmpCommunicator> {
+        IcmpCommunicator::builder(id).magic(magic).build()
+    }
+
+    /// Same as `new` but build the IP header of the packets we send ourselves (`IP_HDRINCL`), with
+    /// `src` as their source address whatever the address of the interface they leave through.
+    /// The header has a TTL of 64, `set_ttl` does not apply. Only available with the `hdrincl`
+    /// feature.
+    ///
+    /// Whoever we talk to answers `src`, not us: this is meant for one way channels, or for a
+    /// peer on the path to `src`. Forging source addresses is easily abused, only use this on
+    /// networks you are authorized to test.
+    #[cfg(feature = "hdrincl")]
+    pub fn with_hdrincl(id: u8, src: net::Ipv4Addr) -> Result<IcmpCommunicator> {
+        let mut com = IcmpCommunicator::new(id)?;
+        com.setsockopt_int_(libc::IPPROTO_IP, libc::IP_HDRINCL, 1)?;
+        com.spoof_src = Some(src);
+        Ok(com)
+    }
+
+    /// Options of a new communicator, to be created with them all at once by `build`.
+    pub fn builder(id: u8) -> IcmpCommunicatorBuilder {
+        IcmpCommunicatorBuilder::new(id)
+    }
+
+    /// Create a communicator sending and receiving ICMPv6 over IPv6. Peers given to `sendto`
+    /// must then be IPv6 addresses.
+    pub fn new_v6(id: u8) -> Result<IcmpCommunicator> {
+        IcmpCommunicator::builder(id).v6().build()
+    }
+
+    /// Create a communicator on top of an unprivileged ICMP socket (Linux only, see the
+    /// `net.ipv4.ping_group_range` sysctl). Such a socket only emits echo requests and only
+    /// receives the echo replies matching its own identifier. The kernel also strips the IP
+    /// header of received packets, so unlike raw communicators there are no IP_SIZE bytes to skip.
+    pub fn new_dgram(id: u8) -> Result<IcmpCommunicator> {
+        IcmpCommunicator::builder(id).dgram().build()
+    }
+
+    fn open_(id: u8, magic: u8, family: AddressFamily, socktype: SockType, proto: i32)
+      -> Result<IcmpCommunicator> {
+        if id == 0 {
+            // 0 is what regular ICMP trafic has in place of our id
+            return Err(ICError::InvalidId);
+        }
+        let sock = socket(family, socktype, SockFlag::empty(), proto).map_err(socket_error)?;
+        let com  = IcmpCommunicator {
+            id,
+            magic,
+            sock,
+            family,
+            socktype,
+            role:            EchoRole::Reply,
+            icmp_code:       0,
+            nonce:           random_nonce(),
+            echo_seq:        AtomicU16::new(0),
+            verify_checksum: AtomicBool::new(true),
+            peer_filter:     Mutex::new(None),
+            recv_bufsize:    DEFAULT_RECV_BUFSIZE,
+            recv_buf:        Mutex::new(vec![0; DEFAULT_RECV_BUFSIZE]),
+            send_buf:        Mutex::new(Vec::new()),
+            pacer:           Mutex::new(Pacer::default()),
+            #[cfg(feature = "hdrincl")]
+            spoof_src:       None,
+            counters:        Counters::default(),
+        };
+
+        // have the kernel tell us the TTL of the packets we receive, see `recvfrom_meta`
+        let (level, name) = match family {
+            AddressFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT),
+            _                    => (libc::IPPROTO_IP,   libc::IP_RECVTTL),
+        };
+        com.setsockopt_int_(level, name, 1)?;
+
+        Ok(com)
+    }
+
+    /// Send and expect the echo messages of `role` instead of echo replies only.
+    pub fn with_echo_role(mut self, role: EchoRole) -> IcmpCommunicator {
+        self.role = role;
+        self
+    }
+
+    /// Send packets with `code` as their ICMP code instead of 0, the only one defined for echo
+    /// messages. Packets are received whatever their code. Datagram communicators always send 0,
+    /// the kernel sees to it.
+    pub fn with_icmp_code(mut self, code: u8) -> IcmpCommunicator {
+        self.icmp_code = code;
+        self
+    }
+
+    /// Receive packets (IP header included for raw IPv4 communicators) of up to `size` bytes
+    /// instead of the default 4096. Larger packets are reported with `ICError::Truncated`.
+    pub fn with_recv_bufsize(mut self, size: usize) -> IcmpCommunicator {
+        self.recv_bufsize = size;
+        self.recv_buf     = Mutex::new(vec![0; size]);
+        self
+    }
+
+    /// Only accept packets coming from `addr`, or from anyone if `None` (the default). Packets
+    /// from other sources are dropped as if they were not ours.
+    pub fn set_peer_filter(&self, addr: Option<net::IpAddr>) {
+        *self.peer_filter.lock().unwrap() = addr;
+    }
+
+    /// Enable or disable the verification of the checksum of received packets (enabled by
+    /// default). Packets with a bad checksum are dropped as if they were not ours. This only
+    /// applies to raw IPv4 communicators: for ICMPv6 and datagram sockets the kernel already
+    /// does it.
+    pub fn set_verify_checksum(&self, verify: bool) {
+        self.verify_checksum.store(verify, Ordering::Relaxed);
+    }
+
+    /// Limit the rate of `sendto` and `sendto_batch` to `packets_per_sec` packets and/or
+    /// `bytes_per_sec` bytes (ICMP header included) per second, `None` meaning no limit. These are
+    /// token buckets holding one second worth of sending: once the budget is spent, sending fails
+    /// with `ICError::PaceLimited` until it refills, which `pace_delay` tells.
+    pub fn set_pace_limit(&self, packets_per_sec: Option<u32>, bytes_per_sec: Option<u32>) {
+        let now = Instant::now();
+        let mut pacer = self.pacer.lock().unwrap();
+        pacer.packets = packets_per_sec.filter(|&r| r > 0).map(|r| Bucket::new(r, now));
+        pacer.bytes   = bytes_per_sec.filter(|&r| r > 0).map(|r| Bucket::new(r, now));
+    }
+
+    /// Leave a random gap, between `gap.start` and `gap.end`, between the packets we send rather
+    /// than emitting them back to back, or stop if `None`. Like with `set_pace_limit`, sending
+    /// before the gap elapsed fails with `ICError::PaceLimited`.
+    ///
+    /// This caps the rate at one packet per average gap, whatever the link can do, and it applies
+    /// to every packet: with a 0.5 to 1.5 second gap, a tunnel moves at most about 1.4 KiB/s in
+    /// each direction, acks included. Protocols on top should then wait longer than `gap.end`
+    /// before retransmitting.
+    pub fn set_jitter(&self, gap: Option<Range<Duration>>) {
+        let now = Instant::now();
+        self.pacer.lock().unwrap().jitter = gap.map(|gap| Jitter::new(gap, now));
+    }
+
+    /// How long to wait before the rate limit lets a (small) packet through, 0 if it already does.
+    pub fn pace_delay(&self) -> Duration {
+        self.pacer.lock().unwrap().delay(Instant::now())
+    }
+
+    /// Set the TTL (hop limit for IPv6) of the packets we emit. The kernel default is used until
+    /// this is called.
+    pub fn set_ttl(&self, ttl: u8) -> Result<()> {
+        let (level, name) = self.ttl_sockopt_();
+        self.setsockopt_int_(level, name, ttl as c_int)
+    }
+
+    /// Get the TTL (hop limit for IPv6) of the packets we emit.
+    pub fn ttl(&self) -> Result<u8> {
+        let (level, name) = self.ttl_sockopt_();
+        self.getsockopt_int_(level, name).map(|ttl| ttl as u8)
+    }
+
+    /// Set the type of service byte (traffic class for IPv6) of the packets we emit, e.g. a DSCP
+    /// value shifted left by 2.
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        let (level, name) = self.tos_sockopt_();
+        self.setsockopt_int_(level, name, tos as c_int)
+    }
+
+    /// Get the type of service byte (traffic class for IPv6) of the packets we emit.
+    pub fn tos(&self) -> Result<u8> {
+        let (level, name) = self.tos_sockopt_();
+        self.getsockopt_int_(level, name).map(|tos| tos as u8)
+    }
+
+    /// Let the kernel queue up to `size` bytes of received packets (SO_RCVBUF), so that bursts
+    /// are not dropped while we are busy. Not to be confused with `with_recv_bufsize`, the size of
+    /// a single packet. The kernel doubles the value for its own bookkeeping and caps it to the
+    /// `net.core.rmem_max` sysctl.
+    pub fn set_recv_bufsize(&self, size: usize) -> Result<()> {
+        self.setsockopt_int_(libc::SOL_SOCKET, libc::SO_RCVBUF, sockopt_size(size))
+    }
+
+    /// Same as `set_recv_bufsize` for the packets waiting to be sent (SO_SNDBUF), capped to the
+    /// `net.core.wmem_max` sysctl.
+    pub fn set_send_bufsize(&self, size: usize) -> Result<()> {
+        self.setsockopt_int_(libc::SOL_SOCKET, libc::SO_SNDBUF, sockopt_size(size))
     }
 
+    /// Set the don't fragment flag on the packets we emit, so that routers on the way tell the
+    /// kernel about smaller MTUs instead of fragmenting them, see `path_mtu`. Packets larger than
+    /// the path MTU known so far are still fragmented (IP_PMTUDISC_WANT) rather than rejected.
+    /// The packets of `with_hdrincl` communicators keep the header we write.
+    pub fn set_path_mtu_discovery(&self, on: bool) -> Result<()> {
+        let (level, name, want, dont) = match self.family {
+            AddressFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER,
+                                     libc::IPV6_PMTUDISC_WANT, libc::IPV6_PMTUDISC_DONT),
+            _                    => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER,
+                                     libc::IP_PMTUDISC_WANT, libc::IP_PMTUDISC_DONT),
+        };
+        self.setsockopt_int_(level, name, if on { want } else { dont })
+    }
+
+    /// MTU of the path to `peer` as the kernel knows it: the one of the outgoing interface, or
+    /// less once a router reported a smaller one. Our socket isn't connected, so the kernel is
+    /// asked through a UDP socket connected to `peer`, which shares the route.
+    pub fn path_mtu(&self, peer: InetAddr) -> Result<usize> {
+        let (ip, level, name) = match peer.to_std() {
+            net::SocketAddr::V4(a) => (net::IpAddr::V4(*a.ip()), libc::IPPROTO_IP, libc::IP_MTU),
+            net::SocketAddr::V6(a) => (net::IpAddr::V6(*a.ip()), libc::IPPROTO_IPV6,
+                                       libc::IPV6_MTU),
+        };
+        let unspecified = match ip {
+            net::IpAddr::V4(_) => net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED),
+            net::IpAddr::V6(_) => net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED),
+        };
+        // nothing is sent, any port does
+        let udp = net::UdpSocket::bind((unspecified, 0))?;
+        udp.connect((ip, 9))?;
+
+        let mut mtu: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let res = unsafe {
+            libc::getsockopt(udp.as_raw_fd(), level, name, &mut mtu as *mut c_int as *mut c_void,
+                             &mut len)
+        };
+        nix::Errno::result(res).map(|_| mtu as usize).map_err(ICError::Nix)
+    }
+
+    /// Whether the socket is in non blocking mode.
+    pub fn is_nonblocking(&self) -> Result<bool> {
+        let flags = fcntl(self.sock, FcntlArg::F_GETFL).map_err(ICError::Nix)?;
+        Ok(OFlag::from_bits_truncate(flags).contains(O_NONBLOCK))
+    }
+
+    /// Put the socket in non blocking mode, or back in blocking mode. See `try_recvfrom`.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        let flags = fcntl(self.sock, FcntlArg::F_GETFL).map_err(ICError::Nix)?;
+        let mut flags = OFlag::from_bits_truncate(flags);
+        if nonblocking {
+            flags.insert(O_NONBLOCK);
+        } else {
+            flags.remove(O_NONBLOCK);
+        }
+        fcntl(self.sock, FcntlArg::F_SETFL(flags)).map(drop).map_err(ICError::Nix)
+    }
+
+    /// Only send and receive through the network interface named `ifname`. This requires the
+    /// CAP_NET_RAW capability, a missing one is reported as EPERM.
+    pub fn bind_device(&self, ifname: &str) -> Result<()> {
+        if ifname.len() >= libc::IFNAMSIZ || ifname.contains('\0') {
+            return Err(ICError::NoSuchDevice(ifname.to_string()));
+        }
+        let res = unsafe {
+            libc::setsockopt(self.sock, libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                             ifname.as_ptr() as *const c_void, ifname.len() as socklen_t)
+        };
+        match nix::Errno::result(res) {
+            Ok(_)                                    => Ok(()),
+            Err(nix::Error::Sys(nix::Errno::ENODEV)) => Err(ICError::NoSuchDevice(ifname.into())),
+            Err(e)                                   => Err(ICError::Nix(e)),
+        }
+    }
+
+    /// Only receive the packets sent to the local address `addr`, and send from it. With
+    /// `bind_device` as well, `addr` must belong to that interface or nothing is received. Packets
+    /// of a communicator made `with_hdrincl` keep the source address given there. Our own packets
+    /// are recognized by their nonce, not their addresses, so binding doesn't change which ones
+    /// are ignored: those sent to `addr` still loop back to us and are dropped.
+    pub fn bind(&self, addr: net::IpAddr) -> Result<()> {
+        // raw IPv4 sockets read the address of whatever they are given
+        match (addr, self.family) {
+            (net::IpAddr::V4(_), AddressFamily::Inet)  |
+            (net::IpAddr::V6(_), AddressFamily::Inet6) => {}
+            _ => return Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAFNOSUPPORT))),
+        }
+        let addr = SockAddr::Inet(InetAddr::from_std(&net::SocketAddr::new(addr, 0)));
+        bind(self.sock, &addr).map_err(ICError::Nix)
+    }
+
+    /// Return the traffic counters of this communicator.
+    pub fn stats(&self) -> CommStats {
+        self.counters.snapshot()
+    }
+
+    /// Bytes added to the data of each packet on the wire: IP and ICMP headers and our nonce. To
+    /// avoid fragmentation on a link of MTU `mtu`, send at most `mtu - overhead()` bytes.
+    pub fn overhead(&self) -> usize {
+        let ip_size = match self.family {
+            AddressFamily::Inet6 => IPV6_SIZE,
+            _                    => IP_SIZE,
+        };
+        ip_size + PKT_HEADER.len() + NONCE_SIZE
+    }
+
+    /// Largest data we can receive without `ICError::Truncated`, see `with_recv_bufsize`
+    pub fn max_payload(&self) -> usize {
+        // datagram sockets don't hand us the IP header
+        let ip_size = if self.socktype == SockType::Datagram { 0 } else { self.ip_size_() };
+        self.recv_bufsize.saturating_sub(ip_size + PKT_HEADER.len() + NONCE_SIZE)
+    }
+
+    pub fn family(&self) -> AddressFamily {
+        self.family
+    }
+
+    /// The socket of the communicator, to wait for it to be readable or to read options the
+    /// communicator has no method for. Changing its options, its flags or closing it behind the
+    /// back of the communicator is not supported.
     pub fn rawfd(&self) -> &RawFd {
         &self.sock
     }
@@ -56,38 +728,156 @@ impl IcmpCommunicator {
         res.map_err(ICError::Nix)
     }
 
-    /// Send the data contained in `buf` to `peer` inside an ICMP packet.
+    /// Send the data contained in `buf` to `peer` inside an ICMP packet, return `buf.len()`.
+    /// Fails with `ShortWrite` if the socket takes only part of the packet.
     pub fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        let mut own;
+        let mut guard;
+        let data = match self.send_buf.try_lock() {
+            Ok(buf) => { guard = buf; &mut *guard }
+            Err(_)  => { own = Vec::new(); &mut own }
+        };
+        let size = PKT_HEADER.len()+NONCE_SIZE+buf.len();
+        if !self.pacer.lock().unwrap().take(size, Instant::now()) {
+            return Err(ICError::PaceLimited);
+        }
+        if let Err(e) = self.send_packet_(buf, peer, data) {
+            self.pacer.lock().unwrap().refund(size, Instant::now());
+            return Err(e);
+        }
 
-        // first add the header
-        let mut data = PKT_HEADER.to_vec();
+        Counters::add(&self.counters.packets_sent, 1);
+        Counters::add(&self.counters.bytes_sent, buf.len());
+        Ok(buf.len())
+    }
+
+    // Build the packet carrying `buf` into `data` and send it to `peer`
+    fn send_packet_(&self, buf: &[u8], peer: InetAddr, data: &mut Vec<u8>) -> Result<()> {
+        self.packet_(buf, peer, data)?;
+        let addr = SockAddr::Inet(peer);
+        let sent = sendto(self.sock, data, &addr, MsgFlags::empty()).map_err(ICError::Nix)?;
+        if sent < data.len() {
+            return Err(ICError::ShortWrite { sent, expected: data.len() });
+        }
+        Ok(())
+    }
+
+    /// Send each buffer of `bufs` to `peer` inside its own ICMP packet, using a single system call
+    /// where supported. Return how many buffers were sent, which may be less than `bufs.len()`.
+    pub fn sendto_batch(&self, bufs: &[&[u8]], peer: InetAddr) -> Result<usize> {
+        // only send what the rate limit lets through
+        let now = Instant::now();
+        let allowed = {
+            let mut pacer = self.pacer.lock().unwrap();
+            bufs.iter().take_while(|buf| pacer.take(PKT_HEADER.len()+NONCE_SIZE+buf.len(), now))
+                .count()
+        };
+        if allowed == 0 && !bufs.is_empty() {
+            return Err(ICError::PaceLimited);
+        }
+
+        let res = bufs[..allowed].iter().map(|buf| {
+            let mut data = Vec::new();
+            self.packet_(buf, peer, &mut data).map(|_| data)
+        }).collect::<Result<Vec<Vec<u8>>>>().and_then(|pkts| {
+            self.sendmmsg_(&pkts, &SockAddr::Inet(peer))
+        });
+        // the budget of what didn't leave is given back
+        let sent = res.as_ref().map_or(0, |&sent| sent);
+        if sent < allowed {
+            let mut pacer = self.pacer.lock().unwrap();
+            for buf in &bufs[sent..allowed] {
+                pacer.refund(PKT_HEADER.len()+NONCE_SIZE+buf.len(), now);
+            }
+        }
+        let sent = res?;
+
+        Counters::add(&self.counters.packets_sent, sent);
+        Counters::add(&self.counters.bytes_sent, bufs[..sent].iter().map(|b| b.len()).sum());
+        Ok(sent)
+    }
 
-        // add this comminucator's id
-        data[1] = self.id;
+    // Build the packet carrying `buf` to `peer` into `data`, which is only reallocated if it is
+    // too small
+    #[cfg_attr(not(feature = "hdrincl"), allow(unused_variables))]
+    fn packet_(&self, buf: &[u8], peer: InetAddr, data: &mut Vec<u8>) -> Result<()> {
+        data.clear();
+
+        // first add the header
+        if self.socktype == SockType::Datagram {
+            // the kernel fills in everything but our id and magic
+            data.extend_from_slice(DGRAM_HEADER);
+            data[6] = self.id;
+            data[7] = self.magic;
+        } else {
+            // set the echo type and add this comminucator's id, magic and the next sequence number
+            let seq = self.echo_seq.fetch_add(1, Ordering::Relaxed);
+            data.extend_from_slice(PKT_HEADER);
+            data[0] = self.send_type_();
+            data[1] = self.icmp_code;
+            data[4] = self.magic;
+            data[5] = self.id;
+            data[6] = (seq >> 8)   as u8;
+            data[7] = (seq & 0xFF) as u8;
+        }
+        data.extend_from_slice(&self.nonce);
 
         // add user data
         data.extend_from_slice(buf);
 
-        // compute the checksum
-        let mut accum: u64 = 0;
-        for (i, &b) in data.iter().enumerate() {
-            accum += (b as u64) << (8 * (i % 2));
-        }
-        while (accum >> 16) > 0 {
-            accum = (accum & 0xFFFF) + (accum >> 16);
+        // write the checsum in the header. With ICMPv6 the kernel overwrites it since the checksum
+        // also covers an IPv6 pseudo-header.
+        let accum = checksum(data);
+        data[2] = (accum >> 8)   as u8;
+        data[3] = (accum & 0xFF) as u8;
+
+        #[cfg(feature = "hdrincl")]
+        {
+            if let Some(src) = self.spoof_src {
+                let dst = match peer.to_std() {
+                    net::SocketAddr::V4(addr) => *addr.ip(),
+                    _ => return Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAFNOSUPPORT))),
+                };
+                let ip_hdr = ip_header(src, dst, data.len());
+                data.splice(..0, ip_hdr.iter().cloned());
+            }
         }
-        accum = !accum;
+        Ok(())
+    }
 
-        // write the checsum in the header; we need to swap bytes because of the way we computed
-        // the checksum
-        data[2] = (accum & 0xFF) as u8;
-        data[3] = (accum >> 8)   as u8;
+    #[cfg(target_os = "linux")]
+    fn sendmmsg_(&self, pkts: &[Vec<u8>], addr: &SockAddr) -> Result<usize> {
+        let (name, namelen) = unsafe { addr.as_ffi_pair() };
 
-        // Finally, send
-        let addr = SockAddr::Inet(peer);
-        sendto(self.sock, &data, &addr, MsgFlags::empty())
-            .map_err(ICError::Nix)
-            .map    (|s| if s > PKT_HEADER.len() { s - PKT_HEADER.len() } else { 0 })
+        let mut iovs: Vec<libc::iovec> = pkts.iter().map(|pkt| libc::iovec {
+            iov_base: pkt.as_ptr() as *mut c_void,
+            iov_len:  pkt.len(),
+        }).collect();
+
+        let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name    = name as *const libc::sockaddr as *mut c_void;
+            msg.msg_hdr.msg_namelen = namelen;
+            msg.msg_hdr.msg_iov     = iov;
+            msg.msg_hdr.msg_iovlen  = 1;
+            msg
+        }).collect();
+
+        let res = unsafe {
+            libc::sendmmsg(self.sock, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0)
+        };
+        nix::Errno::result(res).map(|n| n as usize).map_err(ICError::Nix)
+    }
+
+    // no sendmmsg(2) here, send packets one by one
+    #[cfg(not(target_os = "linux"))]
+    fn sendmmsg_(&self, pkts: &[Vec<u8>], addr: &SockAddr) -> Result<usize> {
+        for (i, pkt) in pkts.iter().enumerate() {
+            if let Err(e) = sendto(self.sock, pkt, addr, MsgFlags::empty()) {
+                return if i == 0 { Err(ICError::Nix(e)) } else { Ok(i) };
+            }
+        }
+        Ok(pkts.len())
     }
 
     /// Read an ICMP packet. If the packet looks like regular ICMP trafic Ok(None) is returned;
@@ -95,71 +885,1406 @@ impl IcmpCommunicator {
     /// of `buf`'s size) along with its origin is returned. If `buf` is smaller than the message's
     /// length, then only `buf.len()` bytes are copied.
     pub fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
-        let mut data = [0; 4096];
+        self.recvfrom_meta(buf).map(|r| r.map(|(sz, peer, _)| (sz, peer)))
+    }
+
+    /// Same as `recvfrom` but, on a non blocking communicator, Ok(None) is also returned when
+    /// there is nothing to read.
+    pub fn try_recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        match self.recvfrom(buf) {
+            Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN))) => Ok(None),
+            res => res,
+        }
+    }
+
+    /// Same as `recvfrom` but also return what we know about the packet that carried the message.
+    pub fn recvfrom_meta(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr, PacketMeta)>> {
+        self.recv_(buf).map(|r| r.ok())
+    }
+
+    /// Same as `recvfrom` but tell why a packet was dropped instead of returning Ok(None), and
+    /// report the ICMP errors about the packets we sent.
+    pub fn recvfrom_reason(&self, buf: &mut [u8]) -> Result<RecvOutcome> {
+        Ok(match self.recv_(buf)? {
+            Ok((sz, peer, _)) => RecvOutcome::Message(sz, peer),
+            Err(outcome)      => outcome,
+        })
+    }
+
+    fn recv_(&self, buf: &mut [u8]) -> Result<result::Result<Message, RecvOutcome>> {
+        let mut own;
+        let mut guard;
+        let data = match self.recv_buf.try_lock() {
+            Ok(buf) => { guard = buf; &mut *guard }
+            Err(_)  => { own = vec![0; self.recv_bufsize]; &mut own }
+        };
+
+        let (sz, addr, ttl) = self.recvmsg_(data)?;
+        if sz > data.len() {
+            return Err(ICError::Truncated(sz));
+        }
+
+        Ok(self.decode_(&data[..sz], addr, ttl, buf))
+    }
+
+    /// Read as many ICMP packets as are already queued, up to `bufs.len()`, in a single system
+    /// call where supported; block until there is at least one. Messages are filtered and copied
+    /// like in `recvfrom`, and stored one per buffer: the i-th element of the returned vector is
+    /// the length and origin of the message stored in `bufs[i]`. Packets too large for the receive
+    /// buffer are dropped.
+    pub fn recvfrom_batch(&self, bufs: &mut [&mut [u8]]) -> Result<Vec<(usize, InetAddr)>> {
+        let mut data = vec![vec![0; self.recv_bufsize]; bufs.len()];
+
+        let pkts = self.recvmmsg_(&mut data)?;
+
+        let mut msgs = Vec::new();
+        for (data, &(sz, addr, ttl)) in data.iter().zip(pkts.iter()) {
+            if sz > data.len() {
+                continue;
+            }
+            if let Ok((sz, peer, _)) = self.decode_(&data[..sz], addr, ttl, bufs[msgs.len()]) {
+                msgs.push((sz, peer));
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// Iterate over the messages received, skipping the packets `recvfrom` drops. The iteration
+    /// ends when there is nothing to read on a non blocking communicator, or when the receive
+    /// timeout of a blocking one expires.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming {
+            com: self,
+            buf: vec![0; self.max_payload()],
+        }
+    }
+
+    // Check that `data` is one of our packets and, if so, copy its message to `buf`
+    fn decode_(&self, data: &[u8], addr: Option<InetAddr>, ttl: Option<u8>, buf: &mut [u8])
+      -> result::Result<Message, RecvOutcome> {
+        let msg = match self.icmp_error_(data, addr) {
+            Some(report) => Err(RecvOutcome::IcmpError(report)),
+            None         => self.parse_(data, addr, ttl, buf).map_err(RecvOutcome::Dropped),
+        };
+        match msg {
+            Ok((sz, _, _)) => {
+                Counters::add(&self.counters.packets_received, 1);
+                Counters::add(&self.counters.bytes_received, sz);
+            }
+            Err(_) => Counters::add(&self.counters.packets_dropped, 1),
+        }
+        msg
+    }
+
+    // The report of an ICMP error message about one of our packets, which starts with the IP
+    // header of the packet (IPv4 or IPv6, as the socket) and the first 8 bytes of its ICMP header
+    fn icmp_error_(&self, data: &[u8], addr: Option<InetAddr>) -> Option<IcmpReport> {
+        if self.socktype == SockType::Datagram {
+            return None;
+        }
+        let icmp = data.get(self.ip_size_()..)?;
+        let kind = match (self.family, *icmp.first()?) {
+            (AddressFamily::Inet6, 1) => IcmpErrorKind::Unreachable,
+            (AddressFamily::Inet6, 3) => IcmpErrorKind::TimeExceeded,
+            (AddressFamily::Inet6, _) => return None,
+            (_, 3)                    => IcmpErrorKind::Unreachable,
+            (_, 11)                   => IcmpErrorKind::TimeExceeded,
+            _                         => return None,
+        };
+        let orig = icmp.get(PKT_HEADER.len()..)?;
+        let (dest, orig_icmp) = if self.family == AddressFamily::Inet6 {
+            if orig.len() < IPV6_SIZE || orig[0] >> 4 != 6 || orig[6] != 58 /* ICMPv6 */ {
+                return None;
+            }
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&orig[24..40]);
+            (net::IpAddr::V6(net::Ipv6Addr::from(ip)), &orig[IPV6_SIZE..])
+        } else {
+            if orig.len() < IP_SIZE || orig[0] >> 4 != 4 || orig[9] != 0x01 /* ICMP */ {
+                return None;
+            }
+            let ip_size = (orig[0] & 0x0f) as usize * 4;
+            let ip = net::Ipv4Addr::new(orig[16], orig[17], orig[18], orig[19]);
+            (net::IpAddr::V4(ip), orig.get(ip_size..)?)
+        };
+        // what we sent, with our signature
+        if orig_icmp.len() < PKT_HEADER.len() || orig_icmp[0] != self.send_type_()
+            || orig_icmp[4] != self.magic || orig_icmp[5] != self.id {
+            return None;
+        }
+        let from = match addr {
+            Some(addr) => addr,
+            None       => ip_source(data)?,
+        };
+        Some(IcmpReport {
+            kind,
+            code: icmp[1],
+            from,
+            dest: InetAddr::from_std(&net::SocketAddr::new(dest, 0)),
+        })
+    }
 
-        let (sz, addr) = recvfrom(self.sock, &mut data).map_err(ICError::Nix)?;
+    fn parse_(&self, data: &[u8], addr: Option<InetAddr>, ttl: Option<u8>, buf: &mut [u8])
+      -> result::Result<Message, DropReason> {
 
-        if sz < IP_SIZE+PKT_HEADER.len() {
-            return Ok(None);
+        let (ip_size, hdr_size, id_idx, magic_idx) = match self.socktype {
+            SockType::Datagram => (0, DGRAM_HEADER.len(), 6, 7),
+            _                  => (self.ip_size_(), PKT_HEADER.len(), 5, 4),
+        };
+        if data.len() < ip_size+hdr_size+NONCE_SIZE {
+            return Err(DropReason::TooShort);
         }
+        // the kernel should always tell us where the packet is from, but the IPv4 header has it too
+        let addr = match addr {
+            Some(addr)                 => addr,
+            None if ip_size == IP_SIZE => ip_source(data).ok_or(DropReason::NoSource)?,
+            None                       => return Err(DropReason::NoSource),
+        };
 
-        let data      = &data[..sz];
-        let icmp_data = &data[IP_SIZE..];
-        let user_data = &icmp_data[PKT_HEADER.len()..];
+        let icmp_data = &data[ip_size..];
+        let nonce     = &icmp_data[hdr_size..hdr_size+NONCE_SIZE];
+        let user_data = &icmp_data[hdr_size+NONCE_SIZE..];
 
-        if icmp_data[0] != 0x00 {
-            // not an ICMP echo request
-            return Ok(None);
+        if icmp_data[0] != self.recv_type_() {
+            // not the ICMP echo message we expect
+            return Err(DropReason::NotEcho);
         }
-        if icmp_data[1] == 0x00 {
+        if icmp_data[magic_idx] != self.magic || icmp_data[id_idx] == 0x00 {
             // our signature is not there => this is probably some other icmp trafic
-            return Ok(None);
+            return Err(DropReason::NotOurs);
+        }
+        if nonce == self.nonce {
+            // this packet was emmited by us (in datagram mode: the peer's kernel answered our own
+            // echo request), ignore it
+            return Err(DropReason::OwnPacket);
         }
-        if icmp_data[1] == self.id {
-            // this packet was emmited using our id, ignore it
-            return Ok(None);
+        if self.verify_checksum.load(Ordering::Relaxed) && self.socktype == SockType::Raw
+            && self.family == AddressFamily::Inet && checksum(icmp_data) != 0 {
+            // corrupted packet; summing over the checksum field itself yields 0 when it is right
+            Counters::add(&self.counters.checksum_failures, 1);
+            return Err(DropReason::BadChecksum);
         }
-        // bytes at idx 2 and 3 are the checksum, skip them
 
-        match addr {
-            SockAddr::Inet(peer) => {
-                let copysize = cmp::min(buf.len(), user_data.len());
-                buf[..copysize].copy_from_slice(&user_data[..copysize]);
-                return Ok(Some((user_data.len(), peer)));
+        if let Some(ip) = *self.peer_filter.lock().unwrap() {
+            if ip != addr.to_std().ip() {
+                // not the peer we were told to listen to
+                return Err(DropReason::NotPeer);
             }
-            _ => unreachable!()
         }
+
+        let meta = PacketMeta {
+            ttl,
+            icmp_type: icmp_data[0],
+            icmp_code: icmp_data[1],
+        };
+
+        let copysize = cmp::min(buf.len(), user_data.len());
+        buf[..copysize].copy_from_slice(&user_data[..copysize]);
+        Ok((user_data.len(), addr, meta))
     }
-}
 
+    // recvfrom(2) along with the TTL of the packet, if the kernel sent it as ancillary data. nix's
+    // recvmsg doesn't let us decode control messages, hence libc. Thanks to MSG_TRUNC, the size
+    // returned is the one of the packet even if it did not fit in `data`.
+    fn recvmsg_(&self, data: &mut [u8]) -> Result<RawPacket> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut cmsg = [0u64; 16];
+        let mut iov  = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut c_void,
+            iov_len:  data.len(),
+        };
+        let mut mhdr = msghdr(&mut addr, &mut iov, &mut cmsg);
 
-impl Drop for IcmpCommunicator {
-    fn drop(&mut self) {
-        self.close().ok();
+        let res = unsafe { libc::recvmsg(self.sock, &mut mhdr, libc::MSG_TRUNC) };
+        let sz  = nix::Errno::result(res).map_err(ICError::Nix)? as usize;
+
+        Ok((sz, sockaddr_to_inet(&addr), cmsg_ttl(&mhdr)))
     }
-}
 
+    // Same as `recvmsg_` for as many packets as there are buffers in `data`
+    #[cfg(target_os = "linux")]
+    fn recvmmsg_(&self, data: &mut [Vec<u8>]) -> Result<Vec<RawPacket>> {
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; data.len()];
+        let mut cmsgs = vec![[0u64; 16]; data.len()];
+        let mut iovs: Vec<libc::iovec> = data.iter_mut().map(|data| libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut c_void,
+            iov_len:  data.len(),
+        }).collect();
 
-impl Evented for IcmpCommunicator {
-    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
-      -> io::Result<()> {
-        EventedFd(&self.sock).register(poll, token, interest, opts)
+        let mut msgs: Vec<libc::mmsghdr> = addrs.iter_mut()
+            .zip(iovs.iter_mut())
+            .zip(cmsgs.iter_mut())
+            .map(|((addr, iov), cmsg)| libc::mmsghdr {
+                msg_hdr: msghdr(addr, iov, cmsg),
+                msg_len: 0,
+            })
+            .collect();
+
+        let res = unsafe {
+            libc::recvmmsg(self.sock, msgs.as_mut_ptr(), msgs.len() as libc::c_uint,
+                           libc::MSG_WAITFORONE | libc::MSG_TRUNC, ptr::null_mut())
+        };
+        let n = nix::Errno::result(res).map_err(ICError::Nix)? as usize;
+
+        Ok(msgs[..n].iter().zip(addrs.iter())
+           .map(|(msg, addr)| {
+               (msg.msg_len as usize, sockaddr_to_inet(addr), cmsg_ttl(&msg.msg_hdr))
+           })
+           .collect())
     }
 
-    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
-      -> io::Result<()> {
-        EventedFd(&self.sock).reregister(poll, token, interest, opts)
+    // no recvmmsg(2) here, only read one packet
+    #[cfg(not(target_os = "linux"))]
+    fn recvmmsg_(&self, data: &mut [Vec<u8>]) -> Result<Vec<RawPacket>> {
+        match data.first_mut() {
+            Some(data) => self.recvmsg_(data).map(|pkt| vec![pkt]),
+            None       => Ok(Vec::new()),
+        }
     }
 
-    fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        EventedFd(&self.sock).deregister(poll)
+    fn ttl_sockopt_(&self) -> (c_int, c_int) {
+        match self.family {
+            AddressFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+            _                    => (libc::IPPROTO_IP,   libc::IP_TTL),
+        }
+    }
+
+    fn tos_sockopt_(&self) -> (c_int, c_int) {
+        match self.family {
+            AddressFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+            _                    => (libc::IPPROTO_IP,   libc::IP_TOS),
+        }
+    }
+
+    // nix only knows about a handful of socket options, so we call into libc for the others
+    fn setsockopt_int_(&self, level: c_int, name: c_int, val: c_int) -> Result<()> {
+        let res = unsafe {
+            libc::setsockopt(self.sock, level, name, &val as *const c_int as *const c_void,
+                             mem::size_of::<c_int>() as socklen_t)
+        };
+        nix::Errno::result(res).map(drop).map_err(ICError::Nix)
+    }
+
+    fn getsockopt_int_(&self, level: c_int, name: c_int) -> Result<c_int> {
+        let mut val: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let res = unsafe {
+            libc::getsockopt(self.sock, level, name, &mut val as *mut c_int as *mut c_void,
+                             &mut len)
+        };
+        nix::Errno::result(res).map(|_| val).map_err(ICError::Nix)
+    }
+
+    fn send_type_(&self) -> u8 {
+        let (request, reply) = self.echo_types_();
+        match self.role {
+            EchoRole::Client => request,
+            _                => reply,
+        }
+    }
+
+    fn recv_type_(&self) -> u8 {
+        let (request, reply) = self.echo_types_();
+        match self.role {
+            // datagram communicators only get replies, whatever the role
+            EchoRole::Server if self.socktype != SockType::Datagram => request,
+            _                                                       => reply,
+        }
+    }
+
+    // The types of echo requests and replies
+    fn echo_types_(&self) -> (u8, u8) {
+        match self.family {
+            AddressFamily::Inet6 => (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY),
+            _                    => (ICMP_ECHO_REQUEST,   ICMP_ECHO_REPLY),
+        }
+    }
+
+    // Size of the IP header preceding the ICMP message in received packets. Raw ICMPv6 sockets
+    // never hand us the 40 bytes IPv6 header (RFC 3542, section 3), unlike raw IPv4 sockets.
+    fn ip_size_(&self) -> usize {
+        match self.family {
+            AddressFamily::Inet6 => 0,
+            _                    => IP_SIZE,
+        }
+    }
+}
+
+
+// Build a msghdr receiving one packet into `iov`, its origin into `addr` and control messages into
+// `cmsg`
+fn msghdr(addr: &mut libc::sockaddr_storage, iov: &mut libc::iovec, cmsg: &mut [u64; 16])
+  -> libc::msghdr {
+    let mut mhdr: libc::msghdr = unsafe { mem::zeroed() };
+    mhdr.msg_name       = addr as *mut libc::sockaddr_storage as *mut c_void;
+    mhdr.msg_namelen    = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    mhdr.msg_iov        = iov;
+    mhdr.msg_iovlen     = 1;
+    mhdr.msg_control    = cmsg.as_mut_ptr() as *mut c_void;
+    mhdr.msg_controllen = mem::size_of_val(cmsg) as _;
+    mhdr
+}
+
+// Look for the TTL (or hop limit) in the control messages received along with a packet
+fn cmsg_ttl(mhdr: &libc::msghdr) -> Option<u8> {
+    let mut ttl = None;
+    unsafe {
+        let mut c = libc::CMSG_FIRSTHDR(mhdr);
+        while !c.is_null() {
+            match ((*c).cmsg_level, (*c).cmsg_type) {
+                (libc::IPPROTO_IP,   libc::IP_TTL) |
+                (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                    let val = ptr::read_unaligned(libc::CMSG_DATA(c) as *const c_int);
+                    ttl = Some(val as u8);
+                }
+                _ => {}
+            }
+            c = libc::CMSG_NXTHDR(mhdr, c);
+        }
+    }
+    ttl
+}
+
+fn sockaddr_to_inet(addr: &libc::sockaddr_storage) -> Option<InetAddr> {
+    let sa = addr as *const libc::sockaddr_storage;
+    match addr.ss_family as c_int {
+        libc::AF_INET  => Some(InetAddr::V4(unsafe { *(sa as *const libc::sockaddr_in)  })),
+        libc::AF_INET6 => Some(InetAddr::V6(unsafe { *(sa as *const libc::sockaddr_in6) })),
+        _              => None,
+    }
+}
+
+// Source address of the IPv4 packet starting with `data`
+fn ip_source(data: &[u8]) -> Option<InetAddr> {
+    if data.len() < IP_SIZE || data[0] >> 4 != 4 {
+        return None;
+    }
+    let ip = net::Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+    Some(InetAddr::from_std(&net::SocketAddr::new(net::IpAddr::V4(ip), 0)))
+}
+
+// IPv4 header for an ICMP packet of `len` bytes from `src` to `dst`. The kernel picks the packet
+// id since it is left to 0.
+#[cfg(feature = "hdrincl")]
+fn ip_header(src: net::Ipv4Addr, dst: net::Ipv4Addr, len: usize) -> [u8; IP_SIZE] {
+    let mut hdr = [0; IP_SIZE];
+    let total = IP_SIZE + len;
+    hdr[0] = 0x45; // IPv4, 5 words long header
+    hdr[2] = (total >> 8)   as u8;
+    hdr[3] = (total & 0xFF) as u8;
+    hdr[8] = 64;   // TTL
+    hdr[9] = 0x01; // IPPROTO_ICMP
+    hdr[12..16].copy_from_slice(&src.octets());
+    hdr[16..20].copy_from_slice(&dst.octets());
+    let accum = checksum(&hdr);
+    hdr[10] = (accum >> 8)   as u8;
+    hdr[11] = (accum & 0xFF) as u8;
+    hdr
+}
+
+// Sizes beyond what the kernel takes are capped by the kernel anyway
+fn sockopt_size(size: usize) -> c_int {
+    cmp::min(size, c_int::MAX as usize) as c_int
+}
+
+// The error of socket(2), telling apart the common case of missing privileges
+fn socket_error(e: nix::Error) -> ICError {
+    match e {
+        nix::Error::Sys(nix::Errno::EPERM) |
+        nix::Error::Sys(nix::Errno::EACCES) => ICError::PermissionDenied,
+        e                                   => ICError::Nix(e),
+    }
+}
+
+// Random bytes from the system, or if that fails, bytes that still differ between the
+// communicators of this host
+fn random_nonce() -> [u8; NONCE_SIZE] {
+    static CREATED: AtomicU64 = AtomicU64::new(0);
+
+    let mut nonce = [0; NONCE_SIZE];
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut nonce)).is_err() {
+        let now  = SystemTime::now().duration_since(UNIX_EPOCH);
+        let now  = now.map_or(0, |d| d.as_nanos() as u64);
+        let seed = now ^ (process::id() as u64) << 32 ^ CREATED.fetch_add(1, Ordering::Relaxed);
+        for (i, b) in nonce.iter_mut().enumerate() {
+            *b = (seed >> (8 * i) ^ seed >> (8 * (i + 4))) as u8;
+        }
+    }
+    nonce
+}
+
+// Compute the internet checksum of `data` (RFC 1071), to be written in network byte order. An odd
+// byte at the end is summed as the high byte of a last, zero padded, 16 bits word.
+fn checksum(data: &[u8]) -> u16 {
+    let mut accum: u64 = 0;
+    for word in data.chunks(2) {
+        let lo = if word.len() == 2 { word[1] } else { 0 };
+        accum += (word[0] as u64) << 8 | lo as u64;
+    }
+    while (accum >> 16) > 0 {
+        accum = (accum & 0xFFFF) + (accum >> 16);
+    }
+    !accum as u16
+}
+
+
+/// The options of a communicator, applied by `build` in one go: either we get a communicator with
+/// all of them, or an error and no socket left behind. Options not set keep the defaults of
+/// `new`, e.g. `IcmpCommunicator::builder(1).magic(0x42).ttl(32).nonblocking(true).build()`.
+#[derive(Debug, Clone)]
+pub struct IcmpCommunicatorBuilder {
+    id:              u8,
+    magic:           u8,
+    family:          AddressFamily,
+    socktype:        SockType,
+    role:            EchoRole,
+    icmp_code:       u8,
+    recv_bufsize:    usize,
+    ttl:             Option<u8>,
+    nonblocking:     bool,
+    device:          Option<String>,
+    local_addr:      Option<net::IpAddr>,
+    peer_filter:     Option<net::IpAddr>,
+    verify_checksum: bool,
+}
+
+impl IcmpCommunicatorBuilder {
+
+    pub fn new(id: u8) -> IcmpCommunicatorBuilder {
+        IcmpCommunicatorBuilder {
+            id,
+            magic:           DEFAULT_MAGIC,
+            family:          AddressFamily::Inet,
+            socktype:        SockType::Raw,
+            role:            EchoRole::Reply,
+            icmp_code:       0,
+            recv_bufsize:    DEFAULT_RECV_BUFSIZE,
+            ttl:             None,
+            nonblocking:     false,
+            device:          None,
+            local_addr:      None,
+            peer_filter:     None,
+            verify_checksum: true,
+        }
+    }
+
+    /// See `IcmpCommunicator::with_magic`
+    pub fn magic(mut self, magic: u8) -> IcmpCommunicatorBuilder {
+        self.magic = magic;
+        self
+    }
+
+    /// Use ICMPv6, see `IcmpCommunicator::new_v6`
+    pub fn v6(mut self) -> IcmpCommunicatorBuilder {
+        self.family = AddressFamily::Inet6;
+        self
+    }
+
+    /// Use an unprivileged ICMP socket, see `IcmpCommunicator::new_dgram`
+    pub fn dgram(mut self) -> IcmpCommunicatorBuilder {
+        self.socktype = SockType::Datagram;
+        self
+    }
+
+    /// See `IcmpCommunicator::with_echo_role`
+    pub fn echo_role(mut self, role: EchoRole) -> IcmpCommunicatorBuilder {
+        self.role = role;
+        self
+    }
+
+    /// See `IcmpCommunicator::with_icmp_code`
+    pub fn icmp_code(mut self, code: u8) -> IcmpCommunicatorBuilder {
+        self.icmp_code = code;
+        self
+    }
+
+    /// See `IcmpCommunicator::with_recv_bufsize`
+    pub fn recv_bufsize(mut self, size: usize) -> IcmpCommunicatorBuilder {
+        self.recv_bufsize = size;
+        self
+    }
+
+    /// See `IcmpCommunicator::set_ttl`
+    pub fn ttl(mut self, ttl: u8) -> IcmpCommunicatorBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// See `IcmpCommunicator::set_nonblocking`
+    pub fn nonblocking(mut self, nonblocking: bool) -> IcmpCommunicatorBuilder {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// See `IcmpCommunicator::bind_device`
+    pub fn bind_device(mut self, ifname: &str) -> IcmpCommunicatorBuilder {
+        self.device = Some(ifname.to_string());
+        self
+    }
+
+    /// See `IcmpCommunicator::bind`
+    pub fn bind(mut self, addr: net::IpAddr) -> IcmpCommunicatorBuilder {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// See `IcmpCommunicator::set_peer_filter`
+    pub fn peer_filter(mut self, addr: Option<net::IpAddr>) -> IcmpCommunicatorBuilder {
+        self.peer_filter = addr;
+        self
+    }
+
+    /// See `IcmpCommunicator::set_verify_checksum`
+    pub fn verify_checksum(mut self, verify: bool) -> IcmpCommunicatorBuilder {
+        self.verify_checksum = verify;
+        self
+    }
+
+    pub fn build(&self) -> Result<IcmpCommunicator> {
+        let proto = match self.family {
+            AddressFamily::Inet6 => 58,   /* IPPROTO_ICMPV6 */
+            _                    => 0x01, /* IPPROTO_ICMP */
+        };
+        // dropped, and its socket closed, if an option fails
+        let com = IcmpCommunicator::open_(self.id, self.magic, self.family, self.socktype, proto)?
+            .with_echo_role(self.role)
+            .with_icmp_code(self.icmp_code)
+            .with_recv_bufsize(self.recv_bufsize);
+
+        if let Some(ref ifname) = self.device {
+            com.bind_device(ifname)?;
+        }
+        if let Some(addr) = self.local_addr {
+            com.bind(addr)?;
+        }
+        if let Some(ttl) = self.ttl {
+            com.set_ttl(ttl)?;
+        }
+        com.set_peer_filter(self.peer_filter);
+        com.set_verify_checksum(self.verify_checksum);
+        com.set_nonblocking(self.nonblocking)?;
+        Ok(com)
+    }
+}
+
+impl Drop for IcmpCommunicator {
+    fn drop(&mut self) {
+        self.close().ok();
+    }
+}
+
+
+impl Evented for IcmpCommunicator {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+      -> io::Result<()> {
+        EventedFd(&self.sock).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+      -> io::Result<()> {
+        EventedFd(&self.sock).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.sock).deregister(poll)
+    }
+}
+
+/// Iterator over the messages received by a communicator, with their origin, see
+/// `IcmpCommunicator::incoming`.
+pub struct Incoming<'a> {
+    com: &'a IcmpCommunicator,
+    buf: Vec<u8>,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<(Vec<u8>, InetAddr)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.com.recvfrom(&mut self.buf) {
+                Ok(Some((n, peer))) => return Some(Ok((self.buf[..n].to_vec(), peer))),
+                Ok(None)            => continue,
+                Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN))) => return None,
+                Err(e)              => return Some(Err(e)),
+            }
+        }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use self::nix::sys::time::{TimeVal, TimeValLike};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::time::Instant;
+
+    // Counts the allocations made by each thread, to check that hot paths do not allocate
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCS.with(Cell::get)
+    }
+
+    // Every raw socket sees every ICMP packet on the host, so keep reading until the expected
+    // payload shows up; the receive timeout turns a lost packet into a test failure.
+    fn recv_expected(com: &IcmpCommunicator, expected: &[u8]) -> InetAddr {
+        recv_expected_into(com, expected, &mut [0; 64])
+    }
+
+    fn recv_expected_into(com: &IcmpCommunicator, expected: &[u8], buf: &mut [u8]) -> InetAddr {
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        loop {
+            if let Some((n, peer)) = com.recvfrom(buf).expect("no packet received") {
+                if buf.get(..n) == Some(expected) {
+                    return peer;
+                }
+            }
+        }
+    }
+
+    // Check that `com` dropped the packets carrying `unexpected`. Since packets are queued on every
+    // raw socket at once, this can be called as soon as another communicator received it.
+    fn recv_unexpected(com: &IcmpCommunicator, unexpected: &[u8]) {
+        com.set_nonblocking(true).unwrap();
+        let mut buf = [0; 64];
+        loop {
+            match com.recvfrom(&mut buf) {
+                Ok(Some((n, _))) => assert!(buf.get(..n) != Some(unexpected)),
+                Ok(None)         => {}
+                Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN))) => break,
+                Err(e)           => panic!("{:?}", e),
+            }
+        }
+    }
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn zero_id_is_rejected() {
+        match IcmpCommunicator::new(0) {
+            Err(ICError::InvalidId) => {}
+            Err(e) => panic!("{:?}", e),
+            Ok(_)  => panic!("id 0 was accepted"),
+        }
+    }
+
+    #[test]
+    fn errors_box_into_dyn_error() {
+        fn open() -> result::Result<IcmpCommunicator, Box<dyn error::Error>> {
+            Ok(IcmpCommunicator::new(0)?)
+        }
+        let e = open().err().unwrap();
+        assert_eq!(e.to_string(), "communicator id must be non zero");
+
+        let e = ICError::from(nix::Error::Sys(nix::Errno::EPERM));
+        assert!(error::Error::source(&e).is_some());
+    }
+
+    #[test]
+    fn echo_v4() {
+        let snd = IcmpCommunicator::new(11).unwrap();
+        let rcv = IcmpCommunicator::new(12).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        snd.sendto(b"hello v4", InetAddr::from_std(&addr)).unwrap();
+        let peer = recv_expected(&rcv, b"hello v4");
+        assert_eq!(peer.to_std().ip(), addr.ip());
+    }
+
+    #[test]
+    fn permission_denied() {
+        let denied = |errno| matches!(socket_error(nix::Error::Sys(errno)),
+                                      ICError::PermissionDenied);
+        assert!(denied(nix::Errno::EPERM));
+        assert!(denied(nix::Errno::EACCES));
+        assert!(!denied(nix::Errno::EMFILE));
+    }
+
+    #[test]
+    fn dgram_ignores_kernel_replies() {
+        let com = match IcmpCommunicator::new_dgram(15) {
+            Ok(com) => com,
+            // unprivileged ICMP sockets are disabled by net.ipv4.ping_group_range
+            Err(ICError::PermissionDenied) => return,
+            Err(e) => panic!("{:?}", e),
+        };
+        let tv = TimeVal::milliseconds(500);
+        setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+
+        // the kernel answers our echo request with our own id, which must not be mistaken for
+        // a message from a peer
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        assert_eq!(com.sendto(b"hello dgram", InetAddr::from_std(&addr)).unwrap(), 11);
+        let mut buf = [0; 64];
+        assert!(com.recvfrom(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn checksum_reference() {
+        // example from RFC 1071, section 3
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), 0x220d);
+        // odd length: the last byte is padded on the right
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7, 0xab]), 0x770c);
+        assert_eq!(checksum(b"\x08\x00\x00\x00\x12\x34\x00\x01abc"), 0x2168);
+
+        // a packet including its checksum sums to 0, whatever its length
+        for data in [&b"\x08\x00\x00\x00even"[..], &b"\x08\x00\x00\x00odd"[..]].iter() {
+            let mut pkt = data.to_vec();
+            let accum = checksum(&pkt);
+            pkt[2] = (accum >> 8)   as u8;
+            pkt[3] = (accum & 0xFF) as u8;
+            assert_eq!(checksum(&pkt), 0);
+        }
+    }
+
+    #[test]
+    fn source_from_ip_header() {
+        let snd = IcmpCommunicator::with_magic(50, 0x0a).unwrap();
+        let rcv = IcmpCommunicator::with_magic(51, 0x0a).unwrap();
+        let mut pkt = Vec::new();
+        snd.packet_(b"hello", InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()), &mut pkt)
+            .unwrap();
+
+        let mut data = vec![0; IP_SIZE];
+        data[0] = 0x45;
+        data[12..16].copy_from_slice(&[10, 1, 2, 3]);
+        data.extend_from_slice(&pkt);
+        data[IP_SIZE] = rcv.recv_type_();
+
+        // no address from the kernel: the one in the IP header is used instead of panicking
+        let mut buf = [0; 16];
+        let (n, peer, _) = rcv.parse_(&data, None, None, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(peer.to_std(), "10.1.2.3:0".parse().unwrap());
+
+        // not an IPv4 header
+        data[0] = 0x60;
+        assert_eq!(rcv.parse_(&data, None, None, &mut buf).err(), Some(DropReason::NoSource));
+    }
+
+    #[test]
+    fn bad_checksum_is_dropped() {
+        let snd = IcmpCommunicator::new(16).unwrap();
+        let rcv = IcmpCommunicator::new(17).unwrap();
+        let addr = SockAddr::Inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()));
+
+        // craft a valid packet by hand, then corrupt its payload
+        let mut pkt = vec![0x00, 16, 0x00, 0x00, DEFAULT_MAGIC, 16, 0x00, 0x00];
+        pkt.extend_from_slice(&[0; NONCE_SIZE]);
+        pkt.extend_from_slice(b"corrupted");
+        let accum = checksum(&pkt);
+        pkt[2] = (accum >> 8)   as u8;
+        pkt[3] = (accum & 0xFF) as u8;
+        pkt[PKT_HEADER.len()+NONCE_SIZE] ^= 0x01;
+        sendto(*snd.rawfd(), &pkt, &addr, MsgFlags::empty()).unwrap();
+        snd.sendto(b"intact", InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())).unwrap();
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut buf = [0; 64];
+        loop {
+            if let Some((n, _)) = rcv.recvfrom(&mut buf).expect("no packet received") {
+                assert!(buf.get(..n) != Some(b"borrupted"));
+                if buf.get(..n) == Some(b"intact") {
+                    break;
+                }
+            }
+        }
+        assert!(rcv.stats().checksum_failures >= 1);
+    }
+
+    #[test]
+    fn set_ttl() {
+        let com = IcmpCommunicator::new(18).unwrap();
+        com.set_ttl(1).unwrap();
+        assert_eq!(com.ttl().unwrap(), 1);
+
+        let com = IcmpCommunicator::new_v6(18).unwrap();
+        com.set_ttl(7).unwrap();
+        assert_eq!(com.ttl().unwrap(), 7);
+    }
+
+    #[test]
+    fn socket_options() {
+        let com = IcmpCommunicator::new(59).unwrap();
+        com.set_tos(0xb8).unwrap();
+        assert_eq!(com.tos().unwrap(), 0xb8);
+
+        // the kernel doubles what it is given, and caps it
+        com.set_recv_bufsize(64 * 1024).unwrap();
+        assert!(com.getsockopt_int_(libc::SOL_SOCKET, libc::SO_RCVBUF).unwrap() >= 64 * 1024);
+        com.set_send_bufsize(usize::MAX).unwrap();
+        assert!(com.getsockopt_int_(libc::SOL_SOCKET, libc::SO_SNDBUF).unwrap() > 0);
+
+        let com = IcmpCommunicator::new_v6(59).unwrap();
+        com.set_tos(0x20).unwrap();
+        assert_eq!(com.tos().unwrap(), 0x20);
+    }
+
+    #[test]
+    fn path_mtu() {
+        let com = IcmpCommunicator::new(52).unwrap();
+        com.set_path_mtu_discovery(true).unwrap();
+        // the MTU of lo, capped to the size of the largest IPv4 packet
+        let lo = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        assert_eq!(com.path_mtu(lo).unwrap(), 65535);
+        com.set_path_mtu_discovery(false).unwrap();
+
+        let com = IcmpCommunicator::new_v6(52).unwrap();
+        com.set_path_mtu_discovery(true).unwrap();
+        let lo = InetAddr::from_std(&"[::1]:0".parse().unwrap());
+        assert_eq!(com.path_mtu(lo).unwrap(), 65536);
+    }
+
+    #[test]
+    fn recv_ttl() {
+        let snd = IcmpCommunicator::new(19).unwrap();
+        let rcv = IcmpCommunicator::new(20).unwrap();
+        snd.set_ttl(42).unwrap();
+        snd.sendto(b"ttl 42", InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())).unwrap();
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut buf = [0; 64];
+        loop {
+            if let Some((n, _, meta)) = rcv.recvfrom_meta(&mut buf).expect("no packet received") {
+                if buf.get(..n) == Some(b"ttl 42") {
+                    assert_eq!(meta.ttl, Some(42));
+                    assert_eq!(meta.icmp_type, ICMP_ECHO_REPLY);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn try_recvfrom_does_not_block() {
+        let com = IcmpCommunicator::new(21).unwrap();
+        com.set_nonblocking(true).unwrap();
+
+        // drain whatever other tests emitted, this would hang if the socket were blocking
+        let mut buf = [0; 64];
+        let mut reads = 0;
+        loop {
+            match com.recvfrom(&mut buf) {
+                Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN))) => break,
+                Err(e) => panic!("{:?}", e),
+                Ok(_)  => reads += 1,
+            }
+            assert!(reads < 10000);
+        }
+        assert!(com.try_recvfrom(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn builder() {
+        let com = IcmpCommunicator::builder(53).magic(0x0b).ttl(9).nonblocking(true)
+            .recv_bufsize(2048).bind_device("lo").build().unwrap();
+        assert_eq!((com.id, com.magic), (53, 0x0b));
+        assert_eq!(com.ttl().unwrap(), 9);
+        assert!(com.is_nonblocking().unwrap());
+        assert_eq!(com.max_payload(), 2048 - IP_SIZE - 12);
+
+        let com = IcmpCommunicator::builder(53).v6().build().unwrap();
+        assert_eq!(com.family(), AddressFamily::Inet6);
+        assert!(!com.is_nonblocking().unwrap());
+
+        match IcmpCommunicator::builder(53).bind_device("nosuchif0").build() {
+            Err(ICError::NoSuchDevice(_)) => {}
+            res => panic!("{:?}", res.map(|com| com.id)),
+        }
+        assert!(IcmpCommunicator::builder(0).build().is_err());
+    }
+
+    #[test]
+    fn bind_device() {
+        let com = IcmpCommunicator::new(22).unwrap();
+        com.bind_device("lo").unwrap();
+        match com.bind_device("nosuchif0") {
+            Err(ICError::NoSuchDevice(ref name)) if name == "nosuchif0" => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn bind_local_addr() {
+        let rcv   = IcmpCommunicator::with_magic(62, 0x10).unwrap();
+        let other = IcmpCommunicator::with_magic(63, 0x10).unwrap();
+        let snd   = IcmpCommunicator::builder(64).magic(0x10)
+            .bind("127.0.0.3".parse().unwrap()).build().unwrap();
+        rcv.bind("127.0.0.2".parse().unwrap()).unwrap();
+        let addr = |ip: &str| InetAddr::from_std(&net::SocketAddr::new(ip.parse().unwrap(), 0));
+
+        // the whole of 127.0.0.0/8 is on lo: only what is sent to the address bound to is received
+        snd.sendto(b"to .1", addr("127.0.0.1")).unwrap();
+        recv_expected(&other, b"to .1");
+        recv_unexpected(&rcv, b"to .1");
+        rcv.set_nonblocking(false).unwrap();
+        snd.sendto(b"to .2", addr("127.0.0.2")).unwrap();
+        assert!(recv_expected(&rcv, b"to .2") == addr("127.0.0.3"));
+        // our own packets are still ignored
+        recv_unexpected(&snd, b"to .2");
+
+        // not a local address, or not of the family of the socket
+        assert!(other.bind("192.0.2.1".parse().unwrap()).is_err());
+        assert!(other.bind("::1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn ignores_own_packets_only() {
+        let com  = IcmpCommunicator::with_magic(42, 0x05).unwrap();
+        let twin = IcmpCommunicator::with_magic(42, 0x05).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+
+        // a communicator with the same id is someone else
+        com.sendto(b"from com", addr).unwrap();
+        recv_expected(&twin, b"from com");
+        recv_unexpected(&com, b"from com");
+        com.set_nonblocking(false).unwrap();
+
+        twin.sendto(b"from twin", addr).unwrap();
+        recv_expected(&com, b"from twin");
+        recv_unexpected(&twin, b"from twin");
+    }
+
+    #[test]
+    fn magics_do_not_mix() {
+        let snd   = IcmpCommunicator::with_magic(23, 0x01).unwrap();
+        let same  = IcmpCommunicator::with_magic(24, 0x01).unwrap();
+        let other = IcmpCommunicator::with_magic(25, 0x02).unwrap();
+        snd.sendto(b"magic 1", InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())).unwrap();
+        recv_expected(&same, b"magic 1");
+
+        recv_unexpected(&other, b"magic 1");
+    }
+
+    #[test]
+    fn peer_filter() {
+        let snd     = IcmpCommunicator::new(26).unwrap();
+        let rcv     = IcmpCommunicator::new(27).unwrap();
+        let witness = IcmpCommunicator::new(28).unwrap();
+        let addr    = "127.0.0.1:0".parse().unwrap();
+
+        rcv.set_peer_filter(Some("10.1.2.3".parse().unwrap()));
+        snd.sendto(b"filtered", InetAddr::from_std(&addr)).unwrap();
+        recv_expected(&witness, b"filtered");
+        recv_unexpected(&rcv, b"filtered");
+
+        rcv.set_peer_filter(Some(addr.ip()));
+        rcv.set_nonblocking(false).unwrap();
+        snd.sendto(b"allowed", InetAddr::from_std(&addr)).unwrap();
+        recv_expected(&rcv, b"allowed");
+    }
+
+    #[test]
+    fn sendto_batch() {
+        let snd = IcmpCommunicator::new(29).unwrap();
+        let rcv = IcmpCommunicator::new(30).unwrap();
+        let bufs: &[&[u8]] = &[b"batch 1", b"batch 2", b"batch 3"];
+        let sent = snd.sendto_batch(bufs, InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()));
+        assert_eq!(sent.unwrap(), 3);
+        for buf in bufs {
+            recv_expected(&rcv, buf);
+        }
+    }
+
+    #[test]
+    fn recvfrom_batch() {
+        let snd = IcmpCommunicator::new(31).unwrap();
+        let rcv = IcmpCommunicator::new(32).unwrap();
+        let bufs: &[&[u8]] = &[b"burst 1", b"burst 2", b"burst 3"];
+        snd.sendto_batch(bufs, InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())).unwrap();
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut received = Vec::new();
+        while received.len() < bufs.len() {
+            let mut slots = [[0; 64]; 8];
+            let msgs = {
+                let mut slots: Vec<&mut [u8]> = slots.iter_mut().map(|s| &mut s[..]).collect();
+                rcv.recvfrom_batch(&mut slots).expect("no packet received")
+            };
+            for (slot, &(n, _)) in slots.iter().zip(msgs.iter()) {
+                if slot.get(..n).is_some_and(|msg| bufs.contains(&msg)) {
+                    received.push(slot[..n].to_vec());
+                }
+            }
+        }
+        assert_eq!(received, bufs);
+    }
+
+    #[test]
+    fn echo_identifier_and_sequence() {
+        let snd = IcmpCommunicator::new(33).unwrap();
+        let rcv = IcmpCommunicator::new(34).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"seq", addr).unwrap();
+        snd.sendto(b"seq", addr).unwrap();
+
+        // look at the raw packets
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut seqs = Vec::new();
+        while seqs.len() < 2 {
+            let mut data = [0; 64];
+            let (n, _) = recvfrom(*rcv.rawfd(), &mut data).expect("no packet received");
+            let icmp_data = &data[IP_SIZE..n];
+            if icmp_data[5] == 33 && &icmp_data[PKT_HEADER.len()+NONCE_SIZE..] == b"seq" {
+                // a well-formed echo message, with code 0
+                assert_eq!(&icmp_data[..2], &[ICMP_ECHO_REPLY, 0]);
+                assert_eq!(&icmp_data[4..6], &[DEFAULT_MAGIC, 33]);
+                seqs.push((icmp_data[6] as u16) << 8 | icmp_data[7] as u16);
+            }
+        }
+        assert_eq!(seqs[1], seqs[0].wrapping_add(1));
+    }
+
+    #[test]
+    fn icmp_code() {
+        let snd  = IcmpCommunicator::builder(65).magic(0x11).icmp_code(3).build().unwrap();
+        let rcv  = IcmpCommunicator::with_magic(66, 0x11).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"code 3", addr).unwrap();
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut buf = [0; 64];
+        loop {
+            let (n, _, meta) = match rcv.recvfrom_meta(&mut buf).expect("no packet received") {
+                Some(msg) => msg,
+                None      => continue,
+            };
+            if &buf[..n] == b"code 3" {
+                assert_eq!((meta.icmp_type, meta.icmp_code), (ICMP_ECHO_REPLY, 3));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn recv_bufsize() {
+        let snd = IcmpCommunicator::new(35).unwrap();
+        let rcv = IcmpCommunicator::new(36).unwrap().with_recv_bufsize(8192);
+        let small = IcmpCommunicator::new(37).unwrap().with_recv_bufsize(IP_SIZE + 64);
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*small.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+
+        assert_eq!(rcv.max_payload(), 8192 - IP_SIZE - 12);
+        assert_eq!((small.max_payload(), small.overhead()), (52, IP_SIZE + 12));
+
+        let jumbo = vec![0x42; 3000];
+        snd.sendto(&jumbo, InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())).unwrap();
+        let mut buf = vec![0; 8192];
+        recv_expected_into(&rcv, &jumbo, &mut buf);
+        loop {
+            match small.recvfrom(&mut buf) {
+                Err(ICError::Truncated(n))
+                    if n == IP_SIZE+PKT_HEADER.len()+NONCE_SIZE+jumbo.len() => break,
+                Err(e) => panic!("{:?}", e),
+                Ok(_)  => {}
+            }
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let snd = IcmpCommunicator::with_magic(38, 0x03).unwrap();
+        let rcv = IcmpCommunicator::with_magic(39, 0x03).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"counted", addr).unwrap();
+        snd.sendto_batch(&[b"1", b"22"], addr).unwrap();
+        let stats = snd.stats();
+        assert_eq!((stats.packets_sent, stats.bytes_sent), (3, 10));
+
+        recv_expected(&rcv, b"counted");
+        recv_expected(&rcv, b"1");
+        recv_expected(&rcv, b"22");
+        let stats = rcv.stats();
+        assert_eq!((stats.packets_received, stats.bytes_received), (3, 10));
+    }
+
+    #[test]
+    fn echo_role() {
+        let client = IcmpCommunicator::with_magic(57, 0x0e).unwrap()
+            .with_echo_role(EchoRole::Client);
+        let server = IcmpCommunicator::builder(58).magic(0x0e).echo_role(EchoRole::Server)
+            .build().unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+
+        let recv_type = |com: &IcmpCommunicator, expected: &[u8]| {
+            let tv = TimeVal::milliseconds(2000);
+            setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+            let mut buf = [0; 64];
+            loop {
+                let msg = com.recvfrom_meta(&mut buf).expect("no packet received");
+                if let Some((n, _, meta)) = msg {
+                    if buf.get(..n) == Some(expected) {
+                        return meta.icmp_type;
+                    }
+                }
+            }
+        };
+        client.sendto(b"ping", addr).unwrap();
+        assert_eq!(recv_type(&server, b"ping"), ICMP_ECHO_REQUEST);
+        server.sendto(b"pong", addr).unwrap();
+        assert_eq!(recv_type(&client, b"pong"), ICMP_ECHO_REPLY);
+    }
+
+    #[test]
+    fn incoming() {
+        let snd  = IcmpCommunicator::with_magic(60, 0x0f).unwrap();
+        let rcv  = IcmpCommunicator::with_magic(61, 0x0f).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"first", addr).unwrap();
+        snd.sendto(b"second", addr).unwrap();
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let msgs: Vec<_> = rcv.incoming().take(2).map(|msg| msg.unwrap().0).collect();
+        assert_eq!(msgs, vec![b"first".to_vec(), b"second".to_vec()]);
+
+        rcv.set_nonblocking(true).unwrap();
+        assert!(rcv.incoming().next().is_none());
+    }
+
+    #[test]
+    fn drop_reason() {
+        let snd   = IcmpCommunicator::with_magic(54, 0x0c).unwrap();
+        let rcv   = IcmpCommunicator::with_magic(55, 0x0c).unwrap();
+        let other = IcmpCommunicator::with_magic(56, 0x0d).unwrap();
+        let addr  = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"reason", addr).unwrap();
+
+        // other tests' traffic is queued too, keep reading until we reach our packet
+        let outcome = |com: &IcmpCommunicator, wanted: &dyn Fn(RecvOutcome) -> bool| {
+            let tv = TimeVal::milliseconds(2000);
+            setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+            loop {
+                let res = com.recvfrom_reason(&mut [0; 64]).expect("no packet received");
+                if wanted(res) {
+                    return;
+                }
+            }
+        };
+        outcome(&rcv, &|res| matches!(res, RecvOutcome::Message(6, _)));
+        outcome(&snd, &|res| matches!(res, RecvOutcome::Dropped(DropReason::OwnPacket)));
+        outcome(&other, &|res| matches!(res, RecvOutcome::Dropped(DropReason::NotOurs)));
+    }
+
+    #[test]
+    fn icmp_errors() {
+        let com  = IcmpCommunicator::with_magic(67, 0x12).unwrap();
+        let raw  = socket(AddressFamily::Inet, SockType::Raw, SockFlag::empty(), 1).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+
+        // what a router sends back about a packet of ours to 10.9.8.7, or of someone else
+        let error = |typ: u8, code: u8, id: u8| {
+            let mut orig = vec![0; IP_SIZE];
+            orig[0] = 0x45;
+            orig[9] = 0x01;
+            orig[16..20].copy_from_slice(&[10, 9, 8, 7]);
+            orig.extend_from_slice(&[com.send_type_(), 0, 0, 0, 0x12, id, 0, 1]);
+            let mut pkt = vec![typ, code, 0, 0, 0, 0, 0, 0];
+            pkt.extend_from_slice(&orig);
+            let accum = checksum(&pkt);
+            pkt[2] = (accum >> 8)   as u8;
+            pkt[3] = (accum & 0xFF) as u8;
+            sendto(raw, &pkt, &SockAddr::Inet(addr), MsgFlags::empty()).unwrap();
+        };
+        error(3, 1, 68);
+        error(3, 1, 67);
+        error(11, 0, 67);
+
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        let mut reports = Vec::new();
+        while reports.len() < 2 {
+            if let RecvOutcome::IcmpError(r) = com.recvfrom_reason(&mut [0; 64]).unwrap() {
+                assert!(r.from == addr);
+                assert!(r.dest == InetAddr::from_std(&"10.9.8.7:0".parse().unwrap()));
+                reports.push((r.kind, r.code));
+            }
+        }
+        assert_eq!(reports, [(IcmpErrorKind::Unreachable, 1), (IcmpErrorKind::TimeExceeded, 0)]);
+        unistd::close(raw).unwrap();
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let snd = Arc::new(IcmpCommunicator::with_magic(40, 0x04).unwrap());
+        let rcv = Arc::new(IcmpCommunicator::with_magic(41, 0x04).unwrap());
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+
+        // the senders race, so take the messages in any order
+        let receiver = {
+            let rcv = rcv.clone();
+            thread::spawn(move || {
+                let tv = TimeVal::milliseconds(2000);
+                setsockopt(*rcv.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+                let mut got = Vec::new();
+                let mut buf = [0; 64];
+                while got.len() < 2 {
+                    if let Some((n, _)) = rcv.recvfrom(&mut buf).expect("no packet received") {
+                        got.push(buf[..n].to_vec());
+                    }
+                }
+                got.sort();
+                got
+            })
+        };
+        let senders: Vec<_> = (0..2).map(|i| {
+            let snd = snd.clone();
+            thread::spawn(move || snd.sendto(format!("from {}", i).as_bytes(), addr).unwrap())
+        }).collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(receiver.join().unwrap(), vec![b"from 0".to_vec(), b"from 1".to_vec()]);
+        assert_eq!(snd.stats().packets_sent, 2);
+    }
+
+    #[test]
+    fn pace_limit() {
+        use std::thread::sleep;
+
+        let snd  = IcmpCommunicator::with_magic(45, 0x08).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.set_pace_limit(Some(5), None);
+        for _ in 0..5 {
+            snd.sendto(b"paced", addr).unwrap();
+        }
+        match snd.sendto(b"paced", addr) {
+            Err(ICError::PaceLimited) => {}
+            res => panic!("{:?}", res),
+        }
+        let delay = snd.pace_delay();
+        assert!(delay > Duration::from_millis(0) && delay <= Duration::from_millis(200));
+        sleep(delay);
+        snd.sendto(b"paced", addr).unwrap();
+
+        // batches are cut short
+        snd.set_pace_limit(Some(2), None);
+        assert_eq!(snd.sendto_batch(&[b"1", b"2", b"3"], addr).unwrap(), 2);
+
+        // a byte budget below the size of the packet lets one through
+        snd.set_pace_limit(None, Some(100));
+        snd.sendto(&[0; 200], addr).unwrap();
+        assert!(snd.sendto(b"x", addr).is_err());
+
+        // packets that fail to leave don't use up the budget
+        snd.set_pace_limit(Some(1), None);
+        assert!(snd.sendto(&[0; 70000], addr).is_err());
+        assert!(snd.sendto_batch(&[&[0; 70000][..]], addr).is_err());
+        snd.sendto(b"x", addr).unwrap();
+
+        snd.set_pace_limit(None, None);
+        assert_eq!(snd.pace_delay(), Duration::from_millis(0));
+        snd.sendto(b"unlimited", addr).unwrap();
+    }
+
+    #[test]
+    fn jitter() {
+        let snd  = IcmpCommunicator::with_magic(46, 0x09).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.set_jitter(Some(Duration::from_millis(20)..Duration::from_millis(40)));
+
+        let start = Instant::now();
+        let mut gaps = Vec::new();
+        for _ in 0..5 {
+            let delay = snd.pace_delay();
+            assert!(delay < Duration::from_millis(40));
+            std::thread::sleep(delay);
+            gaps.push(snd.pace_delay());
+            snd.sendto(b"jittered", addr).unwrap();
+            match snd.sendto(b"too soon", addr) {
+                Err(ICError::PaceLimited) => {}
+                res => panic!("{:?}", res),
+            }
+        }
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        assert!(gaps.iter().all(|&d| d == Duration::from_millis(0)));
+
+        snd.set_jitter(None);
+        snd.sendto(b"1", addr).unwrap();
+        snd.sendto(b"2", addr).unwrap();
+    }
+
+    #[test]
+    fn sendto_does_not_allocate() {
+        let snd  = IcmpCommunicator::with_magic(43, 0x06).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        snd.sendto(b"warm up", addr).unwrap();
+
+        let before = allocations();
+        for _ in 0..100 {
+            snd.sendto(b"no alloc", addr).unwrap();
+        }
+        assert_eq!(allocations() - before, 0);
+    }
+
+    // cargo test --release -- --ignored --nocapture sendto_bench
+    #[test]
+    #[ignore]
+    fn sendto_bench() {
+        const PACKETS: usize = 100_000;
+
+        let snd  = IcmpCommunicator::with_magic(44, 0x07).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        let before = allocations();
+        let start  = Instant::now();
+        for _ in 0..PACKETS {
+            snd.sendto(b"small", addr).unwrap();
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!("{} packets in {:.2}s ({:.0} packets/s), {} allocations",
+                 PACKETS, secs, PACKETS as f64 / secs, allocations() - before);
+    }
+
+    #[cfg(feature = "hdrincl")]
+    #[test]
+    fn hdrincl() {
+        let snd  = IcmpCommunicator::with_hdrincl(48, "127.0.0.9".parse().unwrap()).unwrap();
+        let rcv  = IcmpCommunicator::new(49).unwrap();
+        let addr = InetAddr::from_std(&"127.0.0.1:0".parse().unwrap());
+        assert_eq!(snd.sendto(b"spoofed", addr).unwrap(), 7);
+        let peer = recv_expected(&rcv, b"spoofed");
+        assert_eq!(peer.to_std().ip(), "127.0.0.9".parse::<net::IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn echo_v6() {
+        let snd = IcmpCommunicator::new_v6(13).unwrap();
+        let rcv = IcmpCommunicator::new_v6(14).unwrap();
+        let addr: SocketAddr = "[::1]:0".parse().unwrap();
+        snd.sendto(b"hello v6", InetAddr::from_std(&addr)).unwrap();
+        let peer = recv_expected(&rcv, b"hello v6");
+        assert_eq!(peer.to_std().ip(), addr.ip());
+    }
 }
@@ -0,0 +1,317 @@
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439), to keep the data of a connection
+//! private. Written here, like `hmac`, because the crate builds offline with a fixed set of
+//! dependencies that has no cryptography. It follows the RFC step by step and the tests check it
+//! against the test vectors of the RFC. Tags are compared in constant time.
+
+pub const KEY_SIZE:   usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE:   usize = 16;
+
+const BLOCK_SIZE: usize = 64;
+
+fn le32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+// Key stream block number `counter` for `key` and `nonce`
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; BLOCK_SIZE] {
+    let mut init = [0u32; 16];
+    init[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    init[4..12].copy_from_slice(key);
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = le32(&nonce[4 * i..]);
+    }
+
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4,  8, 12);
+        quarter_round(&mut s, 1, 5,  9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7,  8, 13);
+        quarter_round(&mut s, 3, 4,  9, 14);
+    }
+
+    let mut block = [0; BLOCK_SIZE];
+    for (i, out) in block.chunks_mut(4).enumerate() {
+        out.copy_from_slice(&s[i].wrapping_add(init[i]).to_le_bytes());
+    }
+    block
+}
+
+// XOR `data` with the key stream starting at block `counter`
+fn chacha20_xor(key: &[u32; 8], counter: u32, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+        let stream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(stream.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Poly1305 one-time authenticator, with 26 bits limbs so that products fit in a u64.
+pub struct Poly1305 {
+    r:       [u32; 5],
+    h:       [u32; 5],
+    pad:     [u32; 4],
+    pending: [u8; 16],
+    used:    usize,
+}
+
+impl Poly1305 {
+
+    pub fn new(key: &[u8; 32]) -> Poly1305 {
+        Poly1305 {
+            // clamped as the RFC requires
+            r: [
+                le32(&key[ 0..])       & 0x03ff_ffff,
+                le32(&key[ 3..]) >> 2  & 0x03ff_ff03,
+                le32(&key[ 6..]) >> 4  & 0x03ff_c0ff,
+                le32(&key[ 9..]) >> 6  & 0x03f0_3fff,
+                le32(&key[12..]) >> 8  & 0x000f_ffff,
+            ],
+            h:       [0; 5],
+            pad:     [le32(&key[16..]), le32(&key[20..]), le32(&key[24..]), le32(&key[28..])],
+            pending: [0; 16],
+            used:    0,
+        }
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let n = (16 - self.used).min(buf.len());
+            self.pending[self.used..self.used+n].copy_from_slice(&buf[..n]);
+            self.used += n;
+            buf = &buf[n..];
+            if self.used == 16 {
+                let block = self.pending;
+                self.block_(&block, 1 << 24);
+                self.used = 0;
+            }
+        }
+    }
+
+    // Add the 16 bytes block `m`, and the 2^128 bit `hibit` unless the block was padded, then
+    // multiply by r
+    fn block_(&mut self, m: &[u8; 16], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+
+        h[0] += le32(&m[ 0..])      & 0x03ff_ffff;
+        h[1] += le32(&m[ 3..]) >> 2 & 0x03ff_ffff;
+        h[2] += le32(&m[ 6..]) >> 4 & 0x03ff_ffff;
+        h[3] += le32(&m[ 9..]) >> 6 & 0x03ff_ffff;
+        h[4] += le32(&m[12..]) >> 8 | hibit;
+
+        let mul = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = mul(h[0], r0) + mul(h[1], s4) + mul(h[2], s3) + mul(h[3], s2) + mul(h[4], s1);
+        let d1 = mul(h[0], r1) + mul(h[1], r0) + mul(h[2], s4) + mul(h[3], s3) + mul(h[4], s2);
+        let d2 = mul(h[0], r2) + mul(h[1], r1) + mul(h[2], r0) + mul(h[3], s4) + mul(h[4], s3);
+        let d3 = mul(h[0], r3) + mul(h[1], r2) + mul(h[2], r1) + mul(h[3], r0) + mul(h[4], s4);
+        let d4 = mul(h[0], r4) + mul(h[1], r3) + mul(h[2], r2) + mul(h[3], r1) + mul(h[4], r0);
+
+        // partial reduction modulo 2^130 - 5
+        let mut c;
+        c = d0 >> 26;        h[0] = d0 as u32 & 0x03ff_ffff;
+        let d1 = d1 + c;
+        c = d1 >> 26;        h[1] = d1 as u32 & 0x03ff_ffff;
+        let d2 = d2 + c;
+        c = d2 >> 26;        h[2] = d2 as u32 & 0x03ff_ffff;
+        let d3 = d3 + c;
+        c = d3 >> 26;        h[3] = d3 as u32 & 0x03ff_ffff;
+        let d4 = d4 + c;
+        c = d4 >> 26;        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += c as u32 * 5;
+        h[1] += h[0] >> 26;  h[0] &= 0x03ff_ffff;
+    }
+
+    /// Tag of everything fed so far
+    pub fn finish(mut self) -> [u8; TAG_SIZE] {
+        if self.used > 0 {
+            let mut block = [0; 16];
+            block[..self.used].copy_from_slice(&self.pending[..self.used]);
+            block[self.used] = 1;
+            self.block_(&block, 0);
+        }
+
+        // full reduction
+        let mut h = self.h;
+        let mut c;
+        c = h[1] >> 26; h[1] &= 0x03ff_ffff; h[2] += c;
+        c = h[2] >> 26; h[2] &= 0x03ff_ffff; h[3] += c;
+        c = h[3] >> 26; h[3] &= 0x03ff_ffff; h[4] += c;
+        c = h[4] >> 26; h[4] &= 0x03ff_ffff; h[0] += c * 5;
+        c = h[0] >> 26; h[0] &= 0x03ff_ffff; h[1] += c;
+
+        // h - p, kept if h >= p, in constant time
+        let mut g = [0u32; 5];
+        g[0] = h[0].wrapping_add(5); c = g[0] >> 26; g[0] &= 0x03ff_ffff;
+        g[1] = h[1].wrapping_add(c); c = g[1] >> 26; g[1] &= 0x03ff_ffff;
+        g[2] = h[2].wrapping_add(c); c = g[2] >> 26; g[2] &= 0x03ff_ffff;
+        g[3] = h[3].wrapping_add(c); c = g[3] >> 26; g[3] &= 0x03ff_ffff;
+        g[4] = h[4].wrapping_add(c).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for (h, g) in h.iter_mut().zip(g.iter()) {
+            *h = (*h & !keep_g) | (g & keep_g);
+        }
+
+        // h + pad, mod 2^128
+        let words = [
+            h[0] | h[1] << 26,
+            h[1] >> 6  | h[2] << 20,
+            h[2] >> 12 | h[3] << 14,
+            h[3] >> 18 | h[4] << 8,
+        ];
+        let mut tag = [0; TAG_SIZE];
+        let mut f: u64 = 0;
+        for i in 0..4 {
+            f = words[i] as u64 + self.pad[i] as u64 + (f >> 32);
+            tag[4 * i..4 * i + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        }
+        tag
+    }
+}
+
+/// ChaCha20-Poly1305 with a given key. Each nonce must only ever be used for one message.
+#[derive(Clone)]
+pub struct ChaCha20Poly1305 {
+    key: [u32; 8],
+}
+
+impl ChaCha20Poly1305 {
+
+    pub fn new(key: &[u8; KEY_SIZE]) -> ChaCha20Poly1305 {
+        let mut words = [0; 8];
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = le32(&key[4 * i..]);
+        }
+        ChaCha20Poly1305 { key: words }
+    }
+
+    fn tag_(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+        let mut otk = [0; 32];
+        otk.copy_from_slice(&chacha20_block(&self.key, 0, nonce)[..32]);
+        let mut poly = Poly1305::new(&otk);
+        let zeros = [0; 16];
+        poly.update(aad);
+        poly.update(&zeros[..(16 - aad.len() % 16) % 16]);
+        poly.update(ciphertext);
+        poly.update(&zeros[..(16 - ciphertext.len() % 16) % 16]);
+        poly.update(&(aad.len() as u64).to_le_bytes());
+        poly.update(&(ciphertext.len() as u64).to_le_bytes());
+        poly.finish()
+    }
+
+    /// Append `plaintext` encrypted to `out`, followed by the tag authenticating it along with
+    /// the additional data `aad`, which is not sent
+    pub fn seal(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], plaintext: &[u8],
+                out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        chacha20_xor(&self.key, 1, nonce, &mut out[start..]);
+        let tag = self.tag_(nonce, aad, &out[start..]);
+        out.extend_from_slice(&tag);
+    }
+
+    /// Append the plaintext of `sealed` to `out` if its tag is right. Nothing is appended on
+    /// failure.
+    pub fn open(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], sealed: &[u8], out: &mut Vec<u8>)
+      -> bool {
+        if sealed.len() < TAG_SIZE {
+            return false;
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+        let expected = self.tag_(nonce, aad, ciphertext);
+        if tag.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+            return false;
+        }
+        let start = out.len();
+        out.extend_from_slice(ciphertext);
+        chacha20_xor(&self.key, 1, nonce, &mut out[start..]);
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn chacha20_rfc8439() {
+        // section 2.3.2
+        let key: Vec<u8> = (0..32).collect();
+        let mut words = [0; 8];
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = le32(&key[4 * i..]);
+        }
+        let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        assert_eq!(chacha20_block(&words, 1, &nonce)[..], unhex(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e")[..]);
+    }
+
+    #[test]
+    fn poly1305_rfc8439() {
+        // section 2.5.2
+        let mut key = [0; 32];
+        key.copy_from_slice(&unhex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b"));
+        let mut poly = Poly1305::new(&key);
+        poly.update(b"Cryptographic Forum Research Group");
+        assert_eq!(poly.finish()[..], unhex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+    }
+
+    #[test]
+    fn aead_rfc8439() {
+        // section 2.8.2
+        let mut key = [0; KEY_SIZE];
+        for (i, k) in key.iter_mut().enumerate() {
+            *k = 0x80 + i as u8;
+        }
+        let nonce = [7, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad   = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plain = &b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                       tip for the future, sunscreen would be it."[..];
+        let aead = ChaCha20Poly1305::new(&key);
+
+        let mut sealed = Vec::new();
+        aead.seal(&nonce, &aad, plain, &mut sealed);
+        assert_eq!(sealed, unhex(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+             3ff4def08e4b7a9de576d26586cec64b6116
+             1ae10b594f09e26a7e902ecbd0600691"));
+
+        let mut opened = Vec::new();
+        assert!(aead.open(&nonce, &aad, &sealed, &mut opened));
+        assert_eq!(opened, plain);
+
+        // anything changed and it doesn't open
+        let mut nonce2 = nonce;
+        nonce2[0] = 8;
+        assert!(!aead.open(&nonce2, &aad, &sealed, &mut opened));
+        assert!(!aead.open(&nonce, b"", &sealed, &mut opened));
+        sealed[3] ^= 1;
+        assert!(!aead.open(&nonce, &aad, &sealed, &mut opened));
+        assert!(!aead.open(&nonce, &aad, &sealed[..10], &mut opened));
+        assert_eq!(opened, plain);
+    }
+}
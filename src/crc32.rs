@@ -0,0 +1,82 @@
+//! CRC-32 (IEEE 802.3, as used by zlib and friends), to check that data made it through the
+//! tunnel intact.
+
+const POLY: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { POLY ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Running CRC-32 of data fed in several pieces.
+#[derive(Debug, Copy, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+
+    pub fn new() -> Crc32 {
+        Crc32 { crc: !0 }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        for &b in buf {
+            self.crc = TABLE[((self.crc ^ b as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    /// CRC of everything fed so far
+    pub fn sum(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// CRC-32 of `buf`
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(buf);
+    crc.sum()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn pieces() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut crc = Crc32::new();
+        for chunk in data.chunks(33) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.sum(), crc32(&data));
+    }
+}
@@ -0,0 +1,224 @@
+//! HMAC-SHA256 (RFC 2104, FIPS 180-4), to authenticate the packets of a connection with a
+//! pre-shared key.
+
+const BLOCK_SIZE: usize = 64;
+
+/// Size of a SHA-256 digest, and so of a full HMAC
+pub const DIGEST_SIZE: usize = 32;
+
+const K: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4,
+    0xab1c_5ed5, 0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe,
+    0x9bdc_06a7, 0xc19b_f174, 0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f,
+    0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da, 0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7,
+    0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967, 0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc,
+    0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85, 0xa2bf_e8a1, 0xa81a_664b,
+    0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070, 0x19a4_c116,
+    0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+    0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a, 0x510e_527f, 0x9b05_688c, 0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+/// Running SHA-256 of data fed in several pieces.
+#[derive(Copy, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    used:  usize, // bytes of `block` filled
+    len:   u64,   // bytes fed so far
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        Sha256::new()
+    }
+}
+
+impl Sha256 {
+
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: H0,
+            block: [0; BLOCK_SIZE],
+            used:  0,
+            len:   0,
+        }
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) {
+        self.len = self.len.wrapping_add(buf.len() as u64);
+        while !buf.is_empty() {
+            let n = (BLOCK_SIZE - self.used).min(buf.len());
+            self.block[self.used..self.used+n].copy_from_slice(&buf[..n]);
+            self.used += n;
+            buf = &buf[n..];
+            if self.used == BLOCK_SIZE {
+                self.compress_();
+                self.used = 0;
+            }
+        }
+    }
+
+    /// Digest of everything fed so far
+    pub fn finish(mut self) -> [u8; DIGEST_SIZE] {
+        let bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.used != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0; DIGEST_SIZE];
+        for (out, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress_(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+            let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+            w[i] = w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1  = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch  = (e & f) ^ (!e & g);
+            let t1  = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0  = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2  = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+/// SHA-256 of `buf`
+pub fn sha256(buf: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut sha = Sha256::new();
+    sha.update(buf);
+    sha.finish()
+}
+
+/// HMAC-SHA256 with a given key. The hash states after the padded keys are kept, so that each
+/// message only costs its own blocks and two more.
+#[derive(Clone)]
+pub struct Hmac {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl Hmac {
+
+    pub fn new(key: &[u8]) -> Hmac {
+        // keys longer than a block are hashed first, shorter ones padded with zeros
+        let mut block = [0; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block[..DIGEST_SIZE].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        let ipad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+        let opad: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+        inner.update(&ipad);
+        outer.update(&opad);
+        Hmac { inner, outer }
+    }
+
+    /// Authentication tag of `data`
+    pub fn mac(&self, data: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut inner = self.inner;
+        inner.update(data);
+        let mut outer = self.outer;
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+
+    /// Whether `tag` is the start of the tag of `data`, in a time that doesn't depend on where
+    /// they differ
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let mac = self.mac(data);
+        if tag.is_empty() || tag.len() > mac.len() {
+            return false;
+        }
+        tag.iter().zip(mac.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha256_known_values() {
+        assert_eq!(hex(&sha256(b"")),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn sha256_pieces() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut sha = Sha256::new();
+        for chunk in data.chunks(33) {
+            sha.update(chunk);
+        }
+        assert_eq!(sha.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn hmac_rfc4231() {
+        // test cases 1, 2 and 6 (key larger than a block)
+        assert_eq!(hex(&Hmac::new(&[0x0b; 20]).mac(b"Hi There")),
+                   "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(hex(&Hmac::new(b"Jefe").mac(b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let msg = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        assert_eq!(hex(&Hmac::new(&[0xaa; 131]).mac(msg)),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn verify() {
+        let hmac = Hmac::new(b"key");
+        let tag  = hmac.mac(b"data");
+        assert!(hmac.verify(b"data", &tag));
+        assert!(hmac.verify(b"data", &tag[..8]));
+        assert!(!hmac.verify(b"date", &tag[..8]));
+        assert!(!hmac.verify(b"data", &[]));
+        assert!(!Hmac::new(b"other key").verify(b"data", &tag[..8]));
+    }
+}
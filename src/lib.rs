@@ -1,8 +1,28 @@
+#[cfg(feature = "logging")]
 #[macro_use]
 extern crate log;
 
+// Without the logging feature, messages are type checked but compile to nothing
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "crypto")]
+pub mod aead;
+pub mod crc32;
+pub mod hmac;
+#[cfg(feature = "compression")]
+pub mod lz4;
 pub mod odp;
+pub mod pcap;
 pub mod privs;
+pub mod signals;
+pub mod transport;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,195 @@
+//! Compression in the LZ4 block format, fast enough to be applied to each packet. A block is a
+//! sequence of literals followed by a match, i.e. a copy of bytes seen earlier, and so on: a
+//! token (the number of literals and the length of the match, 4 bits each), the literals, the
+//! match offset (u16, little endian), the rest of the lengths if they did not fit in the token.
+//! The block ends with literals alone.
+
+use std::cmp;
+
+const MIN_MATCH: usize = 4;
+
+// The last match must start 12 bytes before the end of the block, the last 5 bytes are literals
+const MF_LIMIT:      usize = 12;
+const LAST_LITERALS: usize = 5;
+
+const MAX_OFFSET: usize = 0xffff;
+
+const HASH_LOG: u32 = 12;
+
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]])
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+// Write the rest of a length which did not fit in its 4 bits
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_len(src: &[u8], i: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let b = *src.get(*i)?;
+        *i  += 1;
+        len += b as usize;
+        if b != 255 {
+            return Some(len);
+        }
+    }
+}
+
+// Write `literals` followed by the match `m`, `(offset, length)`, if any
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let lit_len   = literals.len();
+    let match_len = m.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((cmp::min(lit_len, 15) << 4 | cmp::min(match_len, 15)) as u8);
+    if lit_len >= 15 {
+        write_len(out, lit_len - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = m {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_len(out, match_len - 15);
+        }
+    }
+}
+
+/// Compress `src`. Incompressible data comes out slightly larger.
+pub fn compress(src: &[u8]) -> Vec<u8> {
+    let mut out    = Vec::with_capacity(src.len() + src.len() / 255 + 16);
+    let mut table  = [0usize; 1 << HASH_LOG]; // 1 + where the last 4 bytes with a hash were seen
+    let mut anchor = 0; // start of the literals not written yet
+    let mut i      = 0;
+
+    while i + MF_LIMIT < src.len() {
+        let seq  = read_u32(src, i);
+        let h    = hash(seq);
+        let cand = table[h];
+        table[h] = i + 1;
+
+        if cand == 0 || i - (cand - 1) > MAX_OFFSET || read_u32(src, cand - 1) != seq {
+            i += 1;
+            continue;
+        }
+        let from    = cand - 1;
+        let mut len = MIN_MATCH;
+        while i + len < src.len() - LAST_LITERALS && src[from + len] == src[i + len] {
+            len += 1;
+        }
+        write_sequence(&mut out, &src[anchor..i], Some((i - from, len)));
+        i     += len;
+        anchor = i;
+    }
+
+    write_sequence(&mut out, &src[anchor..], None);
+    out
+}
+
+/// Decompress the block `src` at the end of `out`, return its size. `None` if the block is
+/// malformed or would decompress to more than `max` bytes.
+pub fn decompress(src: &[u8], out: &mut Vec<u8>, max: usize) -> Option<usize> {
+    let start = out.len();
+    let mut i = 0;
+    loop {
+        let token = *src.get(i)?;
+        i += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += read_len(src, &mut i)?;
+        }
+        let literals = src.get(i..i.checked_add(lit_len)?)?;
+        if out.len() - start + lit_len > max {
+            return None;
+        }
+        out.extend_from_slice(literals);
+        i += lit_len;
+
+        // the last sequence has no match
+        if i == src.len() {
+            return Some(out.len() - start);
+        }
+
+        let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() - start {
+            return None;
+        }
+        let mut len = (token & 0x0f) as usize + MIN_MATCH;
+        if len == 15 + MIN_MATCH {
+            len += read_len(src, &mut i)?;
+        }
+        if out.len() - start + len > max {
+            return None;
+        }
+        // byte by byte: the match may overlap what it produces
+        let from = out.len() - offset;
+        for k in 0..len {
+            let b = out[from + k];
+            out.push(b);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let packed = compress(data);
+        let mut out = Vec::new();
+        assert_eq!(decompress(&packed, &mut out, data.len()), Some(data.len()));
+        assert_eq!(out, data);
+        packed
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"");
+        round_trip(b"short");
+        round_trip(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+
+        let text = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(30);
+        assert!(round_trip(&text).len() < text.len() / 10);
+
+        // incompressible
+        let mut x: u32 = 0x1234_5678;
+        let noise: Vec<u8> = (0..2000).map(|_| {
+            x ^= x << 13; x ^= x >> 17; x ^= x << 5;
+            x as u8
+        }).collect();
+        assert!(round_trip(&noise).len() <= noise.len() + noise.len() / 255 + 16);
+    }
+
+    #[test]
+    fn decode_reference() {
+        // "abc" then a 9 bytes match 3 bytes back, then "xyzxy"
+        let block = [0x35, b'a', b'b', b'c', 3, 0, 0x50, b'x', b'y', b'z', b'x', b'y'];
+        let mut out = b"hdr".to_vec();
+        assert_eq!(decompress(&block, &mut out, 64), Some(17));
+        assert_eq!(&out[..], &b"hdrabcabcabcabcxyzxy"[..]);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut out = Vec::new();
+        // offset before the start of the block, and 0
+        assert_eq!(decompress(&[0x10, b'a', 2, 0, 0x00], &mut out, 64), None);
+        assert_eq!(decompress(&[0x10, b'a', 0, 0, 0x00], &mut out, 64), None);
+        // truncated literals, missing end
+        assert_eq!(decompress(&[0x30, b'a'], &mut out, 64), None);
+        assert_eq!(decompress(&[], &mut out, 64), None);
+        // larger than allowed
+        let packed = compress(&[0; 1000]);
+        assert_eq!(decompress(&packed, &mut out, 999), None);
+    }
+}
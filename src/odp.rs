@@ -1,7 +1,19 @@
 use std::io;
 use std::cmp;
+use std::fmt;
+use std::mem;
+use std::error;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::result;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+
+extern crate nix;
+use self::nix::poll::{poll, PollFd, EventFlags, POLLIN};
 
 extern crate mio;
 use self::mio::*;
@@ -13,225 +25,5657 @@ use self::byteorder::{ByteOrder, LittleEndian};
 extern crate icmp_communicator;
 use self::icmp_communicator::*;
 
+use crc32::crc32;
+use hmac::{Hmac, DIGEST_SIZE};
+use transport::Transport;
+#[cfg(feature = "compression")]
+use lz4;
+#[cfg(feature = "crypto")]
+use aead::{self, ChaCha20Poly1305};
+
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+use std::{future::Future, pin::Pin, task::{self, Context}};
+#[cfg(feature = "tokio")]
+use self::tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf, unix::AsyncFd};
+#[cfg(feature = "tokio")]
+use self::tokio::time::{self as tokio_time, Sleep};
 
-const TYPE_SND: u8 = 'S' as u8; // new packet
-const TYPE_ACK: u8 = 'A' as u8; // packet ack
-const TYPE_AGN: u8 = 'G' as u8; // resend request
+
+const TYPE_SND: u8 = b'S'; // new packet
+const TYPE_ACK: u8 = b'A'; // packet ack
+const TYPE_AGN: u8 = b'G'; // resend request
+const TYPE_SYN: u8 = b'Y'; // connection request
+const TYPE_SYA: u8 = b'K'; // connection accepted
+const TYPE_FIN: u8 = b'F'; // end of the connection
+const TYPE_KAL: u8 = b'L'; // keepalive
+const TYPE_NAK: u8 = b'N'; // single packet resend request
+const TYPE_PRB: u8 = b'P'; // link probe
+const TYPE_PRR: u8 = b'R'; // probe reply
+const TYPE_DGM: u8 = b'D'; // datagram, see `ODP::new_unreliable`
 
 const PKT_HDR_SIZE: usize = 10;
+
+// Version of the protocol, in the high 4 bits of the second byte of every packet, the low ones
+// being the flags of its type. Peers from before it was set send 0, which is the same protocol as
+// version 1. Version 2 added the size of fragmented messages, see FLAG_MORE. Packets of later
+// versions are rejected.
+const VERSION: u8 = 2;
+const VERSION_BITS: u8 = VERSION << 4;
+const FLAGS_MASK: u8 = 0x0f;
+
+// Default size of the largest packets we send and receive, see `ODP::set_max_packet_size`, and
+// the smallest it can be set to: room for headers, trailers and some data
 const PKT_MAX_SIZE: usize = 1480;
+const PKT_MIN_SIZE: usize = 64;
+
+// Flags of SND packets, next to the version. Messages larger than a packet are split in
+// fragments with consecutive seqnums, all flagged with FLAG_MORE but the last one. Since packets
+// are delivered in order, the seqnum tells where a fragment belongs. From version 2, the size of
+// the message (u32) comes before it, so that the receiver can check the fragments against it.
+const FLAG_MORE: u8 = 0x01;
+const MSG_LEN_SIZE: usize = 4;
+
+// Default size of the largest message we reassemble, see `ODP::set_max_message_size`
+const MSG_MAX_SIZE: usize = 16 << 20;
+
+// Flag of SND packets: the packet is padded, see `ODP::set_pad_to`. The length of the data
+// follows the header as a u16, the padding follows the data. Other packets are padded with no
+// notice since their handlers ignore trailing bytes.
+const FLAG_PAD: u8 = 0x02;
+const PAD_LEN_SIZE: usize = 2;
+
+// Flag of SND packets: the data is compressed, see `ODP::with_compression`. The padding length
+// is the one of the compressed data.
+const FLAG_LZ4: u8 = 0x04;
+
+// Flag of SND packets: the data is encrypted, see `ODP::with_encryption`. The header is
+// authenticated along with it, the padding length is the one of the encrypted data and its tag.
+const FLAG_SEALED: u8 = 0x08;
+
+// AGN packets: header with 'from', the first seqnum missing, followed by 'to' (u64), the first
+// one received after the gap
+const AGN_SIZE: usize = PKT_HDR_SIZE + 8;
+
+// Flag of ACK and AGN packets, next to the version: a selective ack follows, a u64 whose bit i
+// is set if the packet `base + i` was received, `base` being the seqnum after the acked one for
+// ACK packets and 'to' for AGN packets. Peers that don't know the flag ignore the trailing bytes.
+const FLAG_SACK: u8 = 0x01;
+const SACK_BITS: u64 = 64;
+
+// Flag of ACK packets: the window of the sender follows (u32), after the selective ack if any.
+// Only set when it is below the one of the handshake, which an ACK without the flag restores.
+const FLAG_WND: u8 = 0x02;
+
+// Flag of ACK packets: the ack answers a keepalive. It acks nothing new most of the time, which
+// is no sign of loss: it is not counted as a duplicate ack.
+const FLAG_KAL: u8 = 0x04;
+
+// Trailer of every packet of connections with a key, see `ODP::with_key` and `Auth`: a counter
+// of the packets sent (u64) then the start of the HMAC-SHA256 of the rest of the packet, padding
+// included
+const CTR_SIZE: usize = 8;
+const TAG_SIZE: usize = 8;
+const AUTH_SIZE: usize = CTR_SIZE + TAG_SIZE;
+
+// Number of counters below the highest one received for which we remember whether they were seen
+const REPLAY_WINDOW: u64 = 64;
 
+// SYN and SYA packets: header with the initial seqnum followed by the window size (u32)
+const SYN_SIZE: usize = PKT_HDR_SIZE + 4;
+
+// Flag of SYN and SYA packets: the sender wants NAK packets, see `ODP::set_nak`. They are used
+// only if both peers set it.
+const FLAG_NAK: u8 = 0x01;
+
+// Flag of SYN and SYA packets: the sender wants a connection id, see `ODP::set_connection_id`.
+// The initiator picks it and the other end sends it back. Once agreed on, every packet ends with
+// it (u64), after the padding and before the trailer of connections with a key.
+const FLAG_CID: u8 = 0x02;
+const CID_SIZE: usize = 8;
+
+// Flag of SYN and SYA packets: the sender wants messages checked, see `ODP::set_message_crc`.
+// Once both peers set it, every message ends with the CRC-32 of the rest of it (u32), before it
+// is split in fragments.
+const FLAG_CRC: u8 = 0x04;
+const CRC_SIZE: usize = 4;
+
+// Largest number of packets found missing at once that are requested with NAK packets, an AGN
+// asks for more
+const NAK_MAX: u64 = 16;
+
+// PRB packets: header with a number that the PRR answering it repeats, see `ODP::probe`. Like
+// keepalives, they don't consume a seqnum.
+
+// Number of SYN packets sent by `connect` before giving up
+const SYN_RETRIES: usize = 5;
+
+// Number of FIN packets sent by `shutdown` before giving up
+const FIN_RETRIES: usize = 5;
+
+// Default number of times a packet is sent again before the peer is considered unreachable.
+// With the RTO backoff this leaves it a few minutes to answer.
+const MAX_RETRANSMITS: usize = 8;
+
+// Number of ICMP destination unreachable messages about our packets to the peer, with nothing
+// from the peer in between, after which it is taken for unreachable without waiting for the
+// retransmissions to run out
+const UNREACHABLE_MAX: usize = 3;
+
+// Number of consecutive timeouts without an ack after which the peer looks stale, see
+// `ODP::stale_peer`. The RTO doubles each time, so a slow peer has the time to answer.
+const STALE_TIMEOUTS: usize = 3;
+
+// Default number of packets we can send before waiting for an ack
 const WINDOW_SIZE: usize = 2;
 
-#[derive(Debug, Copy, Clone)]
+// Initial congestion window, in packets (RFC 6928), see `ODP::cwnd`
+const INIT_CWND: usize = 10;
+
+// Number of ACKs for the same seqnum taken for a loss, as in TCP fast retransmit (RFC 5681)
+const DUP_ACKS: usize = 3;
+
+// With delayed ACKs, packets received in order are acknowledged at least every that many, as
+// for TCP (RFC 1122), see `ODP::set_delayed_ack`
+const ACK_EVERY: usize = 2;
+
+// Default delay after which an unacknowledged packet is sent again. One second is the initial
+// retransmission timeout recommended by RFC 6298 for TCP.
+const RTO: u64 = 1000; // ms
+
+// Bounds of the RTO once it is derived from RTT measurements
+const RTO_MIN: u64 = 200;   // ms
+const RTO_MAX: u64 = 60000; // ms
+
+// How much larger than the bandwidth-delay product the kernel receive buffer is made, see
+// `ODP::set_recv_buf_autotune`: a raw socket also queues the ICMP packets of everyone else
+const RCVBUF_HEADROOM: usize = 2;
+
+// Maximum number of out of order packets we hold until the missing ones arrive
+const REORDER_MAX: usize = 1024;
+
+// Default limits of an `OdpMux`: connections kept, and how long one may stay half open, see
+// `OdpMux::set_max_peers` and `OdpMux::set_handshake_timeout`
+const MUX_MAX_PEERS: usize = 1024;
+const HANDSHAKE_TIMEOUT: u64 = 10000; // ms
+
+#[derive(Debug)]
 pub enum ODPError {
     ICError(icmp_communicator::ICError),
     ProtocolError,
     AckError,
     SndError,
     RemoteWindowFull,
+    InvalidWindow,
+    InvalidPacketSize,
+    BufferTooSmall { needed: usize },
+    NotConnected,
+    ConnectionLost,
+    SessionExpired,
+    IntegrityError,
+    NoRandomness(io::Error),
     Unknown,
 }
 
+impl fmt::Display for ODPError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ODPError::ICError(ref e)      => write!(f, "{}", e),
+            ODPError::ProtocolError       => write!(f, "invalid packet from the peer"),
+            ODPError::AckError            => write!(f, "the peer did not acknowledge"),
+            ODPError::SndError            => write!(f, "packet only partly sent"),
+            ODPError::RemoteWindowFull    => write!(f, "the peer's window is full"),
+            ODPError::InvalidWindow       => write!(f, "window must be at least 1"),
+            ODPError::InvalidPacketSize   => write!(f, "packet size out of bounds"),
+            ODPError::BufferTooSmall { needed } => {
+                write!(f, "buffer too small for the message ({} bytes)", needed)
+            }
+            ODPError::NotConnected        => write!(f, "not connected"),
+            ODPError::ConnectionLost      => write!(f, "connection lost, the peer is unreachable"),
+            ODPError::SessionExpired      => write!(f, "session idle or lasted too long"),
+            ODPError::IntegrityError      => write!(f, "message corrupted, its CRC does not match"),
+            ODPError::NoRandomness(ref e) => write!(f, "could not draw random numbers: {}", e),
+            ODPError::Unknown             => write!(f, "unknown error"),
+        }
+    }
+}
+
+impl error::Error for ODPError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ODPError::ICError(ref e)      => Some(e),
+            ODPError::NoRandomness(ref e) => Some(e),
+            _                             => None,
+        }
+    }
+}
+
+impl From<ICError> for ODPError {
+    fn from(e: ICError) -> ODPError {
+        match e {
+            ICError::ShortWrite { .. } => ODPError::SndError,
+            e                          => ODPError::ICError(e),
+        }
+    }
+}
+
 pub type Result<T> = result::Result<T, ODPError>;
 
 pub type Seqnum = u64;
 
-pub struct ODP {
-    com:         Rc<IcmpCommunicator>,
+// Half of the seqnum space, see `seq_lt`
+const SEQ_HALF: Seqnum = 1 << (8 * mem::size_of::<Seqnum>() - 1);
+
+/// Counters of a connection, see `ODP::stats`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OdpStats {
+    /// SND packets sent, not counting retransmissions
+    pub packets_sent:       u64,
+    /// Bytes of data accepted by `send`
+    pub bytes_sent:         u64,
+    /// Bytes of data received in order from the peer, read or not
+    pub bytes_received:     u64,
+    /// SND packets sent again, after a timeout or on request of the peer
+    pub retransmits:        u64,
+    /// ACK packets received
+    pub acks_received:      u64,
+    /// AGN packets sent, asking the peer for missing packets
+    pub agn_sent:           u64,
+    /// AGN packets received
+    pub agn_received:       u64,
+    /// NAK packets sent, asking the peer for a single missing packet, see `ODP::set_nak`
+    pub nak_sent:           u64,
+    /// NAK packets received
+    pub nak_received:       u64,
+    /// SND packets received ahead of a missing one and kept until it arrives
+    pub out_of_order:       u64,
+    /// SND packets received ahead of a missing one and dropped as the reorder buffer was full
+    pub out_of_order_drops: u64,
+    /// Packets dropped as their authentication tag was missing or wrong, see `ODP::with_key`
+    pub auth_failures:      u64,
+    /// Authenticated packets dropped as they were already received, or are too old to tell
+    pub replays:            u64,
+    /// Times the peer was found at a new address, see `ODP::set_connection_id`
+    pub migrations:         u64,
+    /// ICMP errors received about our packets to the peer: unreachable, or TTL exceeded
+    pub icmp_errors:        u64,
+    /// Messages dropped as their CRC did not match, see `ODP::set_message_crc`
+    pub integrity_errors:   u64,
+    /// Packets dropped as they made no sense: truncated, of an unknown type or version...
+    pub malformed:          u64,
+    /// SND packets sent and waiting for an ack
+    pub in_flight:          usize,
+    /// Bytes of the SND packets waiting for an ack or queued, see `ODP::set_max_inflight_bytes`
+    pub in_flight_bytes:    usize,
+    /// Size given to the kernel receive buffer, 0 until set, see `ODP::set_recv_buf_autotune`
+    pub recv_bufsize:       usize,
+    /// Seqnum of our next SND packet
+    pub seqnum:             Seqnum,
+    /// Seqnum of the next SND packet of the peer to deliver
+    pub peer_seqnum:        Seqnum,
+}
+
+/// What `ODP::recv` does with a message larger than its buffer, see `ODP::set_oversized`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Oversized {
+    /// Deliver what fits and the rest in the next calls, as many as needed
+    Split,
+    /// Deliver what fits and drop the rest
+    Truncate,
+    /// Fail with `BufferTooSmall`, telling the size needed, and keep the message for the next call
+    Fail,
+}
+
+/// The peer stopped acknowledging our packets, see `ODP::stale_peer`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StalePeer {
+    /// Time since the peer last acknowledged something
+    pub silent_for: Duration,
+    /// Whether other packets still came from the peer since: the connection is half-open, the
+    /// peer is there but doesn't get our packets or doesn't process them
+    pub half_open:  bool,
+}
+
+/// Loss and round-trip times of the path to the peer, see `ODP::probe`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProbeStats {
+    /// Probes sent
+    pub sent: usize,
+    /// Round-trip times of the probes answered in time, in the order they were sent
+    pub rtts: Vec<Duration>,
+}
+
+impl ProbeStats {
+    /// Percentage of the probes that were not answered in time.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.;
+        }
+        100. * (self.sent - self.rtts.len()) as f64 / self.sent as f64
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.rtts.iter().min().cloned()
+    }
+
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.rtts.iter().max().cloned()
+    }
+
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+
+    pub fn median_rtt(&self) -> Option<Duration> {
+        let mut rtts = self.rtts.clone();
+        rtts.sort();
+        rtts.get(rtts.len() / 2).cloned()
+    }
+}
+
+// A sent packet waiting for its ack
+struct Unacked {
+    seqnum: Seqnum,
+    pkt:    Vec<u8>,
+    sent:   Instant,
+    resent: usize, // RTT samples from retransmitted packets are ambiguous (Karn's algorithm)
+}
+
+// Authentication state of a connection with a key. Each packet sent gets a new counter,
+// retransmissions included, so that the receiver can tell replayed packets apart: it keeps a
+// sliding window of the counters seen (RFC 4302, section 3.4.3). Packets more than
+// REPLAY_WINDOW counters late are dropped as well, a retransmission replaces them.
+// Counters start over with each connection, so past the handshake packets are authenticated with
+// a key of the connection, derived from both initial seqnums: packets recorded during another
+// connection don't pass for ours. A replayed SYN may still open a connection, nothing else of
+// the old one gets through it.
+struct Auth {
+    hmac:    Hmac,         // key we were given, for the handshake
+    session: Option<Hmac>, // key of the connection, once the handshake is done
+    sent:    Cell<u64>,    // counter of our next packet
+    top:     Option<u64>,  // highest counter received
+    seen:    u64,          // bit i is set if the counter `top - i` was received
+}
+
+enum Rejected {
+    BadTag,
+    Replayed,
+}
+
+impl Auth {
+
+    fn new(key: &[u8]) -> Auth {
+        Auth {
+            hmac:    Hmac::new(key),
+            session: None,
+            sent:    Cell::new(0),
+            top:     None,
+            seen:    0,
+        }
+    }
+
+    // Switch to the key of the connection between the initial seqnums `isns`
+    fn start(&mut self, isns: (Seqnum, Seqnum)) {
+        self.session = Some(Hmac::new(&session_key(&self.hmac, b"odp session key", isns)));
+    }
+
+    // The key of packets of type `pkttype`
+    fn key(&self, pkttype: u8) -> &Hmac {
+        match self.session {
+            Some(ref session) if pkttype != TYPE_SYN && pkttype != TYPE_SYA => session,
+            _                                                               => &self.hmac,
+        }
+    }
+
+    // Append the trailer to the `len` bytes of the packet in `buf`, return the new length
+    fn seal(&self, buf: &mut [u8], len: usize) -> usize {
+        let ctr = self.sent.get();
+        self.sent.set(ctr.wrapping_add(1));
+        LittleEndian::write_u64(&mut buf[len..], ctr);
+        let tag = self.key(buf[0]).mac(&buf[..len+CTR_SIZE]);
+        buf[len+CTR_SIZE..len+AUTH_SIZE].copy_from_slice(&tag[..TAG_SIZE]);
+        len + AUTH_SIZE
+    }
+
+    // The packet without its trailer, if it is genuine and was not received before
+    fn open<'a>(&mut self, pkt: &'a [u8]) -> result::Result<&'a [u8], Rejected> {
+        if pkt.len() < AUTH_SIZE {
+            return Err(Rejected::BadTag);
+        }
+        let (data, tag) = pkt.split_at(pkt.len() - TAG_SIZE);
+        if !self.key(pkt[0]).verify(data, tag) {
+            return Err(Rejected::BadTag);
+        }
+        let (data, ctr) = data.split_at(data.len() - CTR_SIZE);
+        if !self.check(LittleEndian::read_u64(ctr)) {
+            return Err(Rejected::Replayed);
+        }
+        Ok(data)
+    }
+
+    // Record that the counter `ctr` was received, return false if it already was
+    fn check(&mut self, ctr: u64) -> bool {
+        let top = match self.top {
+            Some(top) => top,
+            None      => {
+                self.top  = Some(ctr);
+                self.seen = 1;
+                return true;
+            }
+        };
+        if ctr > top {
+            let shift = ctr - top;
+            self.seen = if shift < REPLAY_WINDOW { self.seen << shift | 1 } else { 1 };
+            self.top  = Some(ctr);
+            return true;
+        }
+        let bit = top - ctr;
+        if bit >= REPLAY_WINDOW || self.seen & (1 << bit) != 0 {
+            return false;
+        }
+        self.seen |= 1 << bit;
+        true
+    }
+}
+
+// Encryption state of a connection, see `ODP::with_encryption`. Each connection gets a key of
+// its own, derived from the secret and the random initial seqnums exchanged in the handshake.
+#[cfg(feature = "crypto")]
+struct Cipher {
+    secret:    Hmac, // to derive the keys
+    aead:      ChaCha20Poly1305,
+    initiator: bool, // whether we sent the SYN, which tells the two directions apart
+}
+
+#[cfg(feature = "crypto")]
+impl Cipher {
+
+    fn new(secret: &[u8]) -> Cipher {
+        // replaced in the handshake, before there is data to encrypt
+        let secret = Hmac::new(secret);
+        let key    = secret.mac(b"odp encryption key");
+        Cipher {
+            aead: ChaCha20Poly1305::new(&key),
+            secret,
+            initiator: false,
+        }
+    }
+
+    // Switch to the key of the connection between the initial seqnums `isns`
+    fn start(&mut self, isns: (Seqnum, Seqnum), initiator: bool) {
+        let key = session_key(&self.secret, b"odp encryption key", isns);
+        self.aead      = ChaCha20Poly1305::new(&key);
+        self.initiator = initiator;
+    }
+
+    // Nonce of the SND packet `seqnum`, sent by us if `outgoing`: the direction and the seqnum.
+    // Retransmissions reuse it, for the same data. Other connections use other keys.
+    fn nonce(&self, seqnum: Seqnum, outgoing: bool) -> [u8; aead::NONCE_SIZE] {
+        let mut nonce = [0; aead::NONCE_SIZE];
+        nonce[0] = (self.initiator == outgoing) as u8;
+        LittleEndian::write_u64(&mut nonce[4..], seqnum);
+        nonce
+    }
+}
+
+/// A reliable connection to a peer over an `IcmpCommunicator`, or any other `Transport`.
+///
+/// An ODP can be moved to another thread, and the communicator shared with ODPs living in other
+/// threads. Keep in mind that an ODP drops the packets it reads from other peers rather than
+/// handing them over: ODPs on the same communicator should not receive concurrently, use an
+/// `OdpMux` or one communicator per thread instead (each raw socket gets every packet).
+pub struct ODP<T: Transport = IcmpCommunicator> {
+    com:         Arc<T>,
     peer:        InetAddr,
     seqnum:      Seqnum,
     peer_seqnum: Seqnum,
-    ack_wait:    Vec<(Seqnum, Vec<u8>)>,
+    ack_wait:    VecDeque<Unacked>, // by seqnum
+    window:      usize,
+    peer_window: usize,
+    max_bytes:   Option<usize>, // see `set_max_inflight_bytes`
+    rcvbuf_max:  Option<usize>, // see `set_recv_buf_autotune`
+    cwnd:        usize,
+    ssthresh:    usize,
+    cwnd_acked:  usize,            // packets acked since the last increase in congestion avoidance
+    dup_acks:    usize,
+    recovery:    Option<Seqnum>,   // last packet sent when a loss was seen, see `on_loss_`
+    rto:         Duration,
+    init_rto:    Duration,
+    srtt:        Option<Duration>,
+    rttvar:      Duration,
+    sendq:       VecDeque<(Seqnum, Vec<u8>)>,
+    reorder:     BTreeMap<Seqnum, Vec<u8>>,
+    frags:       Vec<u8>,
+    msg_len:     Option<usize>, // size of the message in `frags`, if its first fragment told
+    discard:     bool,          // dropping the fragments left of a message found invalid
+    max_msg:     usize,
+    connected:   bool,
+    closed:      bool,
+    fin:         Option<Seqnum>,
+    peer_isn:    Seqnum,
+    lost:        bool,
+    fin_on_drop: bool,
+    unreliable:  bool, // see `new_unreliable`
+    expired:     bool, // closed by `idle` or `lifetime`
+    timeouts:    usize,
+    unreachable: usize, // ICMP destination unreachable messages since the peer was last heard of
+    max_resend:  usize,
+    last_recv:   Instant,
+    last_ack:    Instant,
+    last_data:   Instant, // when data was last sent or received, see `set_idle_timeout`
+    started:     Instant, // when the connection was established
+    half_open:   bool,    // we accepted it, and the peer sent nothing else yet
+    idle:        Option<Duration>,
+    lifetime:    Option<Duration>,
+    ack_delay:   Option<Duration>,
+    ack_due:     Option<Instant>, // when the ACK for the packets held back must be sent
+    ack_held:    usize,           // packets received in order and not acknowledged yet
+    rbuf:        Vec<u8>,
+    oversized:   Oversized,
+    nak:         bool,
+    use_nak:     bool, // both peers want NAK packets
+    crc:         bool,
+    use_crc:     bool, // both peers want messages checked
+    want_cid:    bool,
+    cid:         Option<u64>, // agreed on during the handshake, or proposed while connecting
+    pending:     Option<Vec<u8>>, // (rest of) a message too large for the last buffer
+    inbox:       VecDeque<Vec<u8>>, // messages received during `flush`, not read yet
+    probe:       Option<(u64, Instant)>, // probe waiting for its reply, see `probe`
+    probe_rtt:   Option<Duration>,       // round-trip time of the last probe answered
+    pad_to:      usize,
+    max_size:    usize, // largest packet accepted
+    send_size:   usize, // largest packet sent: `max_size`, or less to fit the path MTU
+    pmtud:       bool,
+    path_mtu:    Option<usize>,
+    auth:        Option<Auth>,
+    #[cfg(feature = "compression")]
+    compress:    bool,
+    #[cfg(feature = "crypto")]
+    cipher:      Option<Cipher>,
+    stats:       OdpStats,
 }
 
-impl ODP {
+impl<T: Transport> ODP<T> {
 
-    pub fn new(com: Rc<IcmpCommunicator>, peer: InetAddr) -> ODP {
+    /// Create an ODP talking to `peer`. The connection must be established with `connect` or
+    /// `accept` before sending and receiving data.
+    pub fn new(com: Arc<T>, peer: InetAddr) -> ODP<T> {
         ODP {
-            com:         com,
-            peer:        peer,
-            seqnum:      0,
+            com,
+            peer,
+            seqnum:      0, // drawn by `connect` or `accept`
             peer_seqnum: 0,
-            ack_wait:    Vec::new(),
+            ack_wait:    VecDeque::new(),
+            window:      WINDOW_SIZE,
+            peer_window: WINDOW_SIZE,
+            max_bytes:   None,
+            rcvbuf_max:  None,
+            cwnd:        INIT_CWND,
+            ssthresh:    usize::MAX,
+            cwnd_acked:  0,
+            dup_acks:    0,
+            recovery:    None,
+            rto:         Duration::from_millis(RTO),
+            init_rto:    Duration::from_millis(RTO),
+            srtt:        None,
+            rttvar:      Duration::from_millis(0),
+            sendq:       VecDeque::new(),
+            reorder:     BTreeMap::new(),
+            frags:       Vec::new(),
+            msg_len:     None,
+            discard:     false,
+            max_msg:     MSG_MAX_SIZE,
+            connected:   false,
+            closed:      false,
+            fin:         None,
+            peer_isn:    0,
+            lost:        false,
+            fin_on_drop: false,
+            unreliable:  false,
+            expired:     false,
+            timeouts:    0,
+            unreachable: 0,
+            max_resend:  MAX_RETRANSMITS,
+            last_recv:   Instant::now(),
+            last_ack:    Instant::now(),
+            last_data:   Instant::now(),
+            started:     Instant::now(),
+            half_open:   false,
+            idle:        None,
+            lifetime:    None,
+            ack_delay:   None,
+            ack_due:     None,
+            ack_held:    0,
+            rbuf:        Vec::new(),
+            oversized:   Oversized::Split,
+            nak:         false,
+            use_nak:     false,
+            crc:         false,
+            use_crc:     false,
+            want_cid:    false,
+            cid:         None,
+            pending:     None,
+            inbox:       VecDeque::new(),
+            probe:       None,
+            probe_rtt:   None,
+            pad_to:      0,
+            max_size:    PKT_MAX_SIZE,
+            send_size:   PKT_MAX_SIZE,
+            pmtud:       false,
+            path_mtu:    None,
+            auth:        None,
+            #[cfg(feature = "compression")]
+            compress:    false,
+            #[cfg(feature = "crypto")]
+            cipher:      None,
+            stats:       OdpStats::default(),
         }
     }
 
-    pub fn rawfd(&self) -> &RawFd {
-        self.com.rawfd()
+    /// Create an ODP exchanging datagrams with `peer`, which must be unreliable as well: there is
+    /// no handshake, `send` and `recv` work right away without `connect` or `accept`. Each
+    /// message is sent once in a single packet, and `recv` delivers the ones that arrive as they
+    /// come, so they may be lost, duplicated or out of order. Messages larger than a packet fail
+    /// with `InvalidPacketSize`. Only padding applies, see `set_pad_to`. `shutdown` just closes
+    /// it, and `connect` or `accept` open it again.
+    pub fn new_unreliable(com: Arc<T>, peer: InetAddr) -> ODP<T> {
+        let mut odp = ODP::new(com, peer);
+        odp.unreliable = true;
+        odp.connected  = true;
+        odp
     }
 
-    pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
-
-        if self.ack_wait.len() >= WINDOW_SIZE {
-            return Err(ODPError::RemoteWindowFull);
+    /// Same as `new` but allow up to `window` (at least 1) unacknowledged packets in flight
+    /// instead of 2. The peer may lower it during the handshake.
+    pub fn with_window(com: Arc<T>, peer: InetAddr, window: usize) -> Result<ODP<T>> {
+        if window == 0 {
+            return Err(ODPError::InvalidWindow);
         }
+        let mut odp = ODP::new(com, peer);
+        odp.window      = window;
+        odp.peer_window = window;
+        Ok(odp)
+    }
 
-        // buffer to build the packet
-        let mut sysbuf = vec![0; PKT_HDR_SIZE];
+    /// Same as `new` but authenticate every packet with `key`, which the peer must use as well.
+    /// Packets from anyone else, e.g. forged by someone who saw our traffic, are dropped: they
+    /// lack a valid tag, which covers the type and seqnum of the packet along with its data.
+    /// Packets recorded and sent again by an attacker are dropped as well, even into a later
+    /// connection: past the handshake, packets are authenticated with a key derived from `key`
+    /// and the initial seqnums of both ends. A replayed connection request may still open a
+    /// connection, which carries nothing.
+    pub fn with_key(com: Arc<T>, peer: InetAddr, key: &[u8]) -> ODP<T> {
+        let mut odp = ODP::new(com, peer);
+        odp.auth = Some(Auth::new(key));
+        odp
+    }
 
-        sysbuf[0] = TYPE_SND; // add type
-        sysbuf[1] = 0;        // reserved byte
+    /// Same as `new` but compress the data of the packets we send, the ones that don't shrink are
+    /// sent as they are. Mostly worth it for text: the packets are compressed one by one.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(com: Arc<T>, peer: InetAddr) -> ODP<T> {
+        let mut odp = ODP::new(com, peer);
+        odp.compress = true;
+        odp
+    }
 
-        // write seqnum
-        let seqnum = self.seqnum;
-        LittleEndian::write_u64(&mut sysbuf[2..], seqnum);
-        self.seqnum += 1;
+    /// Same as `new` but encrypt the data we exchange with ChaCha20-Poly1305, using keys derived
+    /// from `secret`, which the peer must use as well, and from the initial seqnums of the
+    /// connection. All the packets are authenticated as with `with_key`, which also drops packets
+    /// that fail to decrypt. The data is not compressed, there is no combining this with
+    /// `with_compression`: the size of compressed packets would tell about the data they carry,
+    /// encrypted or not.
+    #[cfg(feature = "crypto")]
+    pub fn with_encryption(com: Arc<T>, peer: InetAddr, secret: &[u8]) -> ODP<T> {
+        let mut odp = ODP::with_key(com, peer, secret);
+        odp.cipher = Some(Cipher::new(secret));
+        odp
+    }
 
-        //debug!("> SND {} {:?}", seqnum, String::from_utf8(buf.to_vec()));
-        debug!("> SND {}", seqnum);
+    /// Delay after which an unacknowledged packet is retransmitted by `on_timeout`.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
 
-        // add user data
-        let to_write = cmp::min(PKT_MAX_SIZE-PKT_HDR_SIZE, buf.len());
-        sysbuf.extend_from_slice(&buf[..to_write]);
+    /// Set the retransmission timeout. The default of 1 second is only used until the first
+    /// round trip time is measured, the RTO is then derived from the measurements (RFC 6298).
+    pub fn set_rto(&mut self, rto: Duration) {
+        self.rto      = rto;
+        self.init_rto = rto;
+    }
 
-        match self.com.sendto(&sysbuf, self.peer) {
-            Err(e)                    => Err(ODPError::ICError(e)),
-            Ok(n) if n < PKT_HDR_SIZE => Err(ODPError::SndError),
-            Ok(n)                     => {
-                self.ack_wait.push((seqnum, sysbuf));
-                Ok(n-PKT_HDR_SIZE)
-            }
-        }
+    /// Number of times a packet is sent again, or of consecutive timeouts without news from the
+    /// peer, before giving up with `ConnectionLost`. Defaults to 8.
+    pub fn set_max_retransmits(&mut self, n: usize) {
+        self.max_resend = n;
     }
 
-    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
-        let mut sysbuf = [0; PKT_MAX_SIZE];
+    /// What `recv` does with the messages larger than its buffer. By default they are split: the
+    /// next calls deliver the rest, see `remaining`.
+    pub fn set_oversized(&mut self, oversized: Oversized) {
+        self.oversized = oversized;
+    }
 
-        match self.com.recvfrom(&mut sysbuf).map_err(ODPError::ICError)? {
-            None                              => Ok(None),
-            Some((_, p)) if p != self.peer    => Ok(None),
-            Some((s, _)) if s  < PKT_HDR_SIZE => Err(ODPError::ProtocolError),
-            Some((s, _)) => {
-                let pkttype   = sysbuf[0];
-                let _reserved = sysbuf[1];
-                match pkttype {
-                    TYPE_ACK => { self.handle_ack_(&sysbuf[..s]) }
-                    TYPE_AGN => { self.handle_agn_(&sysbuf[..s]) }
-                    TYPE_SND => { self.handle_snd_(&sysbuf[..s], buf) }
-                    _        => { Err(ODPError::ProtocolError) }
-                }
-            }
-        }
+    /// Number of bytes of the last message that `recv` has yet to deliver
+    pub fn remaining(&self) -> usize {
+        self.pending.as_ref().map_or(0, Vec::len)
     }
 
-    fn handle_ack_(&mut self, ack: &[u8]) -> Result<Option<usize>> {
-        let seqnum = LittleEndian::read_u64(&ack[2..]);
+    /// Wait up to `delay` before acknowledging a packet received in order, unless the next one
+    /// comes first: a single ACK then covers both, as with TCP's delayed acks. Other packets are
+    /// acknowledged right away. The ACKs due are sent by `recv` and `on_timeout`, call the latter
+    /// at least every `delay`, which should stay well below the RTO of the peer. Off by default.
+    pub fn set_delayed_ack(&mut self, delay: Option<Duration>) {
+        self.ack_delay = delay;
+    }
 
-        debug!("< ACK {}", seqnum);
+    /// Whether to ask for each packet found missing with a NAK packet as soon as a later one
+    /// arrives, rather than for the whole gap at once whenever out of order packets arrive (AGN
+    /// packets). Both peers must turn it on before the handshake, it is off by default and old
+    /// peers don't know about it.
+    pub fn set_nak(&mut self, on: bool) {
+        self.nak = on;
+    }
 
-        // remove packets whose seqnum is below the one found in the ack packet
-        self.ack_wait.retain(|&(s, _)| s > seqnum);
-        self.peer_seqnum = cmp::max(self.peer_seqnum, seqnum);
-        Ok(None)
+    /// Whether NAK packets are used on this connection, see `set_nak`
+    pub fn uses_nak(&self) -> bool {
+        self.use_nak
     }
 
-    fn handle_snd_(&mut self, snd: &[u8], buf: &mut [u8]) -> Result<Option<usize>> {
-        let seqnum = LittleEndian::read_u64(&snd[2..]);
+    /// Whether to end every message with its CRC-32, checked once it is reassembled: `recv`
+    /// drops a message that doesn't match and fails with `IntegrityError`, the connection goes
+    /// on. This catches what the ICMP checksum of each packet can't, e.g. a packet from someone
+    /// else using our magic slipping into a message. Both peers must turn it on before the
+    /// handshake, it is off by default and old peers don't know about it.
+    pub fn set_message_crc(&mut self, on: bool) {
+        self.crc = on;
+    }
 
-        debug!("< SND {}", seqnum);
+    /// Whether messages are checked on this connection, see `set_message_crc`
+    pub fn uses_message_crc(&self) -> bool {
+        self.use_crc
+    }
 
-        if seqnum < self.peer_seqnum {
-            // we already sent an ack for this packet, maybe our peer didn't get it?
-            // craft another ack packet with the last seqnum we acknowledged.
-            self.send_ack_(self.peer_seqnum)?;
-            Ok(None)
+    /// Whether to tag every packet with a random connection id, so that the connection survives
+    /// the peer changing address, e.g. behind a NAT: a valid data packet or ack from another
+    /// address carrying the id (and passing authentication, with a key) moves the connection
+    /// there. This is only safe along with `with_key`: without one, anyone seeing the id on the
+    /// way can take the connection over with a packet of their own. Both peers must turn it on
+    /// before the handshake, it is off by default and old peers don't know about it.
+    pub fn set_connection_id(&mut self, on: bool) {
+        self.want_cid = on;
+    }
+
+    /// The connection id in use, see `set_connection_id`
+    pub fn connection_id(&self) -> Option<u64> {
+        self.cid.filter(|_| self.connected)
+    }
+
+    /// Pad every packet we send to `len` bytes (at most the maximum packet size) with zeros, so
+    /// that they all look the same, or stop padding if 0 (the default). The communicator adds its
+    /// 4 bytes nonce: 52 gives the 56 bytes echo payloads of the default `ping`. The receiver
+    /// strips the padding whatever its own setting.
+    pub fn set_pad_to(&mut self, len: usize) {
+        self.pad_to = cmp::min(len, self.send_size);
+    }
+
+    /// Send packets of at most `size` bytes (1480 by default), and drop larger ones, so both ends
+    /// should agree on it. The communicator must be able to receive them whole, see
+    /// `IcmpCommunicator::max_payload`, and they must leave room for some data: fails with
+    /// `InvalidPacketSize` otherwise. Used for the packets sent after the call.
+    pub fn set_max_packet_size(&mut self, size: usize) -> Result<()> {
+        if size < PKT_MIN_SIZE || size > self.com.max_payload() {
+            return Err(ODPError::InvalidPacketSize);
         }
-        else if seqnum == self.peer_seqnum {
-            self.send_ack_(self.peer_seqnum)?;
-            self.peer_seqnum += 1;
-            Ok(Some(copy_buf(buf, &snd[PKT_HDR_SIZE..])))
+        self.max_size  = size;
+        self.send_size = self.path_mtu.map_or(size, |mtu| cmp::min(size, self.mtu_size_(mtu)));
+        self.pad_to    = cmp::min(self.pad_to, self.send_size);
+        Ok(())
+    }
+
+    /// `set_max_packet_size` to the largest size that goes through a link of MTU `mtu` without
+    /// being fragmented, middleboxes often drop fragmented ICMP.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        let size = mtu.saturating_sub(self.com.overhead());
+        self.set_max_packet_size(size)
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Discover the MTU of the path to the peer, and lower the size of the packets we send to fit
+    /// in it, while still accepting packets of the maximum size from the peer. Packets are sent
+    /// with the don't fragment flag, see `IcmpCommunicator::set_path_mtu_discovery`, which
+    /// applies to everyone using the communicator. The MTU is read after the first send and after
+    /// retransmissions, since packets too large for a link are lost.
+    pub fn set_path_mtu_discovery(&mut self, on: bool) -> Result<()> {
+        self.com.set_path_mtu_discovery(on).map_err(ODPError::ICError)?;
+        self.pmtud = on;
+        if !on {
+            self.path_mtu  = None;
+            self.send_size = self.max_size;
         }
-        else {
-            // we missed some packets, drop this one and request resending everything that we
-            // missed. TODO: store the packet and don't include it in the resend request.
-            self.send_agn_(self.peer_seqnum, seqnum)?;
-            Ok(None)
+        Ok(())
+    }
+
+    /// Size the kernel receive buffer of the communicator (see
+    /// `IcmpCommunicator::set_recv_bufsize`) to the bandwidth-delay product once the RTT is
+    /// known, at most `max` bytes: what the peer may send in a round trip, a window of full
+    /// packets, with room to spare. Otherwise the kernel may drop packets before we read them on
+    /// links with a high RTT, which looks like loss. It applies to everyone using the
+    /// communicator, and the buffer keeps its size once turned off. Off by default, since the
+    /// kernel caps it to `net.core.rmem_max` anyway.
+    pub fn set_recv_buf_autotune(&mut self, max: Option<usize>) {
+        self.rcvbuf_max = max;
+        self.tune_recv_buf_();
+    }
+
+    fn tune_recv_buf_(&mut self) {
+        let max = match self.rcvbuf_max {
+            Some(max) if self.srtt.is_some() => max,
+            _                                => return,
+        };
+        let bdp  = self.window * (self.max_size + self.com.overhead());
+        let size = cmp::min(bdp * RCVBUF_HEADROOM, max);
+        if size == self.stats.recv_bufsize {
+            return;
         }
+        debug!("receive buffer {} bytes", size);
+        if let Err(e) = self.com.set_recv_bufsize(size) {
+            debug!("set_recv_bufsize: {}", e);
+        }
+        // not tried again until the window or the limit change
+        self.stats.recv_bufsize = size;
     }
 
-    fn handle_agn_(&mut self, agn: &[u8]) -> Result<Option<usize>> {
-        let from = LittleEndian::read_u64(&agn[ 2..]);
-        let to   = LittleEndian::read_u64(&agn[10..]);
+    /// The path MTU found so far, `None` until path MTU discovery is on and something was sent.
+    pub fn path_mtu(&self) -> Option<usize> {
+        self.path_mtu
+    }
 
-        debug!("< AGN {} -> {}", from, to);
+    // Lower the size of the packets we send to what the path lets through, the kernel may know
+    // better by now. We still accept packets up to the maximum size: the path from the peer may
+    // differ, and the peer may not know yet.
+    fn update_path_mtu_(&mut self) {
+        let mtu = match self.com.path_mtu(self.peer) {
+            Ok(mtu) => mtu,
+            Err(_)  => return,
+        };
+        self.path_mtu = Some(mtu);
+        let size = self.mtu_size_(mtu);
+        if size < self.send_size {
+            debug!("path MTU {}, sending packets of at most {} bytes", mtu, size);
+            self.send_size = size;
+            self.pad_to    = cmp::min(self.pad_to, size);
+        }
+    }
 
-        if from > to {
-            return Err(ODPError::ProtocolError);
+    // Largest packet that fits in a link of MTU `mtu`
+    fn mtu_size_(&self, mtu: usize) -> usize {
+        cmp::max(mtu.saturating_sub(self.com.overhead()), PKT_MIN_SIZE)
+    }
+
+    /// Smoothed round trip time, `None` until an ack for a packet sent only once is received.
+    pub fn rtt_estimate(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rawfd(&self) -> &RawFd {
+        self.com.rawfd()
+    }
+
+    /// Counters of this connection
+    pub fn stats(&self) -> OdpStats {
+        OdpStats {
+            in_flight:       self.ack_wait.len(),
+            in_flight_bytes: self.inflight_bytes(),
+            seqnum:          self.seqnum,
+            peer_seqnum:     self.peer_seqnum,
+            ..self.stats
         }
+    }
 
-        // use the 'from' as an ack
-        self.ack_wait.retain(|&(s, _)| s >= from);
-        self.peer_seqnum = cmp::max(self.peer_seqnum, from);
+    /// Seqnum of our next SND packet
+    pub fn seqnum(&self) -> Seqnum {
+        self.seqnum
+    }
+
+    /// Seqnum of the next SND packet of the peer to deliver
+    pub fn peer_seqnum(&self) -> Seqnum {
+        self.peer_seqnum
+    }
+
+    /// Number of SND packets sent and waiting for an ack
+    pub fn inflight(&self) -> usize {
+        self.ack_wait.len()
+    }
+
+    /// Number of packets the peer said it can take in its last ACK, at most the window agreed on
+    /// during the handshake. Lower while it holds packets out of order.
+    pub fn peer_window(&self) -> usize {
+        self.peer_window
+    }
+
+    /// Number of packets `send` accepts before failing with `RemoteWindowFull`: the smallest of
+    /// our window, the peer's during the handshake, and `peer_window`
+    pub fn window(&self) -> usize {
+        cmp::min(self.window, self.peer_window)
+    }
+
+    /// Also fail `send` with `RemoteWindowFull` when the packets waiting for an ack or queued
+    /// would take more than `max` bytes with the message, headers included, so that large
+    /// messages don't pile up whatever the window. A message larger than `max` is still accepted
+    /// when nothing is buffered. `None`, the default, leaves the window as the only limit.
+    pub fn set_max_inflight_bytes(&mut self, max: Option<usize>) {
+        self.max_bytes = max;
+    }
+
+    /// Drop the messages of the peer larger than `size` bytes, 16 MiB by default, rather than
+    /// buffering their fragments until they are whole. They count as malformed packets, see
+    /// `OdpStats::malformed`.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_msg = size;
+    }
 
-        // resend packets (ignore the 'to' param for now, resend everything)
-        for &(seq, ref buf) in &self.ack_wait {
-            debug!("> RESND {}", seq);
-            self.com.sendto(buf, self.peer).map_err(ODPError::ICError)?;
+    /// Bytes of the packets waiting for an ack or queued, see `set_max_inflight_bytes`
+    pub fn inflight_bytes(&self) -> usize {
+        let unacked = self.ack_wait.iter().map(|p| p.pkt.len());
+        unacked.chain(self.sendq.iter().map(|(_, pkt)| pkt.len())).sum()
+    }
+
+    /// Congestion window: how many of the packets the window allows are actually put on the link
+    /// before waiting for acks, the others are queued. It starts at 10 packets, or the window if
+    /// smaller, and grows up to the window: by one per packet acked at first (slow start), by one
+    /// per window acked after a loss (congestion avoidance). It is halved when a packet is lost,
+    /// which is seen on timeout, on an AGN, or after 3 ACKs for the packet before it.
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Whether the handshake with the peer completed
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Whether the connection was shut down, by us or by our peer
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether we received anything from the peer during the last `timeout`
+    pub fn is_peer_alive(&self, timeout: Duration) -> bool {
+        self.last_recv.elapsed() < timeout
+    }
+
+    /// `Some` when our packets went unacknowledged for 3 timeouts in a row:
+    /// the peer is gone, or the connection half-open, rather than merely slow to ack a full
+    /// window. The connection is lost if that lasts, see `set_max_retransmits`.
+    pub fn stale_peer(&self) -> Option<StalePeer> {
+        if self.ack_wait.is_empty() || self.timeouts < STALE_TIMEOUTS {
+            return None;
         }
+        Some(StalePeer {
+            silent_for: self.last_ack.elapsed(),
+            half_open:  self.last_recv > self.last_ack,
+        })
+    }
 
-        Ok(None)
+    /// Send a keepalive packet, to be called periodically on idle connections so that the state
+    /// kept by firewalls on the way doesn't expire. The peer answers with an ack.
+    pub fn keepalive(&mut self) -> Result<()> {
+        let mut kal = [0; PKT_HDR_SIZE];
+
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
+
+        debug!("> KAL");
+
+        // keepalives don't consume a seqnum
+        kal[0] = TYPE_KAL; // type
+        kal[1] = VERSION_BITS; // version
+        LittleEndian::write_u64(&mut kal[2..], self.seqnum);
+
+        match self.sendto_(&kal, self.peer) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    fn send_agn_(&self, from: Seqnum, to: Seqnum) -> Result<()> {
-        let mut ack = [0; PKT_HDR_SIZE+8];
+    /// Establish the connection with a peer waiting in `accept`. Blocks until the peer answers,
+    /// fails with `NotConnected` if it doesn't after a few attempts.
+    pub fn connect(&mut self) -> Result<()> {
+        let mut buf = vec![0; self.max_size];
+        self.seqnum = random_isn()?;
 
-        debug!("> AGN {} -> {}", from, to);
+        // nothing to agree on, only reopen it after `shutdown` or `reset`
+        if self.unreliable {
+            self.connected = true;
+            self.closed    = false;
+            return Ok(());
+        }
 
-        ack[0] = TYPE_AGN; // type
-        ack[1] = 0;        // reserved byte
-        LittleEndian::write_u64(&mut ack[ 2..], from);
-        LittleEndian::write_u64(&mut ack[10..], to);
+        self.cid = if self.want_cid { Some(random_isn()?) } else { None };
 
-        match self.com.sendto(&ack, self.peer) {
-            Err(e) => Err(ODPError::ICError(e)),
-            Ok(n)  => {
-                if n != ack.len() {
-                    Err(ODPError::Unknown)
-                } else {
-                    Ok(())
+        for _ in 0..SYN_RETRIES {
+            self.send_syn_(TYPE_SYN)?;
+
+            let deadline = Instant::now() + self.rto;
+            loop {
+                let now = Instant::now();
+                if now >= deadline || !self.wait_readable_(Some(deadline - now))? {
+                    break;
+                }
+                if let Some(s) = self.recv_syn_(&mut buf, TYPE_SYA)? {
+                    self.handle_syn_(&buf[..s]);
+                    return Ok(());
                 }
             }
         }
+        Err(ODPError::NotConnected)
     }
 
-    fn send_ack_(&self, seqnum: Seqnum) -> Result<()> {
-        let mut ack = [0; PKT_HDR_SIZE];
+    /// Wait for the peer to `connect` and accept the connection.
+    pub fn accept(&mut self) -> Result<()> {
+        let mut buf = vec![0; self.max_size];
 
-        debug!("> ACK {}", seqnum);
+        if self.unreliable {
+            return self.connect();
+        }
 
-        ack[0] = TYPE_ACK; // type
-        ack[1] = 0;        // reserved byte
-        LittleEndian::write_u64(&mut ack[2..], seqnum);
+        loop {
+            self.wait_readable_(None)?;
+            if let Some(s) = self.recv_syn_(&mut buf, TYPE_SYN)? {
+                return self.accept_syn_(&buf[..s]);
+            }
+        }
+    }
 
-        match self.com.sendto(&ack, self.peer) {
-            Ok(PKT_HDR_SIZE) => Ok(()),
-            Ok(_)            => Err(ODPError::ProtocolError),
-            Err(e)           => Err(ODPError::ICError(e)),
+    fn accept_syn_(&mut self, syn: &[u8]) -> Result<()> {
+        self.seqnum = random_isn()?;
+        self.handle_syn_(syn);
+        self.half_open = true;
+        self.send_syn_(TYPE_SYA)
+    }
+
+    /// Retransmit the packets waiting for an ack right away, then wait until the peer
+    /// acknowledged all the data we sent, including what is still queued, e.g. before a
+    /// checkpoint. Unlike `shutdown`, the connection stays open and the messages received in the
+    /// meantime are kept for `recv`. Fails with `ConnectionLost` if the peer doesn't acknowledge
+    /// them after `set_max_retransmits` attempts.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
         }
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
+        self.resend_all_()?;
+
+        // what was not read yet comes before what is received now
+        let mut inbox = mem::take(&mut self.inbox);
+        let pending   = self.pending.take();
+        let res       = self.flush_wait_(&mut inbox);
+        self.inbox   = inbox;
+        self.pending = pending;
+        res
     }
-}
 
+    fn flush_wait_(&mut self, inbox: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        while (!self.ack_wait.is_empty() || !self.sendq.is_empty()) && !self.closed {
+            if self.wait_readable_(Some(self.wait_time_()))? {
+                self.recv_into_(inbox)?;
+            }
+            self.on_timeout(Instant::now())?;
+        }
+        Ok(())
+    }
 
-impl Evented for ODP {
-    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
-      -> io::Result<()> {
-        EventedFd(&self.com.rawfd()).register(poll, token, interest, opts)
+    // Process a packet, keeping the message it completes in `inbox`
+    fn recv_into_(&mut self, inbox: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        if let Some(msg) = self.recv_whole_()? {
+            inbox.push_back(msg);
+        }
+        Ok(())
     }
 
-    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
-      -> io::Result<()> {
-        EventedFd(&self.com.rawfd()).reregister(poll, token, interest, opts)
+    // `recv` the next message whole, whatever `set_oversized` says
+    fn recv_whole_(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut buf   = vec![0; self.max_size];
+        let oversized = mem::replace(&mut self.oversized, Oversized::Split);
+        let res       = self.recv(&mut buf);
+        self.oversized = oversized;
+        Ok(res?.map(|n| self.whole_(&buf, n)))
     }
 
-    fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        EventedFd(&self.com.rawfd()).deregister(poll)
+    // The message delivered with `Oversized::Split`, of which `buf` holds the first `n` bytes
+    fn whole_(&mut self, buf: &[u8], n: usize) -> Vec<u8> {
+        let mut msg = buf[..n].to_vec();
+        if let Some(rest) = self.pending.take() {
+            msg.extend_from_slice(&rest);
+        }
+        msg
     }
-}
 
+    /// Measure the path to the peer: send `count` probes, one at a time, each waiting up to the
+    /// RTO for its answer. The round-trip times update the RTT estimate as acks do. Like for
+    /// `flush`, the connection goes on meanwhile and the messages received are kept for `recv`.
+    pub fn probe(&mut self, count: usize) -> Result<ProbeStats> {
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
+        }
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
 
-fn copy_buf(dst: &mut[u8], src: &[u8]) -> usize {
-    let copylen = cmp::min(dst.len(), src.len());
-    dst[..copylen].copy_from_slice(&src[..copylen]);
-    copylen
+        let mut stats = ProbeStats::default();
+        let mut inbox = mem::take(&mut self.inbox);
+        let pending   = self.pending.take();
+        let res       = self.probe_wait_(count, &mut stats, &mut inbox);
+        self.inbox   = inbox;
+        self.pending = pending;
+        self.probe   = None;
+        res.map(|_| stats)
+    }
+
+    fn probe_wait_(&mut self, count: usize, stats: &mut ProbeStats,
+                   inbox: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        // numbered from a random base, so that late answers to earlier calls don't match
+        let base = random_isn()?;
+        for i in 0..count as u64 {
+            if self.closed {
+                return Err(ODPError::NotConnected);
+            }
+            let number = base.wrapping_add(i);
+            self.send_probe_(TYPE_PRB, number)?;
+            let sent = Instant::now();
+            self.probe     = Some((number, sent));
+            self.probe_rtt = None;
+            stats.sent    += 1;
+
+            let deadline = sent + self.rto;
+            while self.probe_rtt.is_none() && !self.closed {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                if self.wait_readable_(Some(cmp::min(deadline - now, self.wait_time_())))? {
+                    self.recv_into_(inbox)?;
+                }
+                self.on_timeout(Instant::now())?;
+            }
+            if let Some(rtt) = self.probe_rtt.take() {
+                self.rtt_sample_(rtt);
+                stats.rtts.push(rtt);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_probe_(&self, pkttype: u8, number: u64) -> Result<()> {
+        let mut prb = [0; PKT_HDR_SIZE];
+
+        debug!("> {} {}", if pkttype == TYPE_PRB { "PRB" } else { "PRR" }, number);
+
+        prb[0] = pkttype; // type
+        prb[1] = VERSION_BITS; // version
+        LittleEndian::write_u64(&mut prb[2..], number);
+
+        match self.sendto_(&prb, self.peer) {
+            Ok(_)                     => Ok(()),
+            // as if it was lost
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+        }
+    }
+
+    // Retransmit the packets waiting for an ack, whatever their age
+    fn resend_all_(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for p in &mut self.ack_wait {
+            debug!("> RESND {}", p.seqnum);
+            match send_padded(&*self.com, &p.pkt, self.peer, self.pad_to, self.cid,
+                              self.auth.as_ref()) {
+                // `on_timeout` sends the rest
+                Err(ICError::PaceLimited) => break,
+                res => res?,
+            };
+            p.sent    = now;
+            p.resent += 1;
+            self.stats.retransmits += 1;
+        }
+        Ok(())
+    }
+
+    /// Close the connection once the peer acknowledged all the data we sent. Data received in
+    /// the meantime is discarded. Fails with `AckError` if the peer doesn't acknowledge the end
+    /// of the connection, it may have missed it.
+    pub fn shutdown(&mut self) -> Result<()> {
+        let mut buf = vec![0; self.max_size];
+
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
+        // nothing to wait for, nobody to tell
+        if self.unreliable {
+            self.close_();
+            return Ok(());
+        }
+
+        while (!self.ack_wait.is_empty() || !self.sendq.is_empty()) && !self.closed {
+            if self.wait_readable_(Some(self.wait_time_()))? {
+                self.recv(&mut buf)?;
+            }
+            self.on_timeout(Instant::now())?;
+        }
+
+        let fin = self.seqnum;
+        self.fin = Some(fin);
+        for _ in 0..FIN_RETRIES {
+            self.send_fin_(fin)?;
+
+            let deadline = Instant::now() + self.rto;
+            while !self.closed {
+                let now = Instant::now();
+                if now >= deadline || !self.wait_readable_(Some(deadline - now))? {
+                    break;
+                }
+                self.recv(&mut buf)?;
+            }
+            if self.closed {
+                return Ok(());
+            }
+        }
+        self.close_();
+        Err(ODPError::AckError)
+    }
+
+    /// Shut the connection down like `shutdown`, then release what it holds whatever the outcome,
+    /// including the data received but not read yet. Does nothing if the connection is not
+    /// established or already closed.
+    pub fn close(&mut self) -> Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        let res = self.shutdown();
+        self.close_();
+        self.rbuf    = Vec::new();
+        self.pending = None;
+        self.inbox.clear();
+        res
+    }
+
+    /// Send a FIN when dropped while still connected, so that the peer doesn't wait for us
+    /// forever. It is sent once and not waited for, and the peer only closes once it received
+    /// all our data, which may never happen if some was in flight: use `close` to be sure. Off by
+    /// default.
+    pub fn set_fin_on_drop(&mut self, on: bool) {
+        self.fin_on_drop = on;
+    }
+
+    /// End the session once no data was sent or received for `timeout`: `recv` and `on_timeout`
+    /// then send a FIN and fail with `SessionExpired`, as do the later calls until `reset`.
+    /// Keepalives don't count as data. Off by default.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle = timeout;
+    }
+
+    /// End the session like `set_idle_timeout` once it lasted for `lifetime` since the
+    /// connection was established, whatever the traffic. Off by default.
+    pub fn set_max_lifetime(&mut self, lifetime: Option<Duration>) {
+        self.lifetime = lifetime;
+    }
+
+    /// Forget the connection, e.g. after `ConnectionLost` or if the peer restarted, and start
+    /// over as a new ODP with the same settings. The data in flight or not delivered yet is
+    /// lost. Establish a new connection with `connect` or `accept`, or use `reconnect`.
+    pub fn reset(&mut self) {
+        if self.connected {
+            debug!("reset");
+        }
+        self.close_();
+        self.closed      = false;
+        self.lost        = false;
+        self.expired     = false;
+        self.seqnum      = 0;
+        self.peer_seqnum = 0;
+        self.peer_isn    = 0;
+        self.fin         = None;
+        self.timeouts    = 0;
+        self.half_open   = false;
+        self.unreachable = 0;
+        self.rto         = self.init_rto;
+        self.srtt        = None;
+        self.rttvar      = Duration::from_millis(0);
+        self.last_recv   = Instant::now();
+        self.last_ack    = Instant::now();
+        self.rbuf.clear();
+        self.pending = None;
+        self.inbox.clear();
+        self.cid     = None;
+        self.stats = OdpStats::default();
+
+        // the counters of the peer start over as well if it is new
+        if let Some(ref mut auth) = self.auth {
+            auth.session = None;
+            auth.top     = None;
+            auth.seen    = 0;
+        }
+    }
+
+    /// `reset` then `connect` again. The peer has to be waiting in `accept`: it ignores
+    /// connection requests while the previous connection lasts on its side.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.reset();
+        self.connect()
+    }
+
+    // End the session if it was idle or lasted for too long, telling the peer if we can
+    fn check_expiry_(&mut self, now: Instant) -> Result<()> {
+        if self.expired {
+            return Err(ODPError::SessionExpired);
+        }
+        if !self.connected || self.closed {
+            return Ok(());
+        }
+        let idle = self.idle.is_some_and(|d| now.saturating_duration_since(self.last_data) >= d);
+        let old  = self.lifetime.is_some_and(|d| now.saturating_duration_since(self.started) >= d);
+        if !idle && !old {
+            return Ok(());
+        }
+        debug!("session expired");
+        // best effort, the peer notices anyway when we stop answering
+        let _ = self.send_fin_(self.seqnum);
+        self.close_();
+        self.expired = true;
+        Err(ODPError::SessionExpired)
+    }
+
+    fn lose_(&mut self) -> ODPError {
+        debug!("peer unreachable");
+        self.close_();
+        self.lost = true;
+        ODPError::ConnectionLost
+    }
+
+    fn close_(&mut self) {
+        self.connected = false;
+        self.closed    = true;
+        self.ack_due   = None;
+        self.ack_held  = 0;
+        self.ack_wait.clear();
+        self.sendq.clear();
+        self.reorder.clear();
+        self.frags.clear();
+        self.msg_len = None;
+        self.discard = false;
+    }
+
+    // Whether `pkt`, received from an unknown address, carries our connection id. It still has to
+    // pass authentication before the connection moves there.
+    fn claims_(&self, pkt: &[u8]) -> bool {
+        let end = pkt.len().saturating_sub(self.auth.as_ref().map_or(0, |_| AUTH_SIZE));
+        match self.cid {
+            Some(cid) if self.connected && end >= CID_SIZE => {
+                LittleEndian::read_u64(&pkt[end-CID_SIZE..end]) == cid
+            }
+            _ => false,
+        }
+    }
+
+    // Receive one packet and return its size if it is a handshake packet of type `pkttype` sent
+    // by our peer
+    fn recv_syn_(&mut self, buf: &mut [u8], pkttype: u8) -> Result<Option<usize>> {
+        let s = match self.com.recvfrom(buf).map_err(ODPError::ICError)? {
+            Some((s, p)) if p == self.peer && s <= buf.len() => s,
+            _                                                => return Ok(None),
+        };
+        match self.authenticate_(&buf[..s]) {
+            Some(syn) if syn.len() >= SYN_SIZE && syn[0] == pkttype && known_version(syn) => {
+                Ok(Some(syn.len()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // The packet without its trailer, or `None` if it is forged or replayed. Without a key there
+    // is no trailer.
+    fn authenticate_<'a>(&mut self, pkt: &'a [u8]) -> Option<&'a [u8]> {
+        let auth = match self.auth {
+            Some(ref mut auth) => auth,
+            None               => return Some(pkt),
+        };
+        match auth.open(pkt) {
+            Ok(pkt) => Some(pkt),
+            Err(Rejected::BadTag) => {
+                debug!("< bad tag");
+                self.stats.auth_failures += 1;
+                None
+            }
+            Err(Rejected::Replayed) => {
+                debug!("< replayed");
+                self.stats.replays += 1;
+                None
+            }
+        }
+    }
+
+    // Take the initial seqnum and window of the peer from a SYN or SYA packet
+    fn handle_syn_(&mut self, syn: &[u8]) {
+        let isn    = LittleEndian::read_u64(&syn[2..]);
+        let window = LittleEndian::read_u32(&syn[PKT_HDR_SIZE..]) as usize;
+
+        debug!("< SYN {} window {}", isn, window);
+
+        // 0 means the peer leaves the choice to us
+        if window != 0 {
+            self.window = cmp::min(self.window, window);
+        }
+        self.peer_window = self.window;
+        self.cwnd        = cmp::min(INIT_CWND, self.window);
+        self.ssthresh    = usize::MAX;
+        self.cwnd_acked  = 0;
+        self.dup_acks    = 0;
+        self.recovery    = None;
+        self.use_nak     = self.nak && syn[1] & FLAG_NAK != 0;
+        self.use_crc     = self.crc && syn[1] & FLAG_CRC != 0;
+        self.cid         = self.syn_cid_(syn);
+        self.peer_isn    = isn;
+        self.peer_seqnum = isn;
+        self.connected   = true;
+        self.last_recv   = Instant::now();
+        self.last_ack    = Instant::now();
+        self.last_data   = Instant::now();
+        self.started     = Instant::now();
+
+        // initial seqnums of the initiator then of the other end
+        let isns = if syn[0] == TYPE_SYA { (self.seqnum, isn) } else { (isn, self.seqnum) };
+        if let Some(ref mut auth) = self.auth {
+            auth.start(isns);
+        }
+
+        #[cfg(feature = "crypto")]
+        {
+            if let Some(ref mut cipher) = self.cipher {
+                cipher.start(isns, syn[0] == TYPE_SYA);
+            }
+        }
+    }
+
+    // The connection id to use according to a SYN or SYA packet: the one the initiator picked, if
+    // we both want one
+    fn syn_cid_(&self, syn: &[u8]) -> Option<u64> {
+        if !self.want_cid || syn[1] & FLAG_CID == 0 || syn.len() < SYN_SIZE + CID_SIZE {
+            return None;
+        }
+        let cid = LittleEndian::read_u64(&syn[syn.len()-CID_SIZE..]);
+        match syn[0] {
+            TYPE_SYA if self.cid != Some(cid) => None,
+            _                                 => Some(cid),
+        }
+    }
+
+    fn send_syn_(&self, pkttype: u8) -> Result<()> {
+        let mut syn = [0; SYN_SIZE];
+
+        debug!("> SYN {} window {}", self.seqnum, self.window);
+
+        syn[0] = pkttype; // type
+        syn[1] = VERSION_BITS; // version
+        if self.nak {
+            syn[1] |= FLAG_NAK;
+        }
+        if self.cid.is_some() {
+            syn[1] |= FLAG_CID;
+        }
+        if self.crc {
+            syn[1] |= FLAG_CRC;
+        }
+        LittleEndian::write_u64(&mut syn[2..], self.seqnum);
+        LittleEndian::write_u32(&mut syn[PKT_HDR_SIZE..], self.window as u32);
+
+        match self.sendto_(&syn, self.peer) {
+            Ok(_)                     => Ok(()),
+            // as if it was lost, it is sent again
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+        }
+    }
+
+    fn sendto_(&self, pkt: &[u8], peer: InetAddr) -> result::Result<usize, ICError> {
+        send_padded(&*self.com, pkt, peer, self.pad_to, self.cid, self.auth.as_ref())
+    }
+
+    // Wait until a packet can be read, at most `timeout` if any. Return false on timeout.
+    fn wait_readable_(&self, timeout: Option<Duration>) -> Result<bool> {
+        let timeout = timeout.map_or(-1, |t| t.as_millis() as i32 + 1);
+        let mut fds = [PollFd::new(*self.com.rawfd(), POLLIN, EventFlags::empty())];
+        match poll(&mut fds, timeout) {
+            Ok(n)  => Ok(n > 0),
+            Err(e) => Err(ODPError::ICError(ICError::Nix(e))),
+        }
+    }
+
+    /// Send `buf` as one message, which `recv` on the other end returns whole. There are no empty
+    /// messages: an empty `buf` sends a keepalive instead, which delivers nothing and doesn't
+    /// consume a seqnum, and `Ok(0)` is returned.
+    pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
+
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
+        }
+        if self.expired {
+            return Err(ODPError::SessionExpired);
+        }
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
+        if buf.is_empty() {
+            return self.keepalive().map(|_| 0);
+        }
+        if self.unreliable {
+            return self.send_dgm_(buf);
+        }
+
+        if self.ack_wait.len() + self.sendq.len() >= self.window() {
+            return Err(ODPError::RemoteWindowFull);
+        }
+
+        let checked;
+        let msg = if self.use_crc {
+            checked = with_crc(buf);
+            &checked[..]
+        } else {
+            buf
+        };
+        let framed;
+        let msg = if msg.len() > self.payload_size_() {
+            if msg.len() > u32::MAX as usize {
+                return Err(ODPError::InvalidPacketSize);
+            }
+            framed = with_len(msg);
+            &framed[..]
+        } else {
+            msg
+        };
+
+        // split the message in fragments, the ones the window has no room for are sent as acks
+        // come back
+        let hdr_size = self.hdr_size_();
+        let chunks: Vec<&[u8]> = msg.chunks(self.payload_size_()).collect();
+        if let Some(max) = self.max_bytes {
+            let buffered = self.inflight_bytes();
+            if buffered > 0 && buffered + msg.len() + chunks.len() * hdr_size > max {
+                return Err(ODPError::RemoteWindowFull);
+            }
+        }
+        self.last_data = Instant::now();
+        for (i, chunk) in chunks.iter().enumerate() {
+            // buffer to build the packet
+            let mut sysbuf = vec![0; hdr_size];
+
+            sysbuf[0] = TYPE_SND; // add type
+            sysbuf[1] = VERSION_BITS; // add version
+            if i + 1 < chunks.len() {
+                sysbuf[1] |= FLAG_MORE;
+            }
+
+            // write seqnum
+            let seqnum = self.seqnum;
+            LittleEndian::write_u64(&mut sysbuf[2..], seqnum);
+            self.seqnum = self.seqnum.wrapping_add(1);
+
+            let packed = self.pack_(chunk);
+            let chunk  = match packed {
+                Some(ref packed) => { sysbuf[1] |= FLAG_LZ4; &packed[..] }
+                None             => *chunk,
+            };
+
+            // the padding itself is added by `sendto_`, along with the trailer
+            if self.pad_to > 0 {
+                sysbuf[1] |= FLAG_PAD;
+            }
+
+            // once the flags are all set, since the header is authenticated too
+            let sealed = self.seal_(&mut sysbuf, chunk);
+            let chunk  = sealed.as_ref().map_or(chunk, |sealed| &sealed[..]);
+
+            if self.pad_to > 0 {
+                LittleEndian::write_u16(&mut sysbuf[PKT_HDR_SIZE..], chunk.len() as u16);
+            }
+
+            // add user data
+            sysbuf.extend_from_slice(chunk);
+            self.sendq.push_back((seqnum, sysbuf));
+        }
+        self.stats.bytes_sent += buf.len() as u64;
+
+        self.send_queued_()?;
+        if self.pmtud && self.path_mtu.is_none() {
+            self.update_path_mtu_();
+        }
+        Ok(buf.len())
+    }
+
+    // Send `buf` in a single DGM packet, laid out like a SND packet but not acknowledged
+    fn send_dgm_(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.len() > self.payload_size_() {
+            return Err(ODPError::InvalidPacketSize);
+        }
+        let mut dgm = vec![0; self.hdr_size_()];
+
+        debug!("> DGM {}", self.seqnum);
+
+        dgm[0] = TYPE_DGM; // type
+        dgm[1] = VERSION_BITS; // version
+        LittleEndian::write_u64(&mut dgm[2..], self.seqnum);
+        if self.pad_to > 0 {
+            dgm[1] |= FLAG_PAD;
+            LittleEndian::write_u16(&mut dgm[PKT_HDR_SIZE..], buf.len() as u16);
+        }
+        dgm.extend_from_slice(buf);
+
+        // the seqnum only numbers the datagrams
+        self.sendto_(&dgm, self.peer)?;
+        self.seqnum = self.seqnum.wrapping_add(1);
+        self.last_data = Instant::now();
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent   += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    // The compressed `data`, if it is to be compressed and gets smaller
+    #[cfg(feature = "compression")]
+    fn pack_(&self, data: &[u8]) -> Option<Vec<u8>> {
+        // never along with encryption, see `with_encryption`
+        if !self.compress || self.seal_size_() > 0 {
+            return None;
+        }
+        Some(lz4::compress(data)).filter(|packed| packed.len() < data.len())
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn pack_(&self, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Send as much of `buf` as the window has room for right now, one message per packet, and
+    /// return how much was sent: the caller keeps the rest for when acks make room, e.g. the next
+    /// time the socket is readable. Meant for streams, since `buf` no longer arrives as a single
+    /// message. Fails with `RemoteWindowFull` if there is no room at all.
+    pub fn send_all(&mut self, buf: &[u8]) -> Result<usize> {
+        // the CRC has to fit in the packet as well, which then needs no framing either
+        let size = self.payload_size_() - if self.use_crc { CRC_SIZE } else { 0 };
+        let mut sent = 0;
+        for chunk in buf.chunks(size) {
+            match self.send(chunk) {
+                Ok(_)                                       => sent += chunk.len(),
+                Err(ODPError::RemoteWindowFull) if sent > 0 => break,
+                Err(e)                                      => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+
+    // Size of the header of SND packets
+    fn hdr_size_(&self) -> usize {
+        if self.pad_to > 0 { PKT_HDR_SIZE + PAD_LEN_SIZE } else { PKT_HDR_SIZE }
+    }
+
+    // Largest data a SND packet can carry
+    fn payload_size_(&self) -> usize {
+        self.send_size - self.hdr_size_() - self.trailer_size_() - self.seal_size_()
+    }
+
+    // Room taken at the end of each packet by the connection id and the trailer of connections
+    // with a key
+    fn trailer_size_(&self) -> usize {
+        self.auth.as_ref().map_or(0, |_| AUTH_SIZE) + self.cid.map_or(0, |_| CID_SIZE)
+    }
+
+    // Room taken in each SND packet by encryption
+    #[cfg(feature = "crypto")]
+    fn seal_size_(&self) -> usize {
+        if self.cipher.is_some() { aead::TAG_SIZE } else { 0 }
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn seal_size_(&self) -> usize {
+        0
+    }
+
+    // `data` encrypted for the SND packet starting with the header `hdr`, which gets flagged, if
+    // the data is to be encrypted
+    #[cfg(feature = "crypto")]
+    fn seal_(&self, hdr: &mut [u8], data: &[u8]) -> Option<Vec<u8>> {
+        let cipher = self.cipher.as_ref()?;
+        hdr[1] |= FLAG_SEALED;
+        let nonce = cipher.nonce(LittleEndian::read_u64(&hdr[2..]), true);
+        let mut sealed = Vec::with_capacity(data.len() + aead::TAG_SIZE);
+        cipher.aead.seal(&nonce, &hdr[..PKT_HDR_SIZE], data, &mut sealed);
+        Some(sealed)
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn seal_(&self, _hdr: &mut [u8], _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    // The SND packet `snd` decrypted, with no padding, if it was encrypted. Fails if it can't be
+    // decrypted, or isn't encrypted while it should.
+    #[cfg(feature = "crypto")]
+    fn open_snd_(&self, snd: &[u8]) -> result::Result<Option<Vec<u8>>, ()> {
+        let cipher = match self.cipher {
+            Some(ref cipher)                  => cipher,
+            None if snd[1] & FLAG_SEALED == 0 => return Ok(None),
+            None                              => return Err(()),
+        };
+        if snd[1] & FLAG_SEALED == 0 {
+            return Err(());
+        }
+        let nonce = cipher.nonce(LittleEndian::read_u64(&snd[2..]), false);
+        let mut pkt = snd[..PKT_HDR_SIZE].to_vec();
+        if !cipher.aead.open(&nonce, &snd[..PKT_HDR_SIZE], snd_data(snd).ok_or(())?, &mut pkt) {
+            return Err(());
+        }
+        pkt[1] &= !(FLAG_SEALED | FLAG_PAD);
+        Ok(Some(pkt))
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn open_snd_(&self, snd: &[u8]) -> result::Result<Option<Vec<u8>>, ()> {
+        if snd[1] & FLAG_SEALED == 0 { Ok(None) } else { Err(()) }
+    }
+
+    // Send the queued packets the window has room for
+    fn send_queued_(&mut self) -> Result<()> {
+        while self.ack_wait.len() < cmp::min(self.cwnd, self.window()) {
+            let (seqnum, sysbuf) = match self.sendq.pop_front() {
+                Some(pkt) => pkt,
+                None      => return Ok(()),
+            };
+
+            //debug!("> SND {} {:?}", seqnum, String::from_utf8(buf.to_vec()));
+            debug!("> SND {}", seqnum);
+
+            match self.sendto_(&sysbuf, self.peer) {
+                // the rest goes out as the rate limit allows, see `on_timeout`
+                Err(ICError::PaceLimited) => {
+                    self.sendq.push_front((seqnum, sysbuf));
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.sendq.push_front((seqnum, sysbuf));
+                    return Err(e.into());
+                }
+                Ok(_) => {
+                    self.stats.packets_sent += 1;
+                    self.ack_wait.push_back(Unacked {
+                        seqnum,
+                        pkt:    sysbuf,
+                        sent:   Instant::now(),
+                        resent: 0,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Retransmit the packets that have been waiting for an ack for longer than the RTO, and send
+    /// the ones a rate limit held back (see `IcmpCommunicator::set_pace_limit`). Call it
+    /// regularly, e.g. whenever polling times out, with a timeout of at most `rto()`.
+    pub fn on_timeout(&mut self, now: Instant) -> Result<()> {
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
+        }
+        self.check_expiry_(now)?;
+        if self.ack_due.is_some_and(|due| now >= due) {
+            self.flush_ack_()?;
+        }
+
+        let mut expired = false;
+        for p in &mut self.ack_wait {
+            if now.duration_since(p.sent) < self.rto {
+                continue;
+            }
+            // the peer had enough chances to answer
+            if p.resent >= self.max_resend {
+                return Err(self.lose_());
+            }
+            debug!("> RESND {}", p.seqnum);
+            match send_padded(&*self.com, &p.pkt, self.peer, self.pad_to, self.cid,
+                              self.auth.as_ref()) {
+                // try again next time
+                Err(ICError::PaceLimited) => break,
+                res => res?,
+            };
+            p.sent    = now;
+            p.resent += 1;
+            expired   = true;
+            self.stats.retransmits += 1;
+        }
+
+        if expired {
+            // back off until the next measurement, the link may be congested
+            self.rto = cmp::min(self.rto * 2, Duration::from_millis(RTO_MAX));
+            self.recovery = None;
+            self.on_loss_();
+            self.timeouts += 1;
+            if self.timeouts > self.max_resend {
+                return Err(self.lose_());
+            }
+            if self.timeouts == STALE_TIMEOUTS {
+                debug!("peer stale, no ack for {:?}", self.last_ack.elapsed());
+            }
+            if self.pmtud {
+                self.update_path_mtu_();
+            }
+        }
+        self.send_queued_()
+    }
+
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let mut sysbuf = vec![0; self.max_size];
+
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
+        }
+        self.check_expiry_(Instant::now())?;
+        if self.pending.is_none() {
+            self.pending = self.inbox.pop_front();
+        }
+        if let Some(n) = self.recv_pending_(buf)? {
+            return Ok(Some(n));
+        }
+        // nothing more will come
+        if self.closed {
+            return Ok(None);
+        }
+        if !self.connected {
+            return Err(ODPError::NotConnected);
+        }
+        if self.ack_due.is_some_and(|due| Instant::now() >= due) {
+            self.flush_ack_()?;
+        }
+
+        // deliver what we received out of order first, now that the gap before it has closed
+        if let Some(n) = self.recv_buffered_(buf)? {
+            return self.fitted_(n).map(Some);
+        }
+
+        match self.com.recvfrom_reason(&mut sysbuf)? {
+            RecvOutcome::Dropped(_)                       => Ok(None),
+            RecvOutcome::IcmpError(report)                => self.handle_icmp_error_(report),
+            RecvOutcome::Message(s, _) if s > sysbuf.len() => Ok(None),
+            RecvOutcome::Message(s, p)                     => {
+                match self.handle_packet_(&sysbuf[..s], p, buf)? {
+                    Some(n) => self.fitted_(n).map(Some),
+                    None    => Ok(None),
+                }
+            }
+        }
+    }
+
+    // A router or the peer's host tells that a packet to the peer did not get through: some
+    // unreachable messages in a row and the peer is lost. Our packets are retransmitted anyway,
+    // so a single one could just be a route flapping.
+    fn handle_icmp_error_(&mut self, report: IcmpReport) -> Result<Option<usize>> {
+        if report.dest.to_std().ip() != self.peer.to_std().ip() {
+            return Ok(None);
+        }
+        debug!("< ICMP {:?} {} from {}", report.kind, report.code, report.from.to_std());
+
+        self.stats.icmp_errors += 1;
+        if report.kind == IcmpErrorKind::Unreachable {
+            self.unreachable += 1;
+            if self.unreachable >= UNREACHABLE_MAX {
+                return Err(self.lose_());
+            }
+        }
+        Ok(None)
+    }
+
+    // Deliver what did not fit in the buffer last time: the next part of the message, or all of
+    // it if it fits now
+    fn recv_pending_(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let mut msg = match self.pending.take() {
+            Some(msg) => msg,
+            None      => return Ok(None),
+        };
+        if msg.len() > buf.len() && self.oversized == Oversized::Fail {
+            let needed = msg.len();
+            self.pending = Some(msg);
+            return Err(ODPError::BufferTooSmall { needed });
+        }
+        let n = copy_buf(buf, &msg);
+        if n < msg.len() && self.oversized != Oversized::Truncate {
+            msg.drain(..n);
+            self.pending = Some(msg);
+        }
+        Ok(Some(n))
+    }
+
+    // `n`, the size of what was just delivered, unless the message was kept for not fitting
+    fn fitted_(&self, n: usize) -> Result<usize> {
+        match self.pending {
+            Some(ref msg) if self.oversized == Oversized::Fail => {
+                Err(ODPError::BufferTooSmall { needed: msg.len() })
+            }
+            _ => Ok(n),
+        }
+    }
+
+    /// Like `recv`, but wait up to `timeout` for a message. `Ok(None)` means that none came in
+    /// time (or that the connection is closed, see `is_closed`), so that the caller can run its
+    /// timers, e.g. `on_timeout` or `keepalive`, before trying again.
+    pub fn recv_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.lost || self.closed || !self.connected {
+                return self.recv(buf);
+            }
+            let now  = Instant::now();
+            let left = deadline.saturating_duration_since(now);
+            let wait = match self.ack_due {
+                Some(due) => cmp::min(left, due.saturating_duration_since(now)),
+                None      => left,
+            };
+            let ready = self.pending.is_some()
+                || !self.inbox.is_empty()
+                || self.reorder.contains_key(&self.peer_seqnum)
+                || self.wait_readable_(Some(wait))?;
+            if !ready && Instant::now() >= deadline {
+                return Ok(None);
+            }
+            // the delayed ACK is due
+            if !ready {
+                self.flush_ack_()?;
+                continue;
+            }
+            // our own packets and control packets wake us up without delivering anything
+            if let Some(n) = self.recv(buf)? {
+                return Ok(Some(n));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Handle `pkt`, an ICMP payload from `peer`, the way `recv` handles what it reads, without
+    /// reading from the communicator: e.g. to fuzz the protocol, or for packets read by other
+    /// means. Until the connection is established, only a connection request is taken, as by
+    /// `accept`. The messages it completes are kept for `recv`.
+    pub fn process_packet(&mut self, pkt: &[u8], peer: InetAddr) -> Result<()> {
+        if self.lost {
+            return Err(ODPError::ConnectionLost);
+        }
+        if !self.connected {
+            if peer != self.peer || pkt.len() > self.max_size {
+                return Ok(());
+            }
+            return match self.authenticate_(pkt) {
+                Some(syn) if syn.len() >= SYN_SIZE && syn[0] == TYPE_SYN && known_version(syn) => {
+                    self.accept_syn_(syn)
+                }
+                _ => Ok(()),
+            };
+        }
+
+        // the messages are kept whole, after the rest of the one `recv` was delivering if any
+        let mut buf   = vec![0; self.max_size];
+        let kept      = self.pending.take();
+        let oversized = mem::replace(&mut self.oversized, Oversized::Split);
+        let mut res   = self.handle_packet_(pkt, peer, &mut buf);
+        // and what it lets out of the reorder buffer, which `recv` expects to find empty
+        while let Ok(Some(len)) = res {
+            let msg = self.whole_(&buf, len);
+            self.inbox.push_back(msg);
+            res = self.recv_buffered_(&mut buf);
+        }
+        self.oversized = oversized;
+        self.pending   = kept;
+        res.map(|_| ())
+    }
+
+    // Deliver the next message if the reorder buffer holds all of it
+    fn recv_buffered_(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        while let Some(snd) = self.reorder.remove(&self.peer_seqnum) {
+            debug!("= SND {}", self.peer_seqnum);
+            self.peer_seqnum = self.peer_seqnum.wrapping_add(1);
+            if let Some(n) = self.deliver_(&snd, buf)? {
+                return Ok(Some(n));
+            }
+        }
+        Ok(None)
+    }
+
+    // Handle a packet received from our peer on an established connection
+    fn handle_packet_(&mut self, pkt: &[u8], from: InetAddr, buf: &mut [u8])
+      -> Result<Option<usize>> {
+        match self.dispatch_packet_(pkt, from, buf) {
+            // anyone can send such a packet on behalf of the peer, it mustn't end the connection
+            Err(ODPError::ProtocolError) => {
+                debug!("< malformed packet");
+                self.stats.malformed += 1;
+                Ok(None)
+            }
+            res => res,
+        }
+    }
+
+    fn dispatch_packet_(&mut self, pkt: &[u8], from: InetAddr, buf: &mut [u8])
+      -> Result<Option<usize>> {
+        // larger than we allow, or forged or corrupted, or from someone else: as if it never came
+        if pkt.len() > self.max_size || (from != self.peer && self.cid.is_none()) {
+            return Ok(None);
+        }
+        let pkt = match self.authenticate_(pkt) {
+            Some(pkt) => pkt,
+            None      => return Ok(None),
+        };
+        // with a connection id, packets must carry it and may come from a new address of the peer
+        let pkt = match self.cid {
+            Some(cid) => match pkt.len().checked_sub(CID_SIZE) {
+                Some(n) if LittleEndian::read_u64(&pkt[n..]) == cid => &pkt[..n],
+                _                                                  => return Ok(None),
+            },
+            None => pkt,
+        };
+        self.unreachable = 0;
+        let pkttype = decode_header(pkt).ok_or(ODPError::ProtocolError)?.pkttype;
+        // the rest of the packet may not mean what we think
+        if !known_version(pkt) {
+            return Err(ODPError::ProtocolError);
+        }
+        // the handlers read what the type has without checking
+        if pkt.len() < min_packet_size(pkttype) {
+            return Err(ODPError::ProtocolError);
+        }
+
+        self.last_recv = Instant::now();
+        if pkttype != TYPE_SYN {
+            self.half_open = false;
+        }
+        // datagrams only make sense without a connection, and the other way around
+        if self.unreliable {
+            return if pkttype == TYPE_DGM { self.handle_dgm_(pkt, buf) } else { Ok(None) };
+        }
+        // answered where it came from, but the peer only moves there once data or an ack from
+        // the new address was handled without error
+        let old = mem::replace(&mut self.peer, from);
+        let res = match pkttype {
+            TYPE_ACK => { self.handle_ack_(pkt) }
+            TYPE_AGN => { self.handle_agn_(pkt) }
+            TYPE_NAK => { self.handle_nak_(pkt) }
+            TYPE_SND => { self.handle_snd_(pkt, buf) }
+            TYPE_FIN => { self.handle_fin_(pkt) }
+            TYPE_KAL => { self.handle_kal_() }
+            TYPE_PRB => { self.handle_prb_(pkt) }
+            TYPE_PRR => { self.handle_prr_(pkt) }
+            TYPE_SYN => { self.handle_dup_syn_(pkt) }
+            TYPE_SYA => { Ok(None) } // our SYN was sent again and answered twice
+            _        => { Err(ODPError::ProtocolError) }
+        };
+        if from != old {
+            if res.is_ok() && (pkttype == TYPE_SND || pkttype == TYPE_ACK) {
+                debug!("peer moved to {}", from.to_std());
+                self.stats.migrations += 1;
+            } else {
+                self.peer = old;
+            }
+        }
+        res
+    }
+
+    fn handle_dgm_(&mut self, dgm: &[u8], buf: &mut [u8]) -> Result<Option<usize>> {
+        debug!("< DGM {}", LittleEndian::read_u64(&dgm[2..]));
+
+        if snd_data(dgm).is_none() || dgm[1] & FLAG_MORE != 0 {
+            return Err(ODPError::ProtocolError);
+        }
+        self.last_data = Instant::now();
+        self.deliver_(dgm, buf)
+    }
+
+    fn handle_kal_(&mut self) -> Result<Option<usize>> {
+        debug!("< KAL");
+
+        // answer with the last seqnum we acknowledged, so that our peer knows we are alive
+        self.send_ack_flagged_(self.received_().wrapping_sub(1), FLAG_KAL)?;
+        Ok(None)
+    }
+
+    fn handle_prb_(&mut self, prb: &[u8]) -> Result<Option<usize>> {
+        let number = LittleEndian::read_u64(&prb[2..]);
+
+        debug!("< PRB {}", number);
+
+        self.send_probe_(TYPE_PRR, number)?;
+        Ok(None)
+    }
+
+    fn handle_prr_(&mut self, prr: &[u8]) -> Result<Option<usize>> {
+        let number = LittleEndian::read_u64(&prr[2..]);
+
+        debug!("< PRR {}", number);
+
+        // answers to probes we gave up on are ignored
+        if let Some((_, sent)) = self.probe.filter(|&(n, _)| n == number) {
+            self.probe_rtt = Some(sent.elapsed());
+            self.probe     = None;
+        }
+        Ok(None)
+    }
+
+    fn handle_fin_(&mut self, fin: &[u8]) -> Result<Option<usize>> {
+        let seqnum = LittleEndian::read_u64(&fin[2..]);
+
+        debug!("< FIN {}", seqnum);
+
+        let received = self.received_();
+        if seqnum == self.peer_seqnum {
+            // everything was delivered
+            self.send_ack_(seqnum)?;
+            self.close_();
+        }
+        else if seq_gt(seqnum, received) {
+            // the FIN overtook some packets, get them first
+            self.send_agn_(received, seqnum)?;
+        }
+        Ok(None)
+    }
+
+    fn send_fin_(&self, seqnum: Seqnum) -> Result<()> {
+        let mut fin = [0; PKT_HDR_SIZE];
+
+        debug!("> FIN {}", seqnum);
+
+        fin[0] = TYPE_FIN; // type
+        fin[1] = VERSION_BITS; // version
+        LittleEndian::write_u64(&mut fin[2..], seqnum);
+
+        match self.sendto_(&fin, self.peer) {
+            Ok(_)                     => Ok(()),
+            // as if it was lost, it is sent again
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+        }
+    }
+
+    fn handle_dup_syn_(&mut self, syn: &[u8]) -> Result<Option<usize>> {
+        // our SYA was lost, answer again. A different seqnum belongs to another connection.
+        if LittleEndian::read_u64(&syn[2..]) == self.peer_isn {
+            self.send_syn_(TYPE_SYA)?;
+        }
+        Ok(None)
+    }
+
+    fn handle_ack_(&mut self, ack: &[u8]) -> Result<Option<usize>> {
+        let seqnum = LittleEndian::read_u64(&ack[2..]);
+
+        debug!("< ACK {}", seqnum);
+
+        self.stats.acks_received += 1;
+        self.last_ack = Instant::now();
+
+        // our peer received everything, including the end of the connection
+        if self.fin.is_some_and(|fin| !seq_lt(seqnum, fin)) {
+            self.close_();
+            return Ok(None);
+        }
+
+        self.timeouts = 0;
+
+        // remove packets whose seqnum is below the one found in the ack packet, and measure the
+        // RTT on the most recent one
+        let unacked = self.ack_wait.len();
+        let mut acked = None;
+        while self.ack_wait.front().is_some_and(|p| !seq_gt(p.seqnum, seqnum)) {
+            acked = self.ack_wait.pop_front();
+        }
+        if let Some(p) = acked.filter(|p| p.resent == 0) {
+            self.rtt_sample_(p.sent.elapsed());
+        }
+        let mut off = PKT_HDR_SIZE;
+        if ack[1] & FLAG_SACK != 0 && ack.len() >= off + 8 {
+            self.handle_sack_(seqnum.wrapping_add(1), LittleEndian::read_u64(&ack[off..]));
+            off += 8;
+        }
+        let n = unacked - self.ack_wait.len();
+        if n > 0 || ack[1] & FLAG_KAL == 0 {
+            self.on_acked_(seqnum, n);
+        }
+        self.peer_window = match ack.get(off..off+4) {
+            Some(wnd) if ack[1] & FLAG_WND != 0 => {
+                let wnd = LittleEndian::read_u32(wnd) as usize;
+                debug!("< WND {}", wnd);
+                cmp::max(cmp::min(wnd, self.window), 1)
+            }
+            _ => self.window,
+        };
+        self.send_queued_()?;
+        Ok(None)
+    }
+
+    // Grow the congestion window with the `n` packets an ACK for `seqnum` acknowledged, or count
+    // it as a duplicate if there were none
+    fn on_acked_(&mut self, seqnum: Seqnum, n: usize) {
+        if n == 0 {
+            if self.ack_wait.front().is_some_and(|p| p.seqnum == seqnum.wrapping_add(1)) {
+                self.dup_acks += 1;
+                if self.dup_acks == DUP_ACKS {
+                    self.on_loss_();
+                }
+            }
+            return;
+        }
+        self.dup_acks = 0;
+        if self.recovery.is_some_and(|r| !seq_lt(seqnum, r)) {
+            self.recovery = None;
+        }
+        if self.cwnd < self.ssthresh {
+            self.cwnd += n;
+        } else {
+            self.cwnd_acked += n;
+            if self.cwnd_acked >= self.cwnd {
+                self.cwnd_acked -= self.cwnd;
+                self.cwnd       += 1;
+            }
+        }
+        self.cwnd = cmp::min(self.cwnd, self.window);
+    }
+
+    // Halve the congestion window, once for all the packets in flight when the loss was seen
+    fn on_loss_(&mut self) {
+        if self.recovery.is_some() {
+            return;
+        }
+        self.ssthresh   = cmp::max(self.cwnd / 2, 1);
+        self.cwnd       = self.ssthresh;
+        self.cwnd_acked = 0;
+        self.recovery   = Some(self.seqnum.wrapping_sub(1));
+        debug!("loss, cwnd {}", self.cwnd);
+    }
+
+    // Update the RTT estimate and RTO with a new measurement (Jacobson/Karels, RFC 6298)
+    fn rtt_sample_(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let delta   = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto  = cmp::max(srtt + self.rttvar * 4, Duration::from_millis(RTO_MIN));
+        self.rto  = cmp::min(self.rto, Duration::from_millis(RTO_MAX));
+        self.tune_recv_buf_();
+    }
+
+    fn handle_snd_(&mut self, snd: &[u8], buf: &mut [u8]) -> Result<Option<usize>> {
+        let seqnum = LittleEndian::read_u64(&snd[2..]);
+
+        debug!("< SND {}", seqnum);
+
+        if snd_data(snd).is_none() {
+            return Err(ODPError::ProtocolError);
+        }
+        // from now on the packet is handled as if it was sent in the clear and uncompressed
+        let opened;
+        let snd = match self.open_snd_(snd) {
+            Ok(Some(pkt)) => { opened = pkt; &opened[..] }
+            Ok(None)      => snd,
+            Err(())       => {
+                debug!("< SND {} does not decrypt", seqnum);
+                self.stats.auth_failures += 1;
+                return Ok(None);
+            }
+        };
+        self.last_data = Instant::now();
+        let unpacked;
+        let snd = if snd[1] & FLAG_LZ4 != 0 {
+            unpacked = unpack_snd(snd, self.max_size).ok_or(ODPError::ProtocolError)?;
+            &unpacked[..]
+        } else {
+            snd
+        };
+
+        if seq_lt(seqnum, self.peer_seqnum) {
+            // we already sent an ack for this packet, maybe our peer didn't get it?
+            // craft another ack packet with the last seqnum we acknowledged.
+            self.flush_ack_()?;
+            Ok(None)
+        }
+        else if seqnum == self.peer_seqnum {
+            self.peer_seqnum = self.peer_seqnum.wrapping_add(1);
+            self.ack_in_order_()?;
+            self.deliver_(snd, buf)
+        }
+        else {
+            // we missed some packets, keep this one until they arrive and request resending the
+            // ones up to the next packet we already hold, or the ones just found missing with NAK
+            let next = self.next_seen_();
+            if self.reorder.len() >= REORDER_MAX && !self.reorder.contains_key(&seqnum) {
+                self.stats.out_of_order_drops += 1;
+            } else if self.reorder.insert(seqnum, snd.to_vec()).is_none() {
+                self.stats.out_of_order += 1;
+            }
+            let missing = seqnum.wrapping_sub(next);
+            if self.use_nak && !seq_lt(seqnum, next) && missing <= NAK_MAX {
+                for i in 0..missing {
+                    self.send_nak_(next.wrapping_add(i))?;
+                }
+                return Ok(None);
+            }
+            let from = self.received_();
+            let to   = self.reorder.keys()
+                .cloned()
+                .filter(|&s| seq_gt(s, from))
+                .min_by_key(|&s| s.wrapping_sub(from))
+                .unwrap_or(seqnum);
+            self.send_agn_(from, to)?;
+            Ok(None)
+        }
+    }
+
+    // Acknowledge the packet just received in order, now or later with delayed ACKs. Filling a
+    // gap is acknowledged right away, the peer is probably waiting for it.
+    fn ack_in_order_(&mut self) -> Result<()> {
+        match self.ack_delay {
+            Some(delay) if self.ack_held + 1 < ACK_EVERY && self.reorder.is_empty() => {
+                self.ack_held += 1;
+                self.ack_due.get_or_insert(Instant::now() + delay);
+                Ok(())
+            }
+            _ => self.flush_ack_(),
+        }
+    }
+
+    // Acknowledge everything received so far, including the packets held back
+    fn flush_ack_(&mut self) -> Result<()> {
+        self.ack_due  = None;
+        self.ack_held = 0;
+        self.send_ack_(self.received_().wrapping_sub(1))
+    }
+
+    // Hand the data of the next SND packet to the user, once the message it belongs to is whole.
+    // Unless we truncate, what does not fit in `buf` is kept for later, see `Oversized`.
+    fn deliver_(&mut self, snd: &[u8], buf: &mut [u8]) -> Result<Option<usize>> {
+        let mut data = snd_data(snd).unwrap_or(&[]);
+        let more     = snd[1] & FLAG_MORE != 0;
+
+        if self.discard {
+            self.discard = more;
+            return Ok(None);
+        }
+        if more && self.frags.is_empty() && self.msg_len.is_none() && version(snd) >= 2 {
+            if data.len() < MSG_LEN_SIZE {
+                return self.reject_msg_(more);
+            }
+            self.msg_len = Some(LittleEndian::read_u32(data) as usize);
+            data = &data[MSG_LEN_SIZE..];
+        }
+        // older peers don't tell the size, only our own limit applies then
+        let len = self.frags.len() + data.len();
+        let max = self.msg_len.map_or(self.max_msg, |n| cmp::min(n, self.max_msg));
+        if len > max || self.msg_len.is_some_and(|n| n > self.max_msg || (!more && n != len)) {
+            debug!("message of {} bytes out of bounds", self.msg_len.unwrap_or(len));
+            return self.reject_msg_(more);
+        }
+        self.stats.bytes_received += data.len() as u64;
+
+        if more {
+            self.frags.extend_from_slice(data);
+            return Ok(None);
+        }
+        self.msg_len = None;
+        let truncate = self.oversized == Oversized::Truncate;
+        if self.frags.is_empty() && !self.use_crc && (truncate || data.len() <= buf.len()) {
+            return Ok(Some(copy_buf(buf, data)));
+        }
+        self.frags.extend_from_slice(data);
+        let mut msg = mem::take(&mut self.frags);
+        if self.use_crc && !strip_crc(&mut msg) {
+            debug!("message of {} bytes does not match its CRC", msg.len());
+            self.stats.integrity_errors += 1;
+            return Err(ODPError::IntegrityError);
+        }
+        if self.use_crc {
+            self.stats.bytes_received -= CRC_SIZE as u64;
+        }
+        if truncate || msg.len() <= buf.len() {
+            return Ok(Some(copy_buf(buf, &msg)));
+        }
+        if self.oversized == Oversized::Fail {
+            let n = msg.len();
+            self.pending = Some(msg);
+            return Ok(Some(n));
+        }
+        let n = copy_buf(buf, &msg);
+        msg.drain(..n);
+        self.pending = Some(msg);
+        Ok(Some(n))
+    }
+
+    // Drop the message being reassembled, and its fragments to come if `more` follow
+    fn reject_msg_(&mut self, more: bool) -> Result<Option<usize>> {
+        self.frags.clear();
+        self.msg_len = None;
+        self.discard = more;
+        Err(ODPError::ProtocolError)
+    }
+
+    // Seqnum of the first packet we did not receive: all packets before it are either delivered
+    // or waiting in the reorder buffer.
+    fn received_(&self) -> Seqnum {
+        let mut seqnum = self.peer_seqnum;
+        while self.reorder.contains_key(&seqnum) {
+            seqnum = seqnum.wrapping_add(1);
+        }
+        seqnum
+    }
+
+    fn handle_agn_(&mut self, agn: &[u8]) -> Result<Option<usize>> {
+        let from = LittleEndian::read_u64(&agn[ 2..]);
+        let to   = LittleEndian::read_u64(&agn[10..]);
+
+        debug!("< AGN {} -> {}", from, to);
+
+        self.stats.agn_received += 1;
+
+        if seq_gt(from, to) {
+            return Err(ODPError::ProtocolError);
+        }
+
+        // use the 'from' as an ack
+        self.timeouts = 0;
+        self.last_ack = Instant::now();
+        while self.ack_wait.front().is_some_and(|p| seq_lt(p.seqnum, from)) {
+            self.ack_wait.pop_front();
+        }
+        if agn[1] & FLAG_SACK != 0 && agn.len() >= AGN_SIZE + 8 {
+            self.handle_sack_(to, LittleEndian::read_u64(&agn[AGN_SIZE..]));
+        }
+        // the packets before 'to' were lost
+        self.on_loss_();
+
+        // resend the missing packets only, the peer already has the ones from 'to'
+        let now = Instant::now();
+        for p in self.ack_wait.iter_mut().filter(|p| seq_lt(p.seqnum, to)) {
+            debug!("> RESND {}", p.seqnum);
+            match send_padded(&*self.com, &p.pkt, self.peer, self.pad_to, self.cid,
+                              self.auth.as_ref()) {
+                // left to the retransmission timer
+                Err(ICError::PaceLimited) => break,
+                res => res?,
+            };
+            p.sent    = now;
+            p.resent += 1;
+            self.stats.retransmits += 1;
+        }
+
+        self.send_queued_()?;
+        Ok(None)
+    }
+
+    // Send again the packet the peer says is missing, if we have it
+    fn handle_nak_(&mut self, nak: &[u8]) -> Result<Option<usize>> {
+        let seqnum = LittleEndian::read_u64(&nak[2..]);
+
+        debug!("< NAK {}", seqnum);
+
+        self.stats.nak_received += 1;
+        self.last_ack = Instant::now();
+
+        let now = Instant::now();
+        if let Some(p) = self.ack_wait.iter_mut().find(|p| p.seqnum == seqnum) {
+            debug!("> RESND {}", p.seqnum);
+            match send_padded(&*self.com, &p.pkt, self.peer, self.pad_to, self.cid,
+                              self.auth.as_ref()) {
+                // left to the retransmission timer
+                Err(ICError::PaceLimited) => return Ok(None),
+                res => res?,
+            };
+            p.sent    = now;
+            p.resent += 1;
+            self.stats.retransmits += 1;
+            self.on_loss_();
+        }
+        Ok(None)
+    }
+
+    // Forget the packets our peer says it received, see FLAG_SACK. They may be anywhere in
+    // `ack_wait`, unlike acked ones.
+    fn handle_sack_(&mut self, base: Seqnum, sack: u64) {
+        debug!("< SACK {} {:b}", base, sack);
+
+        self.ack_wait.retain(|p| {
+            let i = p.seqnum.wrapping_sub(base);
+            i >= SACK_BITS || sack & (1 << i) == 0
+        });
+    }
+
+    // Selective ack of the packets we hold from `base`, see FLAG_SACK
+    fn sack_(&self, base: Seqnum) -> u64 {
+        self.reorder.keys()
+            .map(|s| s.wrapping_sub(base))
+            .filter(|&i| i < SACK_BITS)
+            .fold(0, |sack, i| sack | (1 << i))
+    }
+
+    // Number of packets we can take from the peer: the packets held out of order use up room
+    fn recv_window_(&self) -> usize {
+        cmp::max(cmp::min(self.window, REORDER_MAX.saturating_sub(self.reorder.len())), 1)
+    }
+
+    // Seqnum after the highest one received
+    fn next_seen_(&self) -> Seqnum {
+        let base = self.peer_seqnum;
+        self.reorder.keys()
+            .map(|s| s.wrapping_sub(base))
+            .filter(|&i| i < SEQ_HALF)
+            .max()
+            .map_or(base, |i| base.wrapping_add(i + 1))
+    }
+
+    fn send_nak_(&mut self, seqnum: Seqnum) -> Result<()> {
+        let mut nak = [0; PKT_HDR_SIZE];
+
+        debug!("> NAK {}", seqnum);
+
+        self.stats.nak_sent += 1;
+
+        nak[0] = TYPE_NAK; // type
+        nak[1] = VERSION_BITS; // version
+        LittleEndian::write_u64(&mut nak[2..], seqnum);
+
+        match self.sendto_(&nak, self.peer) {
+            Ok(_)                     => Ok(()),
+            // as if it was lost, left to the retransmission timer of the peer
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+        }
+    }
+
+    // Request the packets from `from` up to `to` excluded
+    fn send_agn_(&mut self, from: Seqnum, to: Seqnum) -> Result<()> {
+        let mut ack = [0; AGN_SIZE + 8];
+
+        debug!("> AGN {} -> {}", from, to);
+
+        self.stats.agn_sent += 1;
+
+        ack[0] = TYPE_AGN;  // type
+        ack[1] = VERSION_BITS | FLAG_SACK; // version, flags
+        LittleEndian::write_u64(&mut ack[ 2..], from);
+        LittleEndian::write_u64(&mut ack[10..], to);
+        LittleEndian::write_u64(&mut ack[AGN_SIZE..], self.sack_(to));
+
+        match self.sendto_(&ack, self.peer) {
+            // as if it was lost, the next out of order packet asks again
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+            Ok(_)                     => Ok(()),
+        }
+    }
+
+    fn send_ack_(&self, seqnum: Seqnum) -> Result<()> {
+        self.send_ack_flagged_(seqnum, 0)
+    }
+
+    fn send_ack_flagged_(&self, seqnum: Seqnum, flags: u8) -> Result<()> {
+        let mut ack = [0; PKT_HDR_SIZE+12];
+        let mut len = PKT_HDR_SIZE;
+
+        debug!("> ACK {}", seqnum);
+
+        ack[0] = TYPE_ACK; // type
+        ack[1] = VERSION_BITS | flags; // version
+        LittleEndian::write_u64(&mut ack[2..], seqnum);
+
+        // tell which packets we hold beyond the acked one, if any
+        if !self.reorder.is_empty() {
+            ack[1] |= FLAG_SACK;
+            LittleEndian::write_u64(&mut ack[PKT_HDR_SIZE..], self.sack_(seqnum.wrapping_add(1)));
+            len += 8;
+        }
+        // and how many more we can take, if it's less than usual
+        let wnd = self.recv_window_();
+        if wnd < self.window {
+            debug!("> WND {}", wnd);
+            ack[1] |= FLAG_WND;
+            LittleEndian::write_u32(&mut ack[len..], wnd as u32);
+            len += 4;
+        }
+
+        match self.sendto_(&ack[..len], self.peer) {
+            Ok(_)                     => Ok(()),
+            // as if it was lost, the peer sends the packet again
+            Err(ICError::PaceLimited) => Ok(()),
+            Err(e)                    => Err(e.into()),
+        }
+    }
+
+    // How long to wait for packets before calling `on_timeout`: the RTO, or less so as to send
+    // what the rate limit or delayed ACKs held back in time
+    fn wait_time_(&self) -> Duration {
+        let wait = if self.sendq.is_empty() {
+            self.rto
+        } else {
+            cmp::min(self.rto, self.com.pace_delay())
+        };
+        match self.ack_due {
+            Some(due) => cmp::min(wait, due.saturating_duration_since(Instant::now())),
+            None      => wait,
+        }
+    }
+
+    // Process at most one packet, waiting for it up to the RTO when `block` is set, keep the
+    // data it delivers for `read` and retransmit what needs to be.
+    fn pump_(&mut self, block: bool) -> Result<()> {
+        let ready = !block
+            || self.pending.is_some()
+            || !self.inbox.is_empty()
+            || self.reorder.contains_key(&self.peer_seqnum)
+            || self.wait_readable_(Some(self.wait_time_()))?;
+        if ready {
+            if let Some(msg) = self.recv_whole_()? {
+                self.rbuf.extend_from_slice(&msg);
+            }
+        }
+        self.on_timeout(Instant::now())
+    }
+}
+
+
+/// Stream interface over ODP. Both calls block unless the communicator is in non blocking mode,
+/// in which case they fail with `WouldBlock` instead. `read` returns 0 once the peer shut the
+/// connection down.
+///
+/// In non blocking mode, an event loop other than mio can drive the connection by waiting for
+/// the socket given by `as_raw_fd` to be readable, then retrying the call, as `TokioOdp` does.
+/// It should also call `on_timeout` at least every `rto()`.
+impl<T: Transport> Read for ODP<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block = !self.com.is_nonblocking().map_err(ODPError::ICError)?;
+
+        while self.rbuf.is_empty() {
+            if self.closed && !self.lost {
+                return Ok(0);
+            }
+            self.pump_(block)?;
+        }
+
+        let n = copy_buf(buf, &self.rbuf);
+        self.rbuf.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<T: Transport> Write for ODP<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let block = !self.com.is_nonblocking().map_err(ODPError::ICError)?;
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // data received while waiting for room in the window is kept for `read`
+            match self.send(buf) {
+                Err(ODPError::RemoteWindowFull) => self.pump_(block)?,
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    // data is handed to the peer as soon as it is written
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl From<ODPError> for io::Error {
+    fn from(e: ODPError) -> io::Error {
+        match e {
+            ODPError::ICError(ICError::Nix(e)) => e.into(),
+            ODPError::ICError(ICError::Io(e))  => e,
+            ODPError::NotConnected   => io::ErrorKind::NotConnected.into(),
+            ODPError::ConnectionLost => io::ErrorKind::ConnectionAborted.into(),
+            ODPError::SessionExpired => io::ErrorKind::TimedOut.into(),
+            ODPError::IntegrityError => io::ErrorKind::InvalidData.into(),
+            e                        => io::Error::other(e),
+        }
+    }
+}
+
+
+/// Several connections sharing one communicator, e.g. a server talking to many clients.
+/// Connections are told apart by the address of the peer.
+pub struct OdpMux<T: Transport = IcmpCommunicator> {
+    com:       Arc<T>,
+    peers:     HashMap<InetAddr, ODP<T>>,
+    new_odp:   Box<dyn FnMut(InetAddr) -> Option<ODP<T>> + Send>,
+    max_peers: usize,
+    handshake: Duration, // see `set_handshake_timeout`
+}
+
+impl<T: Transport> OdpMux<T> {
+
+    /// Create a multiplexer over `com`. When a new peer connects, `new_odp` is called with its
+    /// address and returns the ODP the connection goes through (created with `com` and that
+    /// address), or `None` to ignore the peer.
+    pub fn new<F>(com: Arc<T>, new_odp: F) -> OdpMux<T>
+      where F: FnMut(InetAddr) -> Option<ODP<T>> + Send + 'static {
+        OdpMux {
+            com,
+            peers:     HashMap::new(),
+            new_odp:   Box::new(new_odp),
+            max_peers: MUX_MAX_PEERS,
+            handshake: Duration::from_millis(HANDSHAKE_TIMEOUT),
+        }
+    }
+
+    /// Keep at most `max` connections, 1024 by default. Once there are that many, a connection
+    /// request from a new peer takes the place of the oldest connection still half open (see
+    /// `set_handshake_timeout`), or is ignored if there is none.
+    pub fn set_max_peers(&mut self, max: usize) {
+        self.max_peers = max;
+    }
+
+    /// Forget in `on_timeout` the connections whose peer sent nothing but its connection request
+    /// for `timeout`, 10 seconds by default. Requests sent on behalf of others, who never see our
+    /// answer, e.g. to flood us, don't hold on to memory for longer.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake = timeout;
+    }
+
+    // Make room for a new connection by forgetting the oldest one still half open, if any
+    fn evict_half_open_(&mut self) {
+        let oldest = self.peers.iter()
+            .filter(|(_, odp)| odp.half_open)
+            .min_by_key(|(_, odp)| odp.started)
+            .map(|(&peer, _)| peer);
+        if let Some(peer) = oldest {
+            debug!("too many peers, forgetting {}", peer.to_std());
+            self.peers.remove(&peer);
+        }
+    }
+
+    pub fn rawfd(&self) -> &RawFd {
+        self.com.rawfd()
+    }
+
+    /// Connection to `peer`, if it connected
+    pub fn get(&self, peer: &InetAddr) -> Option<&ODP<T>> {
+        self.peers.get(peer)
+    }
+
+    pub fn get_mut(&mut self, peer: &InetAddr) -> Option<&mut ODP<T>> {
+        self.peers.get_mut(peer)
+    }
+
+    /// Addresses of the peers that connected
+    pub fn peers(&self) -> Vec<InetAddr> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// Forget the connection to `peer`, e.g. once it is closed
+    pub fn remove(&mut self, peer: &InetAddr) -> Option<ODP<T>> {
+        self.peers.remove(peer)
+    }
+
+    pub fn send(&mut self, peer: &InetAddr, buf: &[u8]) -> Result<usize> {
+        match self.peers.get_mut(peer) {
+            Some(odp) => odp.send(buf),
+            None      => Err(ODPError::NotConnected),
+        }
+    }
+
+    /// Like `ODP::recv`, but also return who sent the data. Connection requests from new peers
+    /// are handled here as well.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        // the ODPs check the size against their own limit
+        let mut sysbuf = vec![0; self.com.max_payload()];
+
+        for (&peer, odp) in self.peers.iter_mut().filter(|(_, odp)| odp.connected) {
+            if let Some(n) = odp.recv_pending_(buf)? {
+                return Ok(Some((n, peer)));
+            }
+            if let Some(n) = odp.recv_buffered_(buf)? {
+                return Ok(Some((odp.fitted_(n)?, peer)));
+            }
+        }
+
+        let (s, peer) = match self.com.recvfrom(&mut sysbuf).map_err(ODPError::ICError)? {
+            None                             => return Ok(None),
+            Some((s, _)) if s > sysbuf.len() => return Ok(None),
+            Some(p)                          => p,
+        };
+        let pkt = &sysbuf[..s];
+
+        match self.peers.get_mut(&peer) {
+            Some(ref mut odp) if odp.connected => {
+                match odp.handle_packet_(pkt, peer, buf)? {
+                    Some(n) => Ok(Some((odp.fitted_(n)?, peer))),
+                    None    => Ok(None),
+                }
+            }
+            Some(_) => Ok(None),
+            None    => {
+                // a peer that moved, see `ODP::set_connection_id`
+                let moved = self.peers.iter().find(|(_, odp)| odp.claims_(pkt)).map(|(&p, _)| p);
+                if let Some(old) = moved {
+                    let mut odp = self.peers.remove(&old).unwrap();
+                    let res     = odp.handle_packet_(pkt, peer, buf);
+                    let res     = res.and_then(|n| n.map(|n| odp.fitted_(n)).transpose());
+                    let addr    = odp.peer;
+                    self.peers.insert(addr, odp);
+                    return res.map(|n| n.map(|n| (n, addr)));
+                }
+
+                // first contact, only a connection request makes sense, if there is room for it
+                if s < SYN_SIZE || pkt[0] != TYPE_SYN {
+                    return Ok(None);
+                }
+                let full = self.peers.len() >= self.max_peers;
+                if full && !self.peers.values().any(|odp| odp.half_open) {
+                    return Ok(None);
+                }
+                if let Some(mut odp) = (self.new_odp)(peer) {
+                    match odp.authenticate_(pkt) {
+                        Some(syn) if syn.len() >= SYN_SIZE && known_version(syn) => {
+                            if full {
+                                self.evict_half_open_();
+                            }
+                            odp.accept_syn_(syn)?;
+                            self.peers.insert(peer, odp);
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Call `ODP::on_timeout` on every connection. Return the peers found unreachable, whose
+    /// session expired or that stayed half open for too long, they are forgotten.
+    pub fn on_timeout(&mut self, now: Instant) -> Result<Vec<InetAddr>> {
+        let mut lost = Vec::new();
+        for (&peer, odp) in &mut self.peers {
+            if odp.half_open && now.saturating_duration_since(odp.started) >= self.handshake {
+                lost.push(peer);
+                continue;
+            }
+            match odp.on_timeout(now) {
+                Err(ODPError::ConnectionLost) |
+                Err(ODPError::SessionExpired) => lost.push(peer),
+                res                           => res?,
+            }
+        }
+        for peer in &lost {
+            self.peers.remove(peer);
+        }
+        Ok(lost)
+    }
+}
+
+
+impl<T: Transport> Drop for ODP<T> {
+    fn drop(&mut self) {
+        if self.fin_on_drop && self.connected && !self.closed {
+            // best effort
+            let _ = self.send_fin_(self.seqnum);
+        }
+    }
+}
+
+impl<T: Transport> AsRawFd for ODP<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        *self.com.rawfd()
+    }
+}
+
+impl<T: Transport> Evented for ODP<T> {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+      -> io::Result<()> {
+        EventedFd(self.com.rawfd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+      -> io::Result<()> {
+        EventedFd(self.com.rawfd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(self.com.rawfd()).deregister(poll)
+    }
+}
+
+/// Stream interface over ODP for tokio, with the `tokio` feature: `AsyncRead` and `AsyncWrite`
+/// as `Read` and `Write` in non blocking mode, waiting for the socket of the communicator to be
+/// readable instead of failing with `WouldBlock`. Packets are processed and `on_timeout` called
+/// in time while a task polls the stream, e.g. reads from it or waits for room in the window.
+/// Establish the connection with `connect` or `accept` before wrapping the ODP.
+#[cfg(feature = "tokio")]
+pub struct TokioOdp<T: Transport = IcmpCommunicator> {
+    fd:       AsyncFd<ODP<T>>,
+    timer:    Pin<Box<Sleep>>, // when to call `on_timeout` next
+    fins:     usize,           // FINs sent by `poll_shutdown`
+    fin_sent: Instant,
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Transport> TokioOdp<T> {
+
+    /// Wrap `odp`, whose communicator must be in non blocking mode. Must be called from a tokio
+    /// runtime with I/O and time enabled.
+    pub fn new(odp: ODP<T>) -> io::Result<TokioOdp<T>> {
+        if !odp.com.is_nonblocking().map_err(ODPError::ICError)? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the communicator must be in non blocking mode"));
+        }
+        let wait = odp.wait_time_();
+        Ok(TokioOdp {
+            fd:       AsyncFd::with_interest(odp, Interest::READABLE)?,
+            timer:    Box::pin(tokio_time::sleep(wait)),
+            fins:     0,
+            fin_sent: Instant::now(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &ODP<T> {
+        self.fd.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut ODP<T> {
+        self.fd.get_mut()
+    }
+
+    /// The ODP back, e.g. to use it in blocking mode again
+    pub fn into_inner(self) -> ODP<T> {
+        self.fd.into_inner()
+    }
+
+    // Call `f` until it no longer fails with `WouldBlock`, waiting in between for packets or for
+    // the next timeout
+    fn poll_io_<R, F>(&mut self, cx: &mut Context, mut f: F) -> task::Poll<io::Result<R>>
+      where F: FnMut(&mut TokioOdp<T>) -> io::Result<R> {
+        loop {
+            match f(self) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return task::Poll::Ready(res),
+            }
+            match self.poll_wait_(cx) {
+                task::Poll::Ready(Ok(()))  => (),
+                task::Poll::Ready(Err(e))  => return task::Poll::Ready(Err(e)),
+                task::Poll::Pending        => return task::Poll::Pending,
+            }
+        }
+    }
+
+    // Ready once packets may be waiting, or after calling `on_timeout` when it was time to
+    fn poll_wait_(&mut self, cx: &mut Context) -> task::Poll<io::Result<()>> {
+        if self.timer.as_mut().poll(cx).is_ready() {
+            let odp = self.fd.get_mut();
+            odp.on_timeout(Instant::now())?;
+            let next = Instant::now() + odp.wait_time_();
+            self.timer.as_mut().reset(tokio_time::Instant::from_std(next));
+            return task::Poll::Ready(Ok(()));
+        }
+        match self.fd.poll_read_ready_mut(cx) {
+            // the caller reads until `WouldBlock` again
+            task::Poll::Ready(Ok(mut guard)) => {
+                guard.clear_ready();
+                task::Poll::Ready(Ok(()))
+            }
+            task::Poll::Ready(Err(e)) => task::Poll::Ready(Err(e)),
+            task::Poll::Pending       => task::Poll::Pending,
+        }
+    }
+
+    // Wait for the acks of what we sent, as `ODP::flush`. What is received meanwhile is kept for
+    // `read`.
+    fn flush_(&mut self) -> io::Result<()> {
+        let odp = self.fd.get_mut();
+        if odp.lost {
+            return Err(ODPError::ConnectionLost.into());
+        }
+        while (!odp.ack_wait.is_empty() || !odp.sendq.is_empty()) && !odp.closed {
+            odp.pump_(false)?;
+        }
+        Ok(())
+    }
+
+    // `ODP::shutdown`, failing with `WouldBlock` whenever it would wait
+    fn shutdown_(&mut self) -> io::Result<()> {
+        let odp = self.fd.get_mut();
+        if odp.lost {
+            return Err(ODPError::ConnectionLost.into());
+        }
+        if odp.closed && self.fins > 0 {
+            return Ok(());
+        }
+        if !odp.connected {
+            return Err(ODPError::NotConnected.into());
+        }
+        if odp.unreliable {
+            odp.close_();
+            return Ok(());
+        }
+
+        while (!odp.ack_wait.is_empty() || !odp.sendq.is_empty()) && !odp.closed {
+            odp.pump_(false)?;
+        }
+        if odp.closed {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if self.fins == 0 || now >= self.fin_sent + odp.rto {
+            if self.fins == FIN_RETRIES {
+                odp.close_();
+                return Err(ODPError::AckError.into());
+            }
+            let fin = odp.seqnum;
+            odp.fin = Some(fin);
+            odp.send_fin_(fin)?;
+            self.fins    += 1;
+            self.fin_sent = now;
+            self.timer.as_mut().reset(tokio_time::Instant::from_std(now + odp.rto));
+        }
+        while !odp.closed {
+            odp.pump_(false)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Transport> AsyncRead for TokioOdp<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf)
+      -> task::Poll<io::Result<()>> {
+        let res = self.get_mut().poll_io_(cx, |this| {
+            this.get_mut().read(buf.initialize_unfilled())
+        });
+        if let task::Poll::Ready(Ok(n)) = res {
+            buf.advance(n);
+        }
+        res.map_ok(|_| ())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Transport> AsyncWrite for TokioOdp<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+      -> task::Poll<io::Result<usize>> {
+        self.get_mut().poll_io_(cx, |this| this.get_mut().write(buf))
+    }
+
+    // unlike `Write::flush`, wait for the peer to acknowledge the data
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<io::Result<()>> {
+        self.get_mut().poll_io_(cx, |this| this.flush_())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<io::Result<()>> {
+        self.get_mut().poll_io_(cx, |this| this.shutdown_())
+    }
+}
+
+
+// Send `pkt` to `peer`, padded with zeros up to `pad_to` bytes, see `ODP::set_pad_to`, and
+// followed by the connection id and the trailer of connections with a key, if any. Return how much
+// of `pkt` was sent.
+fn send_padded<T: Transport>(com: &T, pkt: &[u8], peer: InetAddr, pad_to: usize,
+               cid: Option<u64>, auth: Option<&Auth>) -> result::Result<usize, ICError> {
+    if auth.is_none() && cid.is_none() && pkt.len() >= pad_to {
+        return sent_whole(com.sendto(pkt, peer)?, pkt.len());
+    }
+    let trailer_size = auth.map_or(0, |_| AUTH_SIZE) + cid.map_or(0, |_| CID_SIZE);
+    let mut len = cmp::max(pkt.len(), pad_to.saturating_sub(trailer_size));
+    let mut padded = vec![0; len + CID_SIZE + AUTH_SIZE];
+    padded[..pkt.len()].copy_from_slice(pkt);
+    if let Some(cid) = cid {
+        LittleEndian::write_u64(&mut padded[len..], cid);
+        len += CID_SIZE;
+    }
+    if let Some(auth) = auth {
+        len = auth.seal(&mut padded, len);
+    }
+    sent_whole(com.sendto(&padded[..len], peer)?, len).map(|_| pkt.len())
+}
+
+// Datagrams are sent whole: a transport reporting less is turned into an error
+fn sent_whole(sent: usize, expected: usize) -> result::Result<usize, ICError> {
+    if sent < expected {
+        return Err(ICError::ShortWrite { sent, expected });
+    }
+    Ok(sent)
+}
+
+// Data carried by a SND packet, without the padding if any. `None` if the packet is malformed.
+fn snd_data(snd: &[u8]) -> Option<&[u8]> {
+    if snd[1] & FLAG_PAD == 0 {
+        return Some(&snd[PKT_HDR_SIZE..]);
+    }
+    let start = PKT_HDR_SIZE + PAD_LEN_SIZE;
+    let len   = LittleEndian::read_u16(snd.get(PKT_HDR_SIZE..start)?) as usize;
+    snd.get(start..start+len)
+}
+
+// The SND packet `snd` with its data decompressed and no padding, `None` if it doesn't decompress
+// or we can't decompress at all
+#[cfg(feature = "compression")]
+fn unpack_snd(snd: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let mut pkt = snd[..PKT_HDR_SIZE].to_vec();
+    pkt[1] &= !(FLAG_LZ4 | FLAG_PAD);
+    lz4::decompress(snd_data(snd)?, &mut pkt, max_size)?;
+    Some(pkt)
+}
+
+#[cfg(not(feature = "compression"))]
+fn unpack_snd(_snd: &[u8], _max_size: usize) -> Option<Vec<u8>> {
+    None
+}
+
+// Key of the connection between the initial seqnums `isns`, of its initiator then of the other
+// end, derived from `key` for `purpose`
+fn session_key(key: &Hmac, purpose: &[u8], isns: (Seqnum, Seqnum)) -> [u8; DIGEST_SIZE] {
+    let n = purpose.len();
+    let mut data = purpose.to_vec();
+    data.resize(n + 16, 0);
+    LittleEndian::write_u64(&mut data[n..], isns.0);
+    LittleEndian::write_u64(&mut data[n+8..], isns.1);
+    key.mac(&data)
+}
+
+// Pick a random initial seqnum so that packets from a previous connection are not mistaken for
+// packets of this one. The keys of connections are derived from them: there is no falling back
+// on something guessable.
+fn random_isn() -> Result<Seqnum> {
+    let mut bytes = [0; 8];
+    let read = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    read.map_err(ODPError::NoRandomness)?;
+    Ok(LittleEndian::read_u64(&bytes))
+}
+
+/// The start of every ODP packet, see `decode_header`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PacketHeader {
+    /// What the packet is, a letter: `b'S'` for data, `b'A'` for an ack and so on
+    pub pkttype: u8,
+    /// Version of the protocol, 0 from peers that predate it
+    pub version: u8,
+    /// Flags, which depend on the type
+    pub flags:   u8,
+    /// Seqnum of the data, or the one the packet refers to
+    pub seqnum:  Seqnum,
+}
+
+/// Decode the header of the ODP packet `pkt`, whatever its type. `None` if it is too short to
+/// have one.
+pub fn decode_header(pkt: &[u8]) -> Option<PacketHeader> {
+    if pkt.len() < PKT_HDR_SIZE {
+        return None;
+    }
+    Some(PacketHeader {
+        pkttype: pkt[0],
+        version: pkt[1] >> 4,
+        flags:   pkt[1] & FLAGS_MASK,
+        seqnum:  LittleEndian::read_u64(&pkt[2..]),
+    })
+}
+
+// Size of the smallest valid packet of type `pkttype`. The data of SND and DGM packets is
+// checked by `snd_data`.
+fn min_packet_size(pkttype: u8) -> usize {
+    match pkttype {
+        TYPE_AGN => AGN_SIZE,
+        TYPE_SYN => SYN_SIZE,
+        _        => PKT_HDR_SIZE,
+    }
+}
+
+// Whether `pkt`, at least a header long, is of a version of the protocol that we speak
+fn known_version(pkt: &[u8]) -> bool {
+    version(pkt) <= VERSION
+}
+
+// Version of the protocol the sender of `pkt` speaks, at least a header long
+fn version(pkt: &[u8]) -> u8 {
+    pkt[1] >> 4
+}
+
+// Serial number arithmetic (RFC 1982): `a` comes before `b` if `b` is less than half the seqnum
+// space ahead of it. Seqnums can then wrap around, whatever the width of `Seqnum`.
+fn seq_lt(a: Seqnum, b: Seqnum) -> bool {
+    let d = b.wrapping_sub(a);
+    d != 0 && d < SEQ_HALF
+}
+
+fn seq_gt(a: Seqnum, b: Seqnum) -> bool {
+    seq_lt(b, a)
+}
+
+// `msg` after its size, for messages split in fragments, see FLAG_MORE
+fn with_len(msg: &[u8]) -> Vec<u8> {
+    let mut framed = vec![0; MSG_LEN_SIZE];
+    LittleEndian::write_u32(&mut framed, msg.len() as u32);
+    framed.extend_from_slice(msg);
+    framed
+}
+
+// `msg` followed by its CRC-32, see `ODP::set_message_crc`
+fn with_crc(msg: &[u8]) -> Vec<u8> {
+    let mut checked = Vec::with_capacity(msg.len() + CRC_SIZE);
+    checked.extend_from_slice(msg);
+    checked.extend_from_slice(&[0; CRC_SIZE]);
+    LittleEndian::write_u32(&mut checked[msg.len()..], crc32(msg));
+    checked
+}
+
+// Remove the CRC-32 ending `msg`, return false if there is none or it doesn't match
+fn strip_crc(msg: &mut Vec<u8>) -> bool {
+    if msg.len() < CRC_SIZE {
+        return false;
+    }
+    let n = msg.len() - CRC_SIZE;
+    if LittleEndian::read_u32(&msg[n..]) != crc32(&msg[..n]) {
+        return false;
+    }
+    msg.truncate(n);
+    true
+}
+
+fn copy_buf(dst: &mut[u8], src: &[u8]) -> usize {
+    let copylen = cmp::min(dst.len(), src.len());
+    dst[..copylen].copy_from_slice(&src[..copylen]);
+    copylen
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate nix;
+    use self::nix::sys::time::{TimeVal, TimeValLike};
+
+    fn localhost() -> InetAddr {
+        InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())
+    }
+
+    // Build a raw ODP packet, to be sent by a plain communicator posing as the peer
+    fn forge(typ: u8, seqnum: Seqnum, data: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![typ, VERSION_BITS];
+        pkt.extend_from_slice(&[0; 8]);
+        LittleEndian::write_u64(&mut pkt[2..], seqnum);
+        pkt.extend_from_slice(data);
+        pkt
+    }
+
+    // Append the trailer of connections with `key` to a forged packet, see `Auth`
+    fn sealed(mut pkt: Vec<u8>, key: &[u8], ctr: u64) -> Vec<u8> {
+        pkt.extend_from_slice(&ctr.to_le_bytes());
+        let tag = Hmac::new(key).mac(&pkt);
+        pkt.extend_from_slice(&tag[..TAG_SIZE]);
+        pkt
+    }
+
+    // Key of the packets of the connection between `isns` with `key`, past the handshake
+    fn session(key: &[u8], isns: (Seqnum, Seqnum)) -> [u8; DIGEST_SIZE] {
+        session_key(&Hmac::new(key), b"odp session key", isns)
+    }
+
+    // Let a plain communicator posing as the peer connect to `odp`, its seqnums start at 0
+    fn accept_forged(odp: ODP, peer_id: u8, magic: u8) -> (ODP, IcmpCommunicator) {
+        accept_forged_at(odp, peer_id, magic, 0)
+    }
+
+    fn accept_forged_at(mut odp: ODP, peer_id: u8, magic: u8, isn: Seqnum)
+      -> (ODP, IcmpCommunicator) {
+        let peer = IcmpCommunicator::with_magic(peer_id, magic).unwrap();
+        peer.sendto(&forge(TYPE_SYN, isn, &[0; 4]), localhost()).unwrap();
+        odp.accept().unwrap();
+        (odp, peer)
+    }
+
+    // Blocking receive on `odp`, bounded by a timeout
+    fn recv_some(odp: &mut ODP, buf: &mut [u8]) -> usize {
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*odp.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        loop {
+            if let Some(n) = odp.recv(buf).expect("nothing received") {
+                return n;
+            }
+        }
+    }
+
+    // Wait until `com` receives `expected`, panics on timeout
+    fn recv_packet(com: &IcmpCommunicator, expected: &[u8]) {
+        let mut buf = [0; PKT_MAX_SIZE];
+        loop {
+            if let Some((n, _)) = com.recvfrom(&mut buf).expect("packet not received") {
+                if buf.get(..n) == Some(expected) {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Process one packet received by `odp` that does not deliver data, panics on timeout
+    fn recv_none(odp: &mut ODP) {
+        let mut buf = [0; 64];
+        let tv = TimeVal::milliseconds(2000);
+        setsockopt(*odp.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        assert_eq!(odp.recv(&mut buf).expect("nothing received"), None);
+    }
+
+    #[test]
+    fn reorder() {
+        let com = Arc::new(IcmpCommunicator::with_magic(102, 0x80).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 103, 0x80);
+
+        peer.sendto(&forge(TYPE_SND, 2, b"two"),  localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, 1, b"one"),  localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, 0, b"zero"), localhost()).unwrap();
+
+        let mut buf = [0; 64];
+        for expected in [&b"zero"[..], b"one", b"two"].iter() {
+            let n = recv_some(&mut odp, &mut buf);
+            assert_eq!(&buf[..n], *expected);
+        }
+        assert!(odp.reorder.is_empty());
+
+        let stats = odp.stats();
+        assert_eq!((stats.out_of_order, stats.agn_sent), (2, 2));
+        assert_eq!((stats.peer_seqnum, stats.bytes_received), (3, 10));
+    }
+
+    #[test]
+    fn on_timeout() {
+        let com = Arc::new(IcmpCommunicator::with_magic(104, 0x81).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 105, 0x81);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+
+        odp.set_rto(Duration::from_millis(50));
+        let expected = forge(TYPE_SND, odp.seqnum, b"lost");
+        odp.send(b"lost").unwrap();
+        // the original transmission
+        recv_packet(&peer, &expected);
+
+        // not yet due
+        let sent = odp.ack_wait[0].sent;
+        odp.on_timeout(sent).unwrap();
+        assert_eq!(odp.ack_wait[0].sent, sent);
+
+        let later = sent + Duration::from_millis(50);
+        odp.on_timeout(later).unwrap();
+        assert_eq!(odp.ack_wait[0].sent, later);
+        recv_packet(&peer, &expected);
+    }
+
+    #[test]
+    fn rtt_estimate() {
+        let com = Arc::new(IcmpCommunicator::with_magic(106, 0x82).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 107, 0x82);
+        let isn = odp.seqnum;
+        assert_eq!(odp.rtt_estimate(), None);
+
+        // retransmitted packets are not measured
+        odp.send(b"resent").unwrap();
+        let sent = odp.ack_wait[0].sent;
+        odp.on_timeout(sent + odp.rto()).unwrap();
+        assert_eq!(odp.rto(), Duration::from_millis(2 * RTO));
+        peer.sendto(&forge(TYPE_ACK, isn, b""), localhost()).unwrap();
+        while !odp.ack_wait.is_empty() {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.rtt_estimate(), None);
+
+        odp.send(b"measured").unwrap();
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), b""), localhost()).unwrap();
+        while !odp.ack_wait.is_empty() {
+            recv_none(&mut odp);
+        }
+        assert!(odp.rtt_estimate().is_some());
+        assert!(odp.rto() >= Duration::from_millis(RTO_MIN));
+    }
+
+    #[test]
+    fn rtt_sample() {
+        let com = Arc::new(IcmpCommunicator::new(108).unwrap());
+        let mut odp = ODP::new(com, localhost());
+
+        odp.rtt_sample_(Duration::from_millis(100));
+        assert_eq!(odp.rtt_estimate(), Some(Duration::from_millis(100)));
+        assert_eq!(odp.rto(), Duration::from_millis(300));
+
+        // srtt = 7/8 * 100 + 1/8 * 500, rttvar = 3/4 * 50 + 1/4 * 400 = 137.5
+        odp.rtt_sample_(Duration::from_millis(500));
+        assert_eq!(odp.rtt_estimate(), Some(Duration::from_millis(150)));
+        assert_eq!(odp.rto(), Duration::from_millis(150 + 550));
+
+        // never below the minimum
+        for _ in 0..100 {
+            odp.rtt_sample_(Duration::from_millis(1));
+        }
+        assert_eq!(odp.rto(), Duration::from_millis(RTO_MIN));
+    }
+
+    #[test]
+    fn agn_range() {
+        let com = Arc::new(IcmpCommunicator::with_magic(109, 0x83).unwrap());
+        let odp = ODP::with_window(com, localhost(), 8).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 110, 0x83);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+
+        let isn = odp.seqnum;
+        let pkts: Vec<_> = (0..4)
+            .map(|i| forge(TYPE_SND, isn.wrapping_add(i), &[i as u8]))
+            .collect();
+        for (i, pkt) in pkts.iter().enumerate() {
+            odp.send(&[i as u8]).unwrap();
+            recv_packet(&peer, pkt);
+        }
+
+        // only the second packet was lost
+        assert_eq!(odp.stats().in_flight, 4);
+        let mut agn = forge(TYPE_AGN, isn.wrapping_add(1), &[0; 8]);
+        LittleEndian::write_u64(&mut agn[10..], isn.wrapping_add(2));
+        peer.sendto(&agn, localhost()).unwrap();
+        while odp.ack_wait.len() == 4 {
+            recv_none(&mut odp);
+        }
+        recv_packet(&peer, &pkts[1]);
+        let stats = odp.stats();
+        assert_eq!((stats.packets_sent, stats.retransmits, stats.agn_received), (4, 1, 1));
+        assert_eq!((stats.in_flight, stats.seqnum), (3, isn.wrapping_add(4)));
+        assert_eq!(stats.bytes_sent, 4);
+
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(200)).unwrap();
+        let mut buf = [0; 64];
+        loop {
+            match peer.recvfrom(&mut buf) {
+                Ok(Some((n, _))) => panic!("unexpected packet {:?}", &buf[..n]),
+                Ok(None)         => {}
+                Err(_)           => break,
+            }
+        }
+    }
+
+    #[test]
+    fn window() {
+        let com = Arc::new(IcmpCommunicator::with_magic(101, 0x84).unwrap());
+        assert!(ODP::with_window(com.clone(), localhost(), 0).is_err());
+
+        let odp = ODP::with_window(com, localhost(), 8).unwrap();
+        let (mut odp, _peer) = accept_forged(odp, 111, 0x84);
+        for _ in 0..8 {
+            odp.send(b"in flight").unwrap();
+        }
+        match odp.send(b"one too many") {
+            Err(ODPError::RemoteWindowFull) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn peer_window() {
+        let com = Arc::new(IcmpCommunicator::with_magic(173, 0xa2).unwrap());
+        let odp = ODP::with_window(com, localhost(), 4).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 174, 0xa2);
+        let isn = odp.seqnum();
+        assert_eq!((odp.peer_window(), odp.window()), (4, 4));
+
+        // the peer has room for a single packet
+        odp.send(b"first").unwrap();
+        let mut ack = forge(TYPE_ACK, isn, &[0; 4]);
+        ack[1] |= FLAG_WND;
+        LittleEndian::write_u32(&mut ack[PKT_HDR_SIZE..], 1);
+        peer.sendto(&ack, localhost()).unwrap();
+        while odp.inflight() > 0 {
+            recv_none(&mut odp);
+        }
+        assert_eq!((odp.peer_window(), odp.window()), (1, 1));
+        odp.send(b"second").unwrap();
+        match odp.send(b"third") {
+            Err(ODPError::RemoteWindowFull) => {}
+            res => panic!("{:?}", res),
+        }
+
+        // an ack without advertisement gives back the whole window
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), &[]), localhost()).unwrap();
+        while odp.inflight() > 0 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.window(), 4);
+
+        // while we hold a packet out of order, we advertise the room left
+        let odp = ODP::with_window(odp.com.clone(), localhost(), 2000).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 175, 0xa2);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        peer.sendto(&forge(TYPE_SND, 1, b"later"), localhost()).unwrap();
+        while odp.reorder.is_empty() {
+            recv_none(&mut odp);
+        }
+        peer.sendto(&forge(TYPE_SND, 0, b"first"), localhost()).unwrap();
+        let mut ack = forge(TYPE_ACK, 1, &[0; 12]);
+        ack[1] |= FLAG_SACK | FLAG_WND;
+        LittleEndian::write_u32(&mut ack[PKT_HDR_SIZE+8..], REORDER_MAX as u32 - 1);
+        recv_some(&mut odp, &mut [0; 64]);
+        recv_packet(&peer, &ack);
+    }
+
+    #[test]
+    fn cwnd() {
+        let com = Arc::new(IcmpCommunicator::with_magic(176, 0xa3).unwrap());
+        let odp = ODP::with_window(com, localhost(), 16).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 177, 0xa3);
+        let isn = odp.seqnum();
+        assert_eq!(odp.cwnd(), INIT_CWND);
+
+        // the window takes them all, the congestion window holds some back
+        for _ in 0..12 {
+            odp.send(b"data").unwrap();
+        }
+        assert_eq!(odp.inflight(), 10);
+
+        // slow start
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), &[]), localhost()).unwrap();
+        while odp.cwnd() < 12 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.inflight(), 10);
+
+        // halved once on duplicate acks
+        for _ in 0..DUP_ACKS+1 {
+            peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), &[]), localhost()).unwrap();
+        }
+        while odp.dup_acks <= DUP_ACKS {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.cwnd(), 6);
+
+        // congestion avoidance
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(11), &[]), localhost()).unwrap();
+        while odp.inflight() > 0 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.cwnd(), 7);
+
+        // halved on timeout
+        odp.send(b"lost").unwrap();
+        odp.on_timeout(Instant::now() + Duration::from_millis(RTO)).unwrap();
+        assert_eq!(odp.cwnd(), 3);
+    }
+
+    #[test]
+    fn nak() {
+        let com = Arc::new(IcmpCommunicator::with_magic(178, 0xa4).unwrap());
+
+        // unless both peers want them
+        let mut odp = ODP::new(com.clone(), localhost());
+        odp.set_nak(true);
+        let (odp, _peer) = accept_forged(odp, 179, 0xa4);
+        assert!(!odp.uses_nak());
+
+        let mut odp = ODP::new(com, localhost());
+        odp.set_nak(true);
+        let peer = IcmpCommunicator::with_magic(180, 0xa4).unwrap();
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let mut syn = forge(TYPE_SYN, 0, &[0; 4]);
+        syn[1] |= FLAG_NAK;
+        peer.sendto(&syn, localhost()).unwrap();
+        odp.accept().unwrap();
+        assert!(odp.uses_nak());
+
+        // each missing packet is requested once
+        peer.sendto(&forge(TYPE_SND, 2, b"two"), localhost()).unwrap();
+        while odp.reorder.is_empty() {
+            recv_none(&mut odp);
+        }
+        recv_packet(&peer, &forge(TYPE_NAK, 0, b""));
+        recv_packet(&peer, &forge(TYPE_NAK, 1, b""));
+        peer.sendto(&forge(TYPE_SND, 3, b"three"), localhost()).unwrap();
+        while odp.reorder.len() < 2 {
+            recv_none(&mut odp);
+        }
+        let stats = odp.stats();
+        assert_eq!((stats.nak_sent, stats.agn_sent), (2, 0));
+
+        // the packet asked for is sent again right away
+        let isn = odp.seqnum();
+        odp.send(b"first").unwrap();
+        odp.send(b"second").unwrap();
+        peer.sendto(&forge(TYPE_NAK, isn.wrapping_add(1), b""), localhost()).unwrap();
+        while odp.stats().nak_received == 0 {
+            recv_none(&mut odp);
+        }
+        recv_packet(&peer, &forge(TYPE_SND, isn.wrapping_add(1), b"second"));
+        assert_eq!(odp.stats().retransmits, 1);
+    }
+
+    #[test]
+    fn delayed_ack() {
+        let com = Arc::new(IcmpCommunicator::with_magic(183, 0xa6).unwrap());
+        let mut odp = ODP::new(com.clone(), localhost());
+        odp.set_delayed_ack(Some(Duration::from_millis(100)));
+        let (mut odp, peer) = accept_forged(odp, 184, 0xa6);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let mut buf = [0; 64];
+        let sent = com.stats().packets_sent; // the SYA
+
+        // a single ACK for two packets
+        peer.sendto(&forge(TYPE_SND, 0, b"zero"), localhost()).unwrap();
+        recv_some(&mut odp, &mut buf);
+        assert_eq!((odp.ack_held, com.stats().packets_sent), (1, sent));
+        peer.sendto(&forge(TYPE_SND, 1, b"one"), localhost()).unwrap();
+        recv_some(&mut odp, &mut buf);
+        recv_packet(&peer, &forge(TYPE_ACK, 1, b""));
+        assert_eq!((odp.ack_held, com.stats().packets_sent), (0, sent + 1));
+
+        // or once the delay is over
+        peer.sendto(&forge(TYPE_SND, 2, b"two"), localhost()).unwrap();
+        recv_some(&mut odp, &mut buf);
+        odp.on_timeout(Instant::now()).unwrap();
+        assert_eq!(odp.ack_held, 1);
+        odp.on_timeout(Instant::now() + Duration::from_millis(100)).unwrap();
+        recv_packet(&peer, &forge(TYPE_ACK, 2, b""));
+
+        // recv_timeout sends it in time as well
+        peer.sendto(&forge(TYPE_SND, 3, b"three"), localhost()).unwrap();
+        recv_some(&mut odp, &mut buf);
+        assert_eq!(odp.recv_timeout(&mut buf, Duration::from_millis(300)).unwrap(), None);
+        assert_eq!(odp.ack_held, 0);
+        recv_packet(&peer, &forge(TYPE_ACK, 3, b""));
+    }
+
+    #[test]
+    fn flush() {
+        let com = Arc::new(IcmpCommunicator::with_magic(185, 0xa7).unwrap());
+        let mut odp = ODP::new(com, localhost());
+        odp.set_rto(Duration::from_secs(5));
+        let (mut odp, peer) = accept_forged(odp, 186, 0xa7);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+
+        // what is in flight is sent again right away, long before the RTO
+        let isn = odp.seqnum();
+        odp.send(b"checkpoint").unwrap();
+        recv_packet(&peer, &forge(TYPE_SND, isn, b"checkpoint"));
+        peer.sendto(&forge(TYPE_SND, 0, b"meanwhile"), localhost()).unwrap();
+        peer.sendto(&forge(TYPE_ACK, isn, b""), localhost()).unwrap();
+        odp.flush().unwrap();
+        recv_packet(&peer, &forge(TYPE_SND, isn, b"checkpoint"));
+        assert_eq!((odp.inflight(), odp.stats().retransmits), (0, 1));
+
+        // the message received while flushing is still there
+        let mut buf = [0; 64];
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"meanwhile");
+
+        odp.set_rto(Duration::from_millis(20));
+        odp.set_max_retransmits(2);
+        odp.send(b"nobody listens").unwrap();
+        match odp.flush() {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn connection_id() {
+        use std::thread;
+        use transport::Loopback;
+
+        let com = Arc::new(IcmpCommunicator::with_magic(187, 0xa8).unwrap());
+
+        // unless both peers want one
+        let mut odp = ODP::new(com.clone(), localhost());
+        odp.set_connection_id(true);
+        let (odp, _peer) = accept_forged(odp, 188, 0xa8);
+        assert_eq!(odp.connection_id(), None);
+
+        // the id picked by the initiator is sent back, then ends every packet
+        let mut odp = ODP::new(com, localhost());
+        odp.set_connection_id(true);
+        let peer = IcmpCommunicator::with_magic(189, 0xa8).unwrap();
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let cid: u64 = 0x0123_4567_89ab_cdef;
+        let with_cid = |mut pkt: Vec<u8>| {
+            pkt.extend_from_slice(&cid.to_le_bytes());
+            pkt
+        };
+        let mut syn = forge(TYPE_SYN, 0, &[0; 4]);
+        syn[1] |= FLAG_CID;
+        peer.sendto(&with_cid(syn), localhost()).unwrap();
+        odp.accept().unwrap();
+        assert_eq!(odp.connection_id(), Some(cid));
+        let mut sya = forge(TYPE_SYA, odp.seqnum(), &(WINDOW_SIZE as u32).to_le_bytes());
+        sya[1] |= FLAG_CID;
+        recv_packet(&peer, &with_cid(sya));
+
+        let mut buf = [0; 64];
+        peer.sendto(&forge(TYPE_SND, 0, b"no id"), localhost()).unwrap();
+        peer.sendto(&with_cid(forge(TYPE_SND, 0, b"with id")), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"with id");
+        recv_packet(&peer, &with_cid(forge(TYPE_ACK, 0, b"")));
+
+        // the peer moves, as after a NAT rebinding, but only with the id
+        let moved = InetAddr::from_std(&"127.0.0.2:0".parse().unwrap());
+        let snd   = with_cid(forge(TYPE_SND, 1, b"moved"));
+        assert_eq!(odp.handle_packet_(&snd, moved, &mut buf).unwrap(), Some(5));
+        assert!(odp.peer == moved);
+        assert_eq!(odp.stats().migrations, 1);
+        let other = InetAddr::from_std(&"127.0.0.3:0".parse().unwrap());
+        assert_eq!(odp.handle_packet_(&forge(TYPE_SND, 2, b"x"), other, &mut buf).unwrap(), None);
+        assert!(odp.peer == moved);
+
+        // not with a malformed packet, nor one that carries neither data nor an ack
+        let mut bad = forge(TYPE_SND, 2, &[10, 0]);
+        bad[1] |= FLAG_PAD;
+        assert_eq!(odp.handle_packet_(&with_cid(bad), other, &mut buf).unwrap(), None);
+        let short = forge(TYPE_ACK, 0, b"")[..5].to_vec();
+        assert_eq!(odp.handle_packet_(&with_cid(short), other, &mut buf).unwrap(), None);
+        assert_eq!(odp.stats().malformed, 2);
+        let kal = with_cid(forge(TYPE_KAL, 0, b""));
+        assert_eq!(odp.handle_packet_(&kal, other, &mut buf).unwrap(), None);
+        assert!(odp.peer == moved);
+        assert_eq!(odp.stats().migrations, 1);
+
+        // along with padding and a key
+        let (a, b) = Loopback::pair().unwrap();
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let mut odp = ODP::with_key(Arc::new(b), peer, b"key");
+            odp.set_connection_id(true);
+            odp.set_pad_to(200);
+            odp.accept().unwrap();
+            let cid = odp.connection_id();
+            let mut received = Vec::new();
+            odp.read_to_end(&mut received).unwrap();
+            (received, cid)
+        });
+        let peer = a.peer();
+        let mut odp = ODP::with_key(Arc::new(a), peer, b"key");
+        odp.set_connection_id(true);
+        odp.connect().unwrap();
+        let cid = odp.connection_id();
+        assert!(cid.is_some());
+        let data = vec![7; 5000];
+        odp.write_all(&data).unwrap();
+        odp.shutdown().unwrap();
+        assert!(server.join().unwrap() == (data, cid));
+    }
+
+    #[test]
+    fn send_all() {
+        let com = Arc::new(IcmpCommunicator::with_magic(190, 0xa9).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 191, 0xa9);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let payload = PKT_MAX_SIZE - PKT_HDR_SIZE;
+        let data    = vec![1; 2 * payload + 100];
+        let isn     = odp.seqnum();
+
+        // as many packets as the window takes, each a message of its own
+        assert_eq!(odp.send_all(&data).unwrap(), 2 * payload);
+        match odp.send_all(&data[2 * payload..]) {
+            Err(ODPError::RemoteWindowFull) => {}
+            res => panic!("{:?}", res),
+        }
+        recv_packet(&peer, &forge(TYPE_SND, isn, &data[..payload]));
+        recv_packet(&peer, &forge(TYPE_SND, isn.wrapping_add(1), &data[..payload]));
+
+        peer.sendto(&forge(TYPE_ACK, isn, b""), localhost()).unwrap();
+        while odp.inflight() == 2 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.send_all(&data[2 * payload..]).unwrap(), 100);
+        recv_packet(&peer, &forge(TYPE_SND, isn.wrapping_add(2), &data[..100]));
+    }
+
+    #[test]
+    fn send_all_crc() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let (pa, pb) = (a.peer(), b.peer());
+        let mut tx = ODP::with_window(Arc::new(a), pa, 8).unwrap();
+        let mut rx = ODP::new(Arc::new(b), pb);
+        tx.set_message_crc(true);
+        rx.set_message_crc(true);
+        let mut syn = forge(TYPE_SYN, tx.seqnum(), &[0; 4]);
+        syn[1] |= FLAG_CRC;
+        rx.handle_syn_(&syn);
+        let mut sya = forge(TYPE_SYA, rx.seqnum(), &[0; 4]);
+        sya[1] |= FLAG_CRC;
+        tx.handle_syn_(&sya);
+
+        // one packet per chunk, CRC included
+        let size = PKT_MAX_SIZE - PKT_HDR_SIZE - CRC_SIZE;
+        let data: Vec<u8> = (0..3 * size).map(|i| i as u8).collect();
+        assert_eq!(tx.send_all(&data).unwrap(), data.len());
+        assert_eq!(tx.inflight(), 3);
+
+        let mut buf = vec![0; PKT_MAX_SIZE];
+        for chunk in data.chunks(size) {
+            let n = rx.recv_timeout(&mut buf, Duration::from_secs(1)).unwrap();
+            assert_eq!(&buf[..n.unwrap()], chunk);
+        }
+        assert_eq!(rx.stats().integrity_errors, 0);
+    }
+
+
+
+    #[test]
+    fn max_inflight_bytes() {
+        let com = Arc::new(IcmpCommunicator::with_magic(192, 0xaa).unwrap());
+        let odp = ODP::with_window(com, localhost(), 8).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 193, 0xaa);
+        odp.set_max_inflight_bytes(Some(1000));
+        let isn = odp.seqnum();
+
+        // headers included
+        odp.send(&[1; 600]).unwrap();
+        odp.send(&[2; 300]).unwrap();
+        assert_eq!(odp.inflight_bytes(), 900 + 2 * PKT_HDR_SIZE);
+        match odp.send(&[3; 100]) {
+            Err(ODPError::RemoteWindowFull) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(odp.stats().in_flight_bytes, 920);
+        assert_eq!(odp.inflight(), 2);
+
+        // once acked, even a message larger than the limit goes
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), b""), localhost()).unwrap();
+        while odp.inflight() > 0 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.inflight_bytes(), 0);
+        odp.send(&[4; 2000]).unwrap();
+        match odp.send(b"x") {
+            Err(ODPError::RemoteWindowFull) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn icmp_unreachable() {
+        use transport::Loopback;
+
+        let (a, _b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        odp.connected = true;
+        let report = |kind, dest: &str| IcmpReport {
+            kind,
+            code: 1,
+            from: InetAddr::from_std(&"10.0.0.1:0".parse().unwrap()),
+            dest: InetAddr::from_std(&dest.parse().unwrap()),
+        };
+        let unreachable = |odp: &mut ODP<Loopback>| {
+            odp.handle_icmp_error_(report(IcmpErrorKind::Unreachable, "127.0.0.1:0"))
+        };
+
+        // about someone else, or only a TTL too low
+        odp.handle_icmp_error_(report(IcmpErrorKind::Unreachable, "10.0.0.2:0")).unwrap();
+        odp.handle_icmp_error_(report(IcmpErrorKind::TimeExceeded, "127.0.0.1:0")).unwrap();
+        assert_eq!(odp.stats().icmp_errors, 1);
+
+        // unless the peer shows up in between
+        for _ in 0..UNREACHABLE_MAX - 1 {
+            unreachable(&mut odp).unwrap();
+        }
+        odp.handle_packet_(&forge(TYPE_KAL, 0, b""), peer, &mut [0; 16]).unwrap();
+        for _ in 0..UNREACHABLE_MAX - 1 {
+            unreachable(&mut odp).unwrap();
+        }
+        match unreachable(&mut odp) {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+        assert!(odp.lost);
+    }
+
+    #[test]
+    fn short_write() {
+        use transport::Loopback;
+
+        // takes all of a packet but its last byte
+        struct Short(Loopback);
+        impl Transport for Short {
+            fn sendto(&self, buf: &[u8], _peer: InetAddr) -> result::Result<usize, ICError> {
+                Ok(buf.len() - 1)
+            }
+            fn recvfrom(&self, buf: &mut [u8])
+              -> result::Result<Option<(usize, InetAddr)>, ICError> {
+                self.0.recvfrom(buf)
+            }
+            fn rawfd(&self) -> &RawFd {
+                self.0.rawfd()
+            }
+            fn is_nonblocking(&self) -> result::Result<bool, ICError> {
+                self.0.is_nonblocking()
+            }
+            fn max_payload(&self) -> usize {
+                self.0.max_payload()
+            }
+        }
+
+        match sent_whole(9, 10) {
+            Err(ICError::ShortWrite { sent: 9, expected: 10 }) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(sent_whole(10, 10).unwrap(), 10);
+        match ODPError::from(ICError::ShortWrite { sent: 9, expected: 10 }) {
+            ODPError::SndError => {}
+            e => panic!("{:?}", e),
+        }
+
+        // a send error, not a packet sent with less data
+        let (a, _b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(Short(a)), peer);
+        match odp.connect() {
+            Err(ODPError::SndError) => {}
+            res => panic!("{:?}", res),
+        }
+        odp.connected = true;
+        match odp.send(b"data") {
+            Err(ODPError::SndError) => {}
+            res => panic!("{:?}", res),
+        }
+        // kept to be sent again
+        assert_eq!((odp.sendq.len(), odp.inflight()), (1, 0));
+    }
+    #[test]
+    fn probe() {
+        use std::thread;
+        use transport::Loopback;
+
+        let ms = Duration::from_millis;
+        let stats = ProbeStats { sent: 4, rtts: vec![ms(3), ms(1), ms(2)] };
+        assert_eq!(stats.loss(), 25.);
+        assert_eq!((stats.min_rtt(), stats.max_rtt()), (Some(ms(1)), Some(ms(3))));
+        assert_eq!((stats.mean_rtt(), stats.median_rtt()), (Some(ms(2)), Some(ms(2))));
+        let stats = ProbeStats::default();
+        assert_eq!((stats.loss(), stats.mean_rtt(), stats.median_rtt()), (0., None, None));
+
+        let (a, b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        match odp.probe(1) {
+            Err(ODPError::NotConnected) => {}
+            res => panic!("{:?}", res),
+        }
+
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let mut odp = ODP::new(Arc::new(b), peer);
+            odp.accept().unwrap();
+            odp.send(b"hello").unwrap();
+            let mut received = Vec::new();
+            odp.read_to_end(&mut received).unwrap();
+            received
+        });
+        odp.connect().unwrap();
+        let stats = odp.probe(4).unwrap();
+        assert_eq!((stats.sent, stats.rtts.len(), stats.loss()), (4, 4, 0.));
+        assert!(odp.rtt_estimate().is_some());
+
+        // what the server sent meanwhile is kept
+        let mut buf = [0; 16];
+        assert_eq!(odp.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        odp.write_all(b"data").unwrap();
+        odp.shutdown().unwrap();
+        assert_eq!(server.join().unwrap(), b"data");
+    }
+    #[test]
+    fn inflight() {
+        let com = Arc::new(IcmpCommunicator::with_magic(153, 0x98).unwrap());
+        let odp = ODP::with_window(com, localhost(), 4).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 154, 0x98);
+        let isn = odp.seqnum();
+
+        for i in 1..4 {
+            odp.send(b"unacked").unwrap();
+            assert_eq!(odp.inflight(), i);
+        }
+        assert_eq!(odp.seqnum(), isn.wrapping_add(3));
+
+        // the first two are acked
+        peer.sendto(&forge(TYPE_ACK, isn.wrapping_add(1), &[]), localhost()).unwrap();
+        while odp.inflight() > 1 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.inflight(), 1);
+
+        peer.sendto(&forge(TYPE_SND, 0, b"data"), localhost()).unwrap();
+        let mut buf = [0; 64];
+        recv_some(&mut odp, &mut buf);
+        assert_eq!(odp.peer_seqnum(), 1);
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let com = Arc::new(IcmpCommunicator::with_magic(159, 0x9b).unwrap());
+        let mut odp = ODP::with_window(com.clone(), localhost(), 4).unwrap();
+        for &size in &[PKT_MIN_SIZE - 1, com.max_payload() + 1] {
+            match odp.set_max_packet_size(size) {
+                Err(ODPError::InvalidPacketSize) => {}
+                res => panic!("{}: {:?}", size, res),
+            }
+        }
+        odp.set_mtu(1500).unwrap();
+        assert_eq!(odp.max_packet_size(), 1500 - com.overhead());
+
+        odp.set_max_packet_size(100).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 160, 0x9b);
+        odp.send(&[7; 250]).unwrap();
+        let mut buf  = [0; PKT_MAX_SIZE];
+        let mut pkts = Vec::new();
+        while pkts.len() < 3 {
+            if let Some((n, _)) = peer.recvfrom(&mut buf).unwrap() {
+                if buf[0] == TYPE_SND {
+                    pkts.push(n);
+                }
+            }
+        }
+        // the size of the message comes first
+        assert_eq!(pkts, [100, 100, 80 + MSG_LEN_SIZE]);
+
+        // too large for us, as if lost
+        let mut data = [0; 64];
+        peer.sendto(&forge(TYPE_SND, 0, &[1; 200]), localhost()).unwrap();
+        recv_none(&mut odp);
+        peer.sendto(&forge(TYPE_SND, 0, b"small"), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut data);
+        assert_eq!(&data[..n], b"small");
+    }
+
+    #[test]
+    fn path_mtu() {
+        let com = Arc::new(IcmpCommunicator::with_magic(161, 0x9c).unwrap());
+        let mut odp = ODP::new(com, localhost());
+        odp.set_path_mtu_discovery(true).unwrap();
+        let (mut odp, _peer) = accept_forged(odp, 162, 0x9c);
+        assert_eq!(odp.path_mtu(), None);
+
+        // lo lets anything through
+        odp.send(b"data").unwrap();
+        assert_eq!(odp.path_mtu(), Some(65535));
+        assert_eq!(odp.max_packet_size(), PKT_MAX_SIZE);
+
+        odp.set_path_mtu_discovery(false).unwrap();
+        assert_eq!(odp.path_mtu(), None);
+    }
+
+    #[test]
+    fn path_mtu_one_side() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+        use transport::Loopback;
+
+        // a path of MTU 576, remembers the largest packet sent
+        struct Narrow(Loopback, AtomicUsize);
+        impl Transport for Narrow {
+            fn sendto(&self, buf: &[u8], peer: InetAddr) -> result::Result<usize, ICError> {
+                self.1.fetch_max(buf.len(), Ordering::Relaxed);
+                self.0.sendto(buf, peer)
+            }
+            fn recvfrom(&self, buf: &mut [u8])
+              -> result::Result<Option<(usize, InetAddr)>, ICError> {
+                self.0.recvfrom(buf)
+            }
+            fn rawfd(&self) -> &RawFd {
+                self.0.rawfd()
+            }
+            fn is_nonblocking(&self) -> result::Result<bool, ICError> {
+                self.0.is_nonblocking()
+            }
+            fn max_payload(&self) -> usize {
+                self.0.max_payload()
+            }
+            fn path_mtu(&self, _peer: InetAddr) -> result::Result<usize, ICError> {
+                Ok(576)
+            }
+        }
+
+        let blob: Vec<u8> = (0..20000).map(|i| (i * 13) as u8).collect();
+        let sent = blob.clone();
+
+        // the peer doesn't know, it keeps sending full packets
+        let (a, b) = Loopback::pair().unwrap();
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let mut odp = ODP::new(Arc::new(b), peer);
+            odp.accept().unwrap();
+            let mut data = vec![0; sent.len()];
+            odp.read_exact(&mut data).unwrap();
+            odp.write_all(&data).unwrap();
+            assert_eq!(odp.payload_size_(), PKT_MAX_SIZE - PKT_HDR_SIZE);
+            odp.read_to_end(&mut data).unwrap();
+        });
+
+        let peer = a.peer();
+        let com  = Arc::new(Narrow(a, AtomicUsize::new(0)));
+        let mut odp = ODP::new(com.clone(), peer);
+        odp.set_path_mtu_discovery(true).unwrap();
+        odp.connect().unwrap();
+        // read after the first send
+        odp.write_all(&blob[..1]).unwrap();
+        com.1.store(0, Ordering::Relaxed);
+        odp.write_all(&blob[1..]).unwrap();
+        assert_eq!(odp.path_mtu(), Some(576));
+        assert_eq!(odp.max_packet_size(), PKT_MAX_SIZE);
+
+        let mut data = vec![0; blob.len()];
+        odp.read_exact(&mut data).unwrap();
+        assert!(data == blob);
+        odp.shutdown().unwrap();
+        server.join().unwrap();
+        assert!(com.1.load(Ordering::Relaxed) <= 576);
+    }
+
+    #[test]
+    fn loopback() {
+        use std::thread;
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let mut odp = ODP::with_window(Arc::new(b), peer, 4).unwrap();
+            odp.accept().unwrap();
+            let mut received = Vec::new();
+            odp.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let peer = a.peer();
+        let mut odp = ODP::with_window(Arc::new(a), peer, 4).unwrap();
+        odp.connect().unwrap();
+        odp.write_all(&data).unwrap();
+        odp.shutdown().unwrap();
+        assert!(server.join().unwrap() == data);
+        assert!(odp.stats().packets_sent > data.len() as u64 / PKT_MAX_SIZE as u64);
+    }
+
+    #[test]
+    fn lossy_link() {
+        use std::thread;
+        use transport::Lossy;
+
+        fn lossy(id: u8, seed: u64) -> Arc<Lossy<IcmpCommunicator>> {
+            let com = Arc::new(IcmpCommunicator::with_magic(id, 0x9d).unwrap());
+            let latency = Duration::from_millis(1)..Duration::from_millis(20);
+            Arc::new(Lossy::new(com, seed).with_drop(0.2).with_duplicate(0.1).with_reorder(0.2)
+                                           .with_delay(0.2, latency))
+        }
+        let data: Vec<u8> = (0..50_000).map(|i| (i * 7 % 251) as u8).collect();
+
+        let server = thread::spawn(|| {
+            let mut odp = ODP::with_window(lossy(163, 1), localhost(), 8).unwrap();
+            odp.accept().unwrap();
+            let mut received = Vec::new();
+            odp.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut odp = ODP::with_window(lossy(164, 2), localhost(), 8).unwrap();
+        odp.connect().unwrap();
+        odp.write_all(&data).unwrap();
+        // the server may be gone before the ack of our FIN gets through
+        match odp.shutdown() {
+            Ok(()) | Err(ODPError::AckError) => {}
+            res => panic!("{:?}", res),
+        }
+        assert!(odp.stats().retransmits > 0);
+        assert!(server.join().unwrap() == data);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let com = Arc::new(IcmpCommunicator::with_magic(167, 0x9f).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 168, 0x9f);
+        let mut small = [0; 4];
+        let mut buf   = [0; 64];
+
+        odp.set_oversized(Oversized::Truncate);
+        peer.sendto(&forge(TYPE_SND, 0, b"truncated"), localhost()).unwrap();
+        assert_eq!(recv_some(&mut odp, &mut small), 4);
+        assert_eq!(&small, b"trun");
+
+        odp.set_oversized(Oversized::Fail);
+        let mut more = forge(TYPE_SND, 1, &with_len(b"split message")[..MSG_LEN_SIZE + 6]);
+        more[1] |= FLAG_MORE;
+        peer.sendto(&more, localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, 2, b"message"), localhost()).unwrap();
+        loop {
+            match odp.recv(&mut small) {
+                Err(ODPError::BufferTooSmall { needed }) => { assert_eq!(needed, 13); break; }
+                res => assert_eq!(res.unwrap(), None),
+            }
+        }
+        // kept until a buffer is large enough
+        match odp.recv(&mut small) {
+            Err(ODPError::BufferTooSmall { needed: 13 }) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(odp.recv(&mut buf).unwrap(), Some(13));
+        assert_eq!(&buf[..13], b"split message");
+    }
+
+    #[test]
+    fn partial_delivery() {
+        let com = Arc::new(IcmpCommunicator::with_magic(181, 0xa5).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 182, 0xa5);
+        let mut tiny = [0; 7];
+
+        // a message in two packets, read 7 bytes at a time
+        let data: Vec<u8> = (0..100).collect();
+        let mut first = forge(TYPE_SND, 0, &with_len(&data)[..MSG_LEN_SIZE + 60]);
+        first[1] |= FLAG_MORE;
+        peer.sendto(&first, localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, 1, &data[60..]), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut tiny);
+        let mut received = tiny[..n].to_vec();
+        assert_eq!(odp.remaining(), 93);
+        while odp.remaining() > 0 {
+            let n = odp.recv(&mut tiny).unwrap().unwrap();
+            received.extend_from_slice(&tiny[..n]);
+        }
+        assert_eq!(received, data);
+
+        // the next message comes after
+        peer.sendto(&forge(TYPE_SND, 2, b"next"), localhost()).unwrap();
+        assert_eq!(recv_some(&mut odp, &mut tiny), 4);
+        assert_eq!(&tiny[..4], b"next");
+    }
+
+    #[test]
+    fn error() {
+        use std::error::Error;
+
+        let e = ODPError::from(ICError::NoSuchDevice("bogus0".to_string()));
+        assert_eq!(e.to_string(), "no such network interface: bogus0");
+        assert!(e.source().is_some());
+        assert_eq!(ODPError::BufferTooSmall { needed: 13 }.to_string(),
+                   "buffer too small for the message (13 bytes)");
+        assert!(ODPError::NotConnected.source().is_none());
+
+        // kept behind io errors
+        let e = io::Error::from(ODPError::RemoteWindowFull);
+        assert_eq!(e.to_string(), "the peer's window is full");
+        assert!(e.get_ref().unwrap().is::<ODPError>());
+    }
+
+    #[test]
+    fn close() {
+        use std::thread;
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let mut odp = ODP::new(Arc::new(b), peer);
+            odp.accept().unwrap();
+            let mut received = Vec::new();
+            odp.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        odp.close().unwrap();
+        odp.connect().unwrap();
+        odp.write_all(b"bye").unwrap();
+        odp.close().unwrap();
+        assert!(odp.is_closed());
+        odp.close().unwrap();
+        assert_eq!(server.join().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn fin_on_drop() {
+        let com = Arc::new(IcmpCommunicator::with_magic(169, 0xa0).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 170, 0xa0);
+        let seqnum = odp.seqnum();
+        odp.set_fin_on_drop(true);
+        drop(odp);
+        recv_packet(&peer, &forge(TYPE_FIN, seqnum, b""));
+    }
+
+    #[test]
+    fn process_packet() {
+        use transport::Loopback;
+
+        let pkt = forge(TYPE_SND, 7, b"data");
+        let hdr = PacketHeader { pkttype: TYPE_SND, version: VERSION, flags: 0, seqnum: 7 };
+        assert_eq!(decode_header(&pkt), Some(hdr));
+        assert_eq!(decode_header(&pkt[..PKT_HDR_SIZE - 1]), None);
+
+        let (a, _b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        let isn = 1000;
+
+        // a connection request first
+        odp.process_packet(&pkt, peer).unwrap();
+        odp.process_packet(&forge(TYPE_SYN, isn, &[0; 3]), peer).unwrap();
+        assert!(!odp.is_connected());
+        odp.process_packet(&forge(TYPE_SYN, isn, &[0; 4]), peer).unwrap();
+        assert!(odp.is_connected());
+
+        // then whatever recv would take
+        odp.process_packet(b"S", peer).unwrap();
+        assert_eq!(odp.stats().malformed, 1);
+        odp.process_packet(&forge(TYPE_SND, isn + 1, b"two"), peer).unwrap();
+        odp.process_packet(&forge(TYPE_SND, isn, b"one"), peer).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(odp.recv(&mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"one");
+        assert_eq!(odp.recv(&mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"two");
+        assert_eq!(odp.peer_seqnum(), isn + 2);
+    }
+
+    #[test]
+    fn truncated_packets() {
+        use transport::Loopback;
+
+        let connected = || {
+            let (a, _b) = Loopback::pair().unwrap();
+            // what the handlers send back is dropped rather than blocking
+            a.set_nonblocking(true).unwrap();
+            let peer = a.peer();
+            let mut odp = ODP::new(Arc::new(a), peer);
+            odp.connected = true;
+            (odp, peer)
+        };
+        let protocol_error = |pkt: &[u8]| {
+            let (mut odp, peer) = connected();
+            odp.process_packet(pkt, peer).unwrap();
+            assert_eq!(odp.stats().malformed, 1);
+        };
+
+        protocol_error(&forge(TYPE_ACK, 0, b"")[..PKT_HDR_SIZE - 1]);
+        protocol_error(&forge(TYPE_AGN, 0, &[0; 7]));
+        protocol_error(&forge(TYPE_SYN, 0, &[0; 3]));
+        let mut snd = forge(TYPE_SND, 0, &[10, 0, 1, 2]);
+        snd[1] |= FLAG_PAD;
+        protocol_error(&snd);
+        protocol_error(&snd[..PKT_HDR_SIZE + 1]);
+
+        // nothing panics, whatever the type and length, flags set or not, of any version
+        let mut x: u64 = 1;
+        for &pkttype in b"SAGYKFLNPRDZ" {
+            for len in 0..48 {
+                let mut pkt: Vec<u8> = (0..len).map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    x as u8
+                }).collect();
+                if len > 0 {
+                    pkt[0] = pkttype;
+                }
+                for &flags in &[0, 0x1f, 0xff] {
+                    if len > 1 {
+                        pkt[1] = flags;
+                    }
+                    let (mut odp, peer) = connected();
+                    let _ = odp.process_packet(&pkt, peer);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_version() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        let isn = 1000;
+
+        // connection requests of other versions are ignored
+        let mut syn = forge(TYPE_SYN, isn, &[8, 0, 0, 0]);
+        syn[1] = (VERSION + 1) << 4;
+        odp.process_packet(&syn, peer).unwrap();
+        assert!(!odp.is_connected());
+        syn[1] = VERSION_BITS;
+        odp.process_packet(&syn, peer).unwrap();
+        assert!(odp.is_connected());
+
+        // what we send says which version it is
+        let mut buf = [0; 64];
+        let (n, _) = b.recvfrom(&mut buf).unwrap().unwrap();
+        assert_eq!(decode_header(&buf[..n]).unwrap().version, VERSION);
+        odp.keepalive().unwrap();
+        let (n, _) = b.recvfrom(&mut buf).unwrap().unwrap();
+        assert_eq!(buf[..n], forge(TYPE_KAL, odp.seqnum(), b"")[..]);
+
+        // peers from before versions are understood, others are not
+        let mut snd = forge(TYPE_SND, isn, b"old");
+        snd[1] = 0;
+        odp.process_packet(&snd, peer).unwrap();
+        assert_eq!(odp.recv(&mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"old");
+        let mut snd = forge(TYPE_SND, isn + 1, b"new");
+        snd[1] = 0xf0;
+        odp.process_packet(&snd, peer).unwrap();
+        assert_eq!(odp.stats().malformed, 1);
+        assert_eq!(odp.peer_seqnum(), isn + 1);
+    }
+
+    #[test]
+    fn fragment_limits() {
+        use transport::Loopback;
+
+        let (a, _b) = Loopback::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        odp.connected = true;
+        let frag = |seqnum, data: &[u8], more| {
+            let mut snd = forge(TYPE_SND, seqnum, data);
+            if more {
+                snd[1] |= FLAG_MORE;
+            }
+            snd
+        };
+
+        // the fragments must add up to the size in the first one
+        let msg = with_len(b"0123456789");
+        odp.process_packet(&frag(0, &msg[..10], true), peer).unwrap();
+        odp.process_packet(&frag(1, b"6789!", false), peer).unwrap();
+        odp.process_packet(&frag(2, &msg[..10], true), peer).unwrap();
+        odp.process_packet(&frag(3, b"678", false), peer).unwrap();
+        assert_eq!(odp.stats().malformed, 2);
+        assert!(odp.frags.is_empty() && odp.inbox.is_empty());
+
+        // a message too large is dropped along with the fragments left
+        odp.set_max_message_size(50);
+        odp.process_packet(&frag(4, &with_len(&[1; 100])[..20], true), peer).unwrap();
+        odp.process_packet(&frag(5, &[1; 40], true), peer).unwrap();
+        odp.process_packet(&frag(6, &[1; 40], false), peer).unwrap();
+        assert_eq!(odp.stats().malformed, 3);
+        assert!(odp.frags.is_empty() && odp.inbox.is_empty());
+
+        // older peers don't tell the size, the limit still holds
+        let mut old = frag(7, &[2; 30], true);
+        old[1] = FLAG_MORE;
+        odp.process_packet(&old, peer).unwrap();
+        old[2] = 8;
+        odp.process_packet(&old, peer).unwrap();
+        assert_eq!(odp.stats().malformed, 4);
+        assert!(odp.frags.is_empty());
+        let mut last = frag(9, &[2; 30], false);
+        last[1] = 0;
+        odp.process_packet(&last, peer).unwrap();
+        assert!(odp.inbox.is_empty());
+
+        // then the next message goes through
+        let msg = with_len(b"whole message");
+        odp.process_packet(&frag(10, &msg[..10], true), peer).unwrap();
+        odp.process_packet(&frag(11, &msg[10..], false), peer).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(odp.recv(&mut buf).unwrap(), Some(13));
+        assert_eq!(&buf[..13], b"whole message");
+    }
+
+    #[test]
+    fn malformed_packets() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        odp.connected = true;
+
+        // dropped, whoever sent them, and the connection goes on
+        b.sendto(b"S", peer).unwrap();
+        b.sendto(&forge(b'Z', 0, b""), peer).unwrap();
+        b.sendto(&forge(TYPE_AGN, 0, &[0; 7]), peer).unwrap();
+        b.sendto(&forge(TYPE_SND, 0, b"data"), peer).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(odp.recv_timeout(&mut buf, Duration::from_secs(1)).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"data");
+        assert_eq!(odp.stats().malformed, 3);
+        assert!(odp.is_connected());
+    }
+
+    #[test]
+    fn recv_buf_autotune() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use transport::Loopback;
+
+        // remembers the receive buffer asked for
+        struct Tuned(Loopback, AtomicUsize);
+        impl Transport for Tuned {
+            fn sendto(&self, buf: &[u8], peer: InetAddr) -> result::Result<usize, ICError> {
+                self.0.sendto(buf, peer)
+            }
+            fn recvfrom(&self, buf: &mut [u8])
+              -> result::Result<Option<(usize, InetAddr)>, ICError> {
+                self.0.recvfrom(buf)
+            }
+            fn rawfd(&self) -> &RawFd {
+                self.0.rawfd()
+            }
+            fn is_nonblocking(&self) -> result::Result<bool, ICError> {
+                self.0.is_nonblocking()
+            }
+            fn max_payload(&self) -> usize {
+                self.0.max_payload()
+            }
+            fn overhead(&self) -> usize {
+                28
+            }
+            fn set_recv_bufsize(&self, size: usize) -> result::Result<(), ICError> {
+                self.1.store(size, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let (a, _b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::with_window(Arc::new(Tuned(a, AtomicUsize::new(0))), peer, 8).unwrap();
+        odp.set_max_packet_size(1000).unwrap();
+
+        // opt-in, and only once the RTT is known
+        odp.rtt_sample_(Duration::from_millis(100));
+        assert_eq!(odp.com.1.load(Ordering::Relaxed), 0);
+        odp.srtt = None;
+        odp.set_recv_buf_autotune(Some(1 << 20));
+        assert_eq!(odp.com.1.load(Ordering::Relaxed), 0);
+        odp.rtt_sample_(Duration::from_millis(100));
+        let bdp = 8 * (1000 + 28);
+        assert_eq!(odp.com.1.load(Ordering::Relaxed), bdp * RCVBUF_HEADROOM);
+        assert_eq!(odp.stats().recv_bufsize, bdp * RCVBUF_HEADROOM);
+
+        // bounded
+        odp.set_recv_buf_autotune(Some(4096));
+        assert_eq!((odp.com.1.load(Ordering::Relaxed), odp.stats().recv_bufsize), (4096, 4096));
+    }
+
+    #[test]
+    fn message_crc() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let (pa, pb) = (a.peer(), b.peer());
+        let mut tx = ODP::with_window(Arc::new(a), pa, 8).unwrap();
+        let mut rx = ODP::new(Arc::new(b), pb);
+        let syn = |odp: &ODP<Loopback>| {
+            let mut syn = forge(TYPE_SYN, odp.seqnum(), &[0; 4]);
+            syn[1] |= FLAG_CRC;
+            syn
+        };
+
+        // only if both peers want it
+        rx.handle_syn_(&syn(&tx));
+        assert!(!rx.uses_message_crc());
+        tx.set_message_crc(true);
+        rx.set_message_crc(true);
+        tx.handle_syn_(&syn(&rx));
+        rx.handle_syn_(&syn(&tx));
+        assert!(tx.uses_message_crc() && rx.uses_message_crc());
+
+        // checked once reassembled
+        let msg: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let mut buf = vec![0; 8192];
+        tx.send(&msg).unwrap();
+        let n = rx.recv_timeout(&mut buf, Duration::from_secs(1)).unwrap();
+        assert_eq!(n, Some(msg.len()));
+        assert_eq!(&buf[..msg.len()], &msg[..]);
+        assert_eq!(rx.stats().bytes_received, msg.len() as u64);
+
+        // a corrupted message is dropped, the connection goes on
+        tx.send(b"hello").unwrap();
+        let (s, from) = rx.com.recvfrom(&mut buf).unwrap().unwrap();
+        buf[s - 1] ^= 1;
+        let pkt = buf[..s].to_vec();
+        match rx.handle_packet_(&pkt, from, &mut buf) {
+            Err(ODPError::IntegrityError) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(rx.stats().integrity_errors, 1);
+        tx.send(b"world").unwrap();
+        assert_eq!(rx.recv_timeout(&mut buf, Duration::from_secs(1)).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn unreliable() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let (pa, pb) = (a.peer(), b.peer());
+        let mut tx = ODP::new_unreliable(Arc::new(a), pa);
+        let mut rx = ODP::new_unreliable(Arc::new(b), pb);
+        tx.set_pad_to(64);
+
+        // no handshake, nothing to acknowledge
+        let mut buf = [0; 16];
+        tx.send(b"one").unwrap();
+        tx.send(b"two").unwrap();
+        assert_eq!(tx.inflight(), 0);
+        for msg in [b"one", b"two"] {
+            assert_eq!(rx.recv_timeout(&mut buf, Duration::from_secs(1)).unwrap(), Some(3));
+            assert_eq!(&buf[..3], msg);
+        }
+        assert_eq!((tx.stats().packets_sent, rx.stats().bytes_received), (2, 6));
+        match tx.send(&[0; PKT_MAX_SIZE]) {
+            Err(ODPError::InvalidPacketSize) => {}
+            res => panic!("{:?}", res),
+        }
+
+        // the packets of connections are ignored, and the other way around
+        let snd = forge(TYPE_SND, 0, b"data");
+        assert_eq!(rx.handle_packet_(&snd, pb, &mut buf).unwrap(), None);
+        let (c, _d) = Loopback::pair().unwrap();
+        let peer = c.peer();
+        let mut odp = ODP::new(Arc::new(c), peer);
+        odp.connected = true;
+        assert_eq!(odp.handle_packet_(&forge(TYPE_DGM, 0, b"data"), peer, &mut buf).unwrap(), None);
+        assert_eq!(odp.stats().malformed, 1);
+
+        tx.shutdown().unwrap();
+        assert!(tx.is_closed());
+        tx.connect().unwrap();
+        tx.send(b"three").unwrap();
+    }
+
+    #[test]
+    fn session_expiry() {
+        use transport::Loopback;
+
+        let com = Arc::new(IcmpCommunicator::with_magic(194, 0xab).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 195, 0xab);
+        let seqnum = odp.seqnum();
+        odp.set_idle_timeout(Some(Duration::from_secs(10)));
+        let now = Instant::now();
+
+        // data keeps the session alive
+        odp.on_timeout(now + Duration::from_secs(5)).unwrap();
+        odp.send(b"data").unwrap();
+        recv_packet(&peer, &forge(TYPE_SND, seqnum, b"data"));
+        peer.sendto(&forge(TYPE_ACK, seqnum.wrapping_add(1), b""), localhost()).unwrap();
+        while odp.inflight() > 0 {
+            recv_none(&mut odp);
+        }
+        odp.on_timeout(now + Duration::from_secs(9)).unwrap();
+        match odp.on_timeout(Instant::now() + Duration::from_secs(10)) {
+            Err(ODPError::SessionExpired) => {}
+            res => panic!("{:?}", res),
+        }
+        recv_packet(&peer, &forge(TYPE_FIN, seqnum.wrapping_add(1), b""));
+        assert!(odp.is_closed());
+        match (odp.recv(&mut [0; 16]), odp.send(b"more")) {
+            (Err(ODPError::SessionExpired), Err(ODPError::SessionExpired)) => {}
+            res => panic!("{:?}", res),
+        }
+
+        // however busy
+        let (a, _b) = Loopback::pair().unwrap();
+        let peer = a.peer();
+        let mut odp = ODP::new(Arc::new(a), peer);
+        odp.connected = true;
+        odp.set_max_lifetime(Some(Duration::from_secs(60)));
+        odp.send(b"data").unwrap();
+        odp.on_timeout(Instant::now() + Duration::from_secs(30)).unwrap();
+        match odp.on_timeout(Instant::now() + Duration::from_secs(60)) {
+            Err(ODPError::SessionExpired) => {}
+            res => panic!("{:?}", res),
+        }
+
+        // until the next connection
+        odp.reset();
+        assert!(!odp.expired);
+    }
+
+    #[test]
+    fn stale_peer() {
+        use std::thread;
+
+        let com = Arc::new(IcmpCommunicator::with_magic(171, 0xa1).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 172, 0xa1);
+        let isn = odp.seqnum();
+        odp.set_rto(Duration::from_millis(20));
+
+        odp.send(b"unacked").unwrap();
+        assert_eq!(odp.stale_peer(), None);
+        while odp.stale_peer().is_none() {
+            thread::sleep(Duration::from_millis(5));
+            odp.on_timeout(Instant::now()).unwrap();
+        }
+        let stale = odp.stale_peer().unwrap();
+        assert!(!stale.half_open);
+        assert!(stale.silent_for >= Duration::from_millis(20 + 40 + 80));
+
+        // still sending, but no ack
+        peer.sendto(&forge(TYPE_KAL, 0, b""), localhost()).unwrap();
+        while !odp.stale_peer().unwrap().half_open {
+            recv_none(&mut odp);
+        }
+
+        peer.sendto(&forge(TYPE_ACK, isn, b""), localhost()).unwrap();
+        while odp.stale_peer().is_some() {
+            recv_none(&mut odp);
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        use std::thread;
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(112, 0x85).unwrap());
+            let mut odp = ODP::with_window(com, localhost(), 8).unwrap();
+            odp.accept().unwrap();
+            let mut buf = [0; 64];
+            let n = recv_some(&mut odp, &mut buf);
+            (odp.window, buf[..n].to_vec())
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(113, 0x85).unwrap());
+        let mut odp = ODP::with_window(com, localhost(), 4).unwrap();
+        match odp.send(b"too early") {
+            Err(ODPError::NotConnected) => {}
+            res => panic!("{:?}", res),
+        }
+
+        // the server may not be listening yet, the SYN is then sent again
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        assert!(odp.is_connected());
+        odp.send(b"hello").unwrap();
+
+        // both sides use the smallest window
+        let (window, data) = server.join().unwrap();
+        assert_eq!((window, odp.window), (4, 4));
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn authentication() {
+        let com     = Arc::new(IcmpCommunicator::with_magic(142, 0x93).unwrap());
+        let mut odp = ODP::with_key(com, localhost(), b"secret");
+        let peer     = IcmpCommunicator::with_magic(143, 0x93).unwrap();
+        // a SYN without a tag is ignored, the next one is accepted
+        peer.sendto(&forge(TYPE_SYN, 0, &[0; 4]), localhost()).unwrap();
+        peer.sendto(&sealed(forge(TYPE_SYN, 0, &[0; 4]), b"secret", 0), localhost()).unwrap();
+        odp.accept().unwrap();
+        recv_packet(&peer, &sealed(forge(TYPE_SYA, odp.seqnum, &[2, 0, 0, 0]), b"secret", 0));
+        let key = session(b"secret", (0, odp.seqnum));
+
+        // forged packets are dropped, even though their seqnum is right
+        peer.sendto(&forge(TYPE_SND, 0, b"forged"), localhost()).unwrap();
+        peer.sendto(&sealed(forge(TYPE_SND, 0, b"forged"), b"guess", 1), localhost()).unwrap();
+        while odp.stats().auth_failures < 3 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.stats().peer_seqnum, 0);
+
+        // so are packets sealed with the key we were given instead of the one of the connection
+        peer.sendto(&sealed(forge(TYPE_SND, 0, b"stale"), b"secret", 1), localhost()).unwrap();
+        while odp.stats().auth_failures < 4 {
+            recv_none(&mut odp);
+        }
+
+        peer.sendto(&sealed(forge(TYPE_SND, 0, b"genuine"), &key, 1), localhost()).unwrap();
+        let mut buf = [0; 64];
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"genuine");
+        recv_packet(&peer, &sealed(forge(TYPE_ACK, 0, &[]), &key, 1));
+    }
+
+    #[test]
+    fn replay() {
+        let com     = Arc::new(IcmpCommunicator::with_magic(144, 0x94).unwrap());
+        let mut odp = ODP::with_key(com, localhost(), b"secret");
+        let peer    = IcmpCommunicator::with_magic(145, 0x94).unwrap();
+        peer.sendto(&sealed(forge(TYPE_SYN, 0, &[0; 4]), b"secret", 0), localhost()).unwrap();
+        odp.accept().unwrap();
+        let key = session(b"secret", (0, odp.seqnum));
+
+        let mut buf = [0; 64];
+        let snd = sealed(forge(TYPE_SND, 0, b"once"), &key, 2);
+        peer.sendto(&snd, localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"once");
+
+        // the same packet again is dropped without even an ack, and without delivering anything
+        peer.sendto(&snd, localhost()).unwrap();
+        while odp.stats().replays < 1 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.stats().peer_seqnum, 1);
+        assert_eq!(odp.auth.as_ref().unwrap().sent.get(), 2); // SYA and ACK
+
+        // counters may arrive out of order, but not too late
+        assert!(odp.auth.as_mut().unwrap().check(1));
+        assert!(!odp.auth.as_mut().unwrap().check(1));
+        assert!(odp.auth.as_mut().unwrap().check(2 + REPLAY_WINDOW));
+        assert!(!odp.auth.as_mut().unwrap().check(2));
+        assert!(odp.auth.as_mut().unwrap().check(3));
+
+        // a retransmission gets a new counter, it is acked again but delivered once
+        peer.sendto(&sealed(forge(TYPE_SND, 0, b"once"), &key, 100), localhost()).unwrap();
+        recv_none(&mut odp);
+        recv_packet(&peer, &sealed(forge(TYPE_ACK, 0, &[]), &key, 2));
+        peer.sendto(&sealed(forge(TYPE_SND, 1, b"twice"), &key, 101), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"twice");
+    }
+
+    #[test]
+    fn replay_across_connections() {
+        let com     = Arc::new(IcmpCommunicator::with_magic(196, 0xac).unwrap());
+        let mut odp = ODP::with_key(com, localhost(), b"secret");
+        let peer    = IcmpCommunicator::with_magic(197, 0xac).unwrap();
+        let syn = sealed(forge(TYPE_SYN, 0, &[0; 4]), b"secret", 0);
+        peer.sendto(&syn, localhost()).unwrap();
+        odp.accept().unwrap();
+
+        let mut buf = [0; 64];
+        let snd = sealed(forge(TYPE_SND, 0, b"once"), &session(b"secret", (0, odp.seqnum)), 1);
+        peer.sendto(&snd, localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"once");
+
+        // the counters start over with the next connection, but its key is another one: the
+        // recorded SYN opens it, the data that followed is dropped
+        odp.reset();
+        peer.sendto(&syn, localhost()).unwrap();
+        odp.accept().unwrap();
+        peer.sendto(&snd, localhost()).unwrap();
+        while odp.stats().auth_failures < 1 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.stats().replays, 0);
+        assert_eq!(odp.stats().peer_seqnum, 0);
+    }
+
+    #[test]
+    fn duplicate_syn() {
+        let com = Arc::new(IcmpCommunicator::with_magic(114, 0x86).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 115, 0x86);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+
+        let mut sya = forge(TYPE_SYA, odp.seqnum, &[0; 4]);
+        LittleEndian::write_u32(&mut sya[PKT_HDR_SIZE..], WINDOW_SIZE as u32);
+        recv_packet(&peer, &sya);
+
+        // the SYA was lost, the peer asks again
+        peer.sendto(&forge(TYPE_SYN, 0, &[0; 4]), localhost()).unwrap();
+        let tv = TimeVal::milliseconds(200);
+        setsockopt(*odp.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+        while let Ok(None) = odp.recv(&mut [0; 64]) {}
+        recv_packet(&peer, &sya);
+    }
+
+    #[test]
+    fn shutdown() {
+        use std::thread;
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(116, 0x87).unwrap());
+            let mut odp = ODP::with_window(com, localhost(), 8).unwrap();
+            odp.accept().unwrap();
+
+            let tv = TimeVal::milliseconds(2000);
+            setsockopt(*odp.rawfd(), sockopt::ReceiveTimeout, &tv).unwrap();
+            let mut data = Vec::new();
+            let mut buf  = [0; 64];
+            while !odp.is_closed() {
+                if let Some(n) = odp.recv(&mut buf).expect("no FIN received") {
+                    data.extend_from_slice(&buf[..n]);
+                }
+            }
+            // the end of the connection is reported as end of file
+            assert_eq!(odp.recv(&mut buf).unwrap(), None);
+            data
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(117, 0x87).unwrap());
+        let mut odp = ODP::with_window(com, localhost(), 8).unwrap();
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        for msg in [&b"one "[..], b"two ", b"three"].iter() {
+            odp.send(msg).unwrap();
+        }
+        odp.shutdown().unwrap();
+        assert!(odp.is_closed());
+        match odp.send(b"too late") {
+            Err(ODPError::NotConnected) => {}
+            res => panic!("{:?}", res),
+        }
+
+        assert_eq!(server.join().unwrap(), b"one two three");
+    }
+
+    #[test]
+    fn seq_cmp() {
+        let max = Seqnum::MAX;
+        assert!(seq_lt(0, 1));
+        assert!(seq_lt(max, 0));
+        assert!(seq_lt(max - 1, 1));
+        assert!(seq_gt(0, max));
+        assert!(seq_gt(2, max - 2));
+        assert!(!seq_lt(3, 3));
+        assert!(!seq_gt(3, 3));
+
+        // the two halves of the space
+        assert!(seq_lt(0, SEQ_HALF - 1));
+        assert!(seq_gt(0, SEQ_HALF + 1));
+        assert!(!seq_lt(0, SEQ_HALF) && !seq_gt(0, SEQ_HALF));
+    }
+
+    #[test]
+    fn reorder_wraparound() {
+        let com = Arc::new(IcmpCommunicator::with_magic(118, 0x88).unwrap());
+        let max = Seqnum::MAX;
+        let odp = ODP::with_window(com, localhost(), 4).unwrap();
+        let (mut odp, peer) = accept_forged_at(odp, 119, 0x88, max - 1);
+
+        peer.sendto(&forge(TYPE_SND, 1,       b"d"), localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, max,     b"b"), localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, 0,       b"c"), localhost()).unwrap();
+        peer.sendto(&forge(TYPE_SND, max - 1, b"a"), localhost()).unwrap();
+
+        let mut buf = [0; 64];
+        for expected in [&b"a"[..], b"b", b"c", b"d"].iter() {
+            let n = recv_some(&mut odp, &mut buf);
+            assert_eq!(&buf[..n], *expected);
+        }
+        assert_eq!(odp.peer_seqnum, 2);
+
+        // our own seqnums wrap around as well
+        odp.seqnum = max;
+        odp.send(b"last").unwrap();
+        odp.send(b"first").unwrap();
+        assert_eq!(odp.seqnum, 1);
+        peer.sendto(&forge(TYPE_ACK, 0, b""), localhost()).unwrap();
+        while !odp.ack_wait.is_empty() {
+            recv_none(&mut odp);
+        }
+    }
+
+    #[test]
+    fn connection_lost() {
+        let com = Arc::new(IcmpCommunicator::with_magic(120, 0x89).unwrap());
+        let (mut odp, _peer) = accept_forged(ODP::new(com, localhost()), 121, 0x89);
+        odp.set_max_retransmits(2);
+        odp.send(b"nobody listens").unwrap();
+
+        // far enough in the future for any RTO
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            now += Duration::from_millis(2 * RTO_MAX);
+            odp.on_timeout(now).unwrap();
+        }
+        assert_eq!(odp.ack_wait[0].resent, 2);
+
+        now += Duration::from_millis(2 * RTO_MAX);
+        match odp.on_timeout(now) {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+        match odp.recv(&mut [0; 64]) {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+        match odp.send(b"still nobody") {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let com = Arc::new(IcmpCommunicator::with_magic(155, 0x99).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 156, 0x99);
+        odp.set_rto(Duration::from_millis(300));
+        odp.set_max_retransmits(0);
+        odp.send(b"lost").unwrap();
+        let isn = odp.seqnum();
+        match odp.on_timeout(Instant::now() + Duration::from_millis(2 * RTO_MAX)) {
+            Err(ODPError::ConnectionLost) => {}
+            res => panic!("{:?}", res),
+        }
+
+        odp.reset();
+        assert!(!odp.is_connected() && !odp.is_closed());
+        assert_ne!(odp.seqnum(), isn);
+        assert_eq!((odp.inflight(), odp.peer_seqnum(), odp.stats().packets_sent), (0, 0, 0));
+        assert_eq!(odp.rto(), Duration::from_millis(300));
+
+        // a new connection, with other seqnums
+        peer.sendto(&forge(TYPE_SYN, 500, &[0; 4]), localhost()).unwrap();
+        odp.accept().unwrap();
+        peer.sendto(&forge(TYPE_SND, 500, b"again"), localhost()).unwrap();
+        let mut buf = [0; 64];
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"again");
+        odp.send(b"hello again").unwrap();
+    }
+
+    #[test]
+    fn keepalive() {
+        let com = Arc::new(IcmpCommunicator::with_magic(122, 0x8a).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 123, 0x8a);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        assert!(odp.is_peer_alive(Duration::from_secs(60)));
+        assert!(!odp.is_peer_alive(Duration::from_secs(0)));
+
+        let seqnum = odp.seqnum;
+        odp.keepalive().unwrap();
+        recv_packet(&peer, &forge(TYPE_KAL, seqnum, b""));
+        assert_eq!(odp.seqnum, seqnum);
+        assert!(odp.ack_wait.is_empty());
+
+        // so does sending nothing, rather than an empty message
+        assert_eq!(odp.send(b"").unwrap(), 0);
+        recv_packet(&peer, &forge(TYPE_KAL, seqnum, b""));
+        assert_eq!(odp.seqnum, seqnum);
+        assert!(odp.ack_wait.is_empty());
+
+        // no data received yet, the seqnum before the first one is acked
+        let before = odp.last_recv;
+        peer.sendto(&forge(TYPE_KAL, 0, b""), localhost()).unwrap();
+        while odp.last_recv == before {
+            recv_none(&mut odp);
+        }
+        let mut ack = forge(TYPE_ACK, Seqnum::MAX, b"");
+        ack[1] |= FLAG_KAL;
+        recv_packet(&peer, &ack);
+    }
+
+    #[test]
+    fn keepalive_in_flight() {
+        use transport::Loopback;
+
+        let (a, b) = Loopback::pair().unwrap();
+        let (pa, pb) = (a.peer(), b.peer());
+        let mut tx = ODP::with_window(Arc::new(a), pa, 8).unwrap();
+        let mut rx = ODP::new(Arc::new(b), pb);
+        rx.handle_syn_(&forge(TYPE_SYN, tx.seqnum(), &[0; 4]));
+        tx.handle_syn_(&forge(TYPE_SYA, rx.seqnum(), &[0; 4]));
+        rx.com.set_nonblocking(true).unwrap();
+        tx.com.set_nonblocking(true).unwrap();
+
+        // the answers to keepalives come back while data is in flight, they ack nothing new
+        let mut buf = [0; 64];
+        for _ in 0..DUP_ACKS {
+            tx.keepalive().unwrap();
+            assert_eq!(rx.recv(&mut buf).unwrap(), None);
+        }
+        tx.send(b"one").unwrap();
+        tx.send(b"two").unwrap();
+        let cwnd = tx.cwnd();
+        for _ in 0..DUP_ACKS {
+            assert_eq!(tx.recv(&mut buf).unwrap(), None);
+        }
+        assert_eq!(tx.stats().acks_received, DUP_ACKS as u64);
+        assert_eq!((tx.cwnd(), tx.dup_acks, tx.inflight()), (cwnd, 0, 2));
+        assert!(tx.recovery.is_none());
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let com = Arc::new(IcmpCommunicator::with_magic(136, 0x90).unwrap());
+        let (mut odp, peer) = accept_forged(ODP::new(com, localhost()), 137, 0x90);
+        let mut buf = [0; 64];
+
+        let start = Instant::now();
+        assert_eq!(odp.recv_timeout(&mut buf, Duration::from_millis(100)).unwrap(), None);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        peer.sendto(&forge(TYPE_SND, 0, b"hi"), localhost()).unwrap();
+        let got = odp.recv_timeout(&mut buf, Duration::from_secs(2)).unwrap();
+        assert_eq!(got.map(|n| &buf[..n]), Some(&b"hi"[..]));
+    }
+
+    #[test]
+    fn read_write() {
+        use std::thread;
+
+        // larger than a packet and than the window
+        let blob: Vec<u8> = (0..20000).map(|i| (i * 7) as u8).collect();
+        let sent = blob.clone();
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(124, 0x8b).unwrap());
+            let mut odp = ODP::new(com, localhost());
+            odp.accept().unwrap();
+            let mut data = Vec::new();
+            odp.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(125, 0x8b).unwrap());
+        let mut odp = ODP::new(com, localhost());
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        io::copy(&mut &sent[..], &mut odp).unwrap();
+        odp.shutdown().unwrap();
+
+        assert_eq!(server.join().unwrap(), blob);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compression() {
+        use std::thread;
+
+        let text = b"Compressed one packet at a time, so that each can be lost alone. ".repeat(300);
+        let mut x: u32 = 0x9e37_79b9;
+        let noise: Vec<u8> = (0..20000).map(|_| {
+            x ^= x << 13; x ^= x >> 17; x ^= x << 5;
+            x as u8
+        }).collect();
+        let mut blob = text.clone();
+        blob.extend_from_slice(&noise);
+        let sent = blob.clone();
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(146, 0x95).unwrap());
+            let mut odp = ODP::new(com, localhost());
+            odp.accept().unwrap();
+            let mut data = Vec::new();
+            odp.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(147, 0x95).unwrap());
+        let mut odp = ODP::with_compression(com, localhost());
+        assert!(odp.pack_(&text[..1000]).is_some_and(|p| p.len() < 200));
+        assert!(odp.pack_(&noise[..1000]).is_none());
+
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        io::copy(&mut &sent[..], &mut odp).unwrap();
+        odp.shutdown().unwrap();
+
+        assert_eq!(server.join().unwrap(), blob);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn tokio_copy() {
+        use std::thread;
+        use self::tokio::io::{self as tokio_io, AsyncWriteExt};
+        use self::tokio::runtime::{Builder, Runtime};
+        use transport::{Loopback, Lossy};
+
+        fn runtime() -> Runtime {
+            Builder::new_current_thread().enable_io().enable_time().build().unwrap()
+        }
+
+        let blob: Vec<u8> = (0..50000).map(|i| (i * 7) as u8).collect();
+        let sent = blob.clone();
+
+        let (a, b) = Loopback::pair().unwrap();
+        let server = thread::spawn(move || {
+            let peer = b.peer();
+            let com  = Arc::new(b);
+            let mut odp = ODP::new(com.clone(), peer);
+            odp.accept().unwrap();
+            com.set_nonblocking(true).unwrap();
+
+            let rt = runtime();
+            let _rt = rt.enter();
+            let mut stream = TokioOdp::new(odp).unwrap();
+            let mut data = Vec::new();
+            rt.block_on(tokio_io::copy(&mut stream, &mut data)).unwrap();
+            data
+        });
+
+        // losses are recovered from while the task waits for acks
+        let peer = a.peer();
+        let com  = Arc::new(Lossy::new(Arc::new(a), 29).with_drop(0.1));
+        let mut odp = ODP::new(com.clone(), peer);
+        odp.set_rto(Duration::from_millis(50));
+        odp.connect().unwrap();
+
+        // the communicator has to be in non blocking mode
+        let rt = runtime();
+        let _rt = rt.enter();
+        assert!(TokioOdp::new(ODP::new(com.clone(), peer)).is_err());
+        com.inner().set_nonblocking(true).unwrap();
+
+        let mut stream = TokioOdp::new(odp).unwrap();
+        rt.block_on(tokio_io::copy(&mut &sent[..], &mut stream)).unwrap();
+        rt.block_on(stream.shutdown()).unwrap();
+        assert!(stream.get_ref().is_closed());
+        assert!(stream.get_ref().stats().retransmits > 0);
+        assert!(server.join().unwrap() == blob);
+    }
+
+    #[test]
+    #[cfg(feature = "crypto")]
+    fn encryption() {
+        use std::thread;
+
+        // data in the clear is dropped, even though the packet is authenticated
+        let com     = Arc::new(IcmpCommunicator::with_magic(148, 0x96).unwrap());
+        let mut odp = ODP::with_encryption(com, localhost(), b"secret");
+        let peer    = IcmpCommunicator::with_magic(149, 0x96).unwrap();
+
+        // and never compressed, the size would tell about the data
+        #[cfg(feature = "compression")]
+        {
+            odp.compress = true;
+            assert!(odp.pack_(&[0; 1000]).is_none());
+            odp.compress = false;
+        }
+
+        peer.sendto(&sealed(forge(TYPE_SYN, 0, &[0; 4]), b"secret", 0), localhost()).unwrap();
+        odp.accept().unwrap();
+        let key = session(b"secret", (0, odp.seqnum));
+        peer.sendto(&sealed(forge(TYPE_SND, 0, b"clear"), &key, 1), localhost()).unwrap();
+        while odp.stats().auth_failures < 1 {
+            recv_none(&mut odp);
+        }
+        assert_eq!(odp.stats().peer_seqnum, 0);
+
+        // so is data encrypted with the key of the secret alone, which every connection would
+        // share, rather than with the one of this connection
+        let encrypted = |key: &[u8; DIGEST_SIZE], data: &[u8]| {
+            let mut snd = forge(TYPE_SND, 0, &[]);
+            snd[1] |= FLAG_SEALED;
+            let mut nonce = [0; aead::NONCE_SIZE];
+            nonce[0] = 1; // sent by the initiator
+            let hdr = snd.clone();
+            ChaCha20Poly1305::new(key).seal(&nonce, &hdr, data, &mut snd);
+            snd
+        };
+        let shared = Hmac::new(b"secret").mac(b"odp encryption key");
+        let own    = session_key(&Hmac::new(b"secret"), b"odp encryption key", (0, odp.seqnum));
+        peer.sendto(&sealed(encrypted(&shared, b"shared"), &key, 2), localhost()).unwrap();
+        while odp.stats().auth_failures < 2 {
+            recv_none(&mut odp);
+        }
+        peer.sendto(&sealed(encrypted(&own, b"own"), &key, 3), localhost()).unwrap();
+        let mut buf = [0; 64];
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"own");
+
+        // what goes through the tunnel can't be read on the way
+        let blob = b"attack at dawn ".repeat(200);
+        let sent = blob.clone();
+        let sniffer = IcmpCommunicator::with_magic(152, 0x97).unwrap();
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(150, 0x97).unwrap());
+            let mut odp = ODP::with_encryption(com, localhost(), b"secret");
+            odp.accept().unwrap();
+            let mut data = Vec::new();
+            odp.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(151, 0x97).unwrap());
+        let mut odp = ODP::with_encryption(com, localhost(), b"secret");
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        io::copy(&mut &sent[..], &mut odp).unwrap();
+        odp.shutdown().unwrap();
+        assert_eq!(server.join().unwrap(), blob);
+
+        sniffer.set_nonblocking(true).unwrap();
+        let mut buf = [0; PKT_MAX_SIZE];
+        let mut seen = 0;
+        while let Ok(res) = sniffer.recvfrom(&mut buf) {
+            if let Some((n, _)) = res {
+                assert!(!buf[..n].windows(6).any(|w| w == b"attack"));
+                seen += 1;
+            }
+        }
+        assert!(seen > 2);
+    }
+
+    #[test]
+    fn paced() {
+        use std::thread;
+
+        let blob: Vec<u8> = (0..20000).map(|i| (i * 3) as u8).collect();
+        let sent = blob.clone();
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(138, 0x91).unwrap());
+            let mut odp = ODP::new(com, localhost());
+            odp.accept().unwrap();
+            let mut data = Vec::new();
+            odp.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        // a SYN, 14 SND and a FIN, a second worth of packets goes out right away
+        let com = Arc::new(IcmpCommunicator::with_magic(139, 0x91).unwrap());
+        com.set_pace_limit(Some(10), None);
+        let mut odp = ODP::new(com, localhost());
+        odp.set_rto(Duration::from_millis(100));
+        let start = Instant::now();
+        odp.connect().unwrap();
+        io::copy(&mut &sent[..], &mut odp).unwrap();
+        odp.shutdown().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(server.join().unwrap(), blob);
+    }
+
+    #[test]
+    fn padding() {
+        let com = Arc::new(IcmpCommunicator::with_magic(140, 0x92).unwrap());
+        let mut odp = ODP::new(com, localhost());
+        odp.set_pad_to(52);
+        let (mut odp, peer) = accept_forged(odp, 141, 0x92);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let pad = |mut pkt: Vec<u8>| { pkt.resize(52, 0); pkt };
+        let mut buf = [0; 64];
+
+        // data packets tell the length of their data
+        let seqnum = odp.seqnum;
+        odp.send(b"hi").unwrap();
+        let mut snd = forge(TYPE_SND, seqnum, &[2, 0]);
+        snd[1] |= FLAG_PAD;
+        snd.extend_from_slice(b"hi");
+        recv_packet(&peer, &pad(snd));
+
+        // control packets are padded too, unpadded packets are understood
+        peer.sendto(&forge(TYPE_SND, 0, b"yo"), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"yo");
+        recv_packet(&peer, &pad(forge(TYPE_ACK, 0, b"")));
+
+        let mut snd = forge(TYPE_SND, 1, &[3, 0]);
+        snd[1] |= FLAG_PAD;
+        snd.extend_from_slice(b"abc");
+        peer.sendto(&pad(snd), localhost()).unwrap();
+        let n = recv_some(&mut odp, &mut buf);
+        assert_eq!(&buf[..n], b"abc");
+
+        // the length must fit in the packet
+        let mut snd = forge(TYPE_SND, 2, &[200, 0]);
+        snd[1] |= FLAG_PAD;
+        peer.sendto(&snd, localhost()).unwrap();
+        while odp.stats().malformed == 0 {
+            recv_none(&mut odp);
+        }
+    }
+
+    #[test]
+    fn write_would_block() {
+        let com = Arc::new(IcmpCommunicator::with_magic(126, 0x8c).unwrap());
+        let (mut odp, _peer) = accept_forged(ODP::new(com, localhost()), 127, 0x8c);
+        odp.com.set_nonblocking(true).unwrap();
+
+        // a single write fills the window
+        assert_eq!(odp.write(&[0; 4000]).unwrap(), 4000);
+        match odp.write(b"no ack yet") {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            res => panic!("{:?}", res),
+        }
+        match odp.read(&mut [0; 64]) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn fragmentation() {
+        use std::thread;
+
+        let blob: Vec<u8> = (0..50000).map(|i| (i % 251) as u8).collect();
+        let sent = blob.clone();
+
+        let server = thread::spawn(|| {
+            let com = Arc::new(IcmpCommunicator::with_magic(128, 0x8d).unwrap());
+            let mut odp = ODP::new(com, localhost());
+            odp.accept().unwrap();
+            let mut buf = vec![0; 65536];
+            let n = recv_some(&mut odp, &mut buf);
+            buf.truncate(n);
+            // until the FIN
+            while !odp.is_closed() {
+                recv_none(&mut odp);
+            }
+            buf
+        });
+
+        let com = Arc::new(IcmpCommunicator::with_magic(129, 0x8d).unwrap());
+        let mut odp = ODP::new(com, localhost());
+        odp.set_rto(Duration::from_millis(100));
+        odp.connect().unwrap();
+        assert_eq!(odp.send(&sent).unwrap(), sent.len());
+        let last = odp.seqnum.wrapping_sub(1);
+        assert_eq!(odp.sendq.back().map(|p| (p.0, p.1[1])), Some((last, VERSION_BITS)));
+        assert_eq!(odp.sendq.front().map(|p| p.1[1]), Some(VERSION_BITS | FLAG_MORE));
+        odp.shutdown().unwrap();
+
+        assert_eq!(server.join().unwrap(), blob);
+    }
+
+    #[test]
+    fn mux() {
+        extern crate nix;
+        use self::nix::sys::socket::bind;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // peers need their own address, bind them to other loopback addresses
+        let forged = |id: u8, addr: &str| {
+            let com  = IcmpCommunicator::with_magic(id, 0x8e).unwrap();
+            let addr = InetAddr::from_std(&addr.parse().unwrap());
+            bind(*com.rawfd(), &SockAddr::Inet(addr)).unwrap();
+            (com, addr)
+        };
+        let (one, one_addr) = forged(131, "127.0.0.2:0");
+        let (two, two_addr) = forged(132, "127.0.0.3:0");
+        let (bad, _)        = forged(133, "127.0.0.4:0");
+
+        let com = Arc::new(IcmpCommunicator::with_magic(130, 0x8e).unwrap());
+        setsockopt(*com.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+        let seen     = Arc::new(AtomicUsize::new(0));
+        let new_com  = com.clone();
+        let new_seen = seen.clone();
+        let mut mux = OdpMux::new(com, move |peer| {
+            new_seen.fetch_add(1, Ordering::Relaxed);
+            if peer == InetAddr::from_std(&"127.0.0.4:0".parse().unwrap()) {
+                return None;
+            }
+            Some(ODP::new(new_com.clone(), peer))
+        });
+
+        for &(peer, data) in [(&one, b"one"), (&two, b"two"), (&bad, b"bad")].iter() {
+            peer.sendto(&forge(TYPE_SYN, 0, &[0; 4]), localhost()).unwrap();
+            peer.sendto(&forge(TYPE_SND, 0, data),    localhost()).unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0; 64];
+        while received.len() < 2 || seen.load(Ordering::Relaxed) < 3 {
+            if let Some((n, peer)) = mux.recv(&mut buf).expect("nothing received") {
+                received.push((buf[..n].to_vec(), peer.to_std()));
+            }
+        }
+        received.sort();
+        let expected = vec![
+            (b"one".to_vec(), one_addr.to_std()),
+            (b"two".to_vec(), two_addr.to_std()),
+        ];
+        assert_eq!(received, expected);
+
+        assert_eq!(seen.load(Ordering::Relaxed), 3);
+        let mut peers: Vec<_> = mux.peers().iter().map(InetAddr::to_std).collect();
+        peers.sort();
+        assert_eq!(peers, vec![one_addr.to_std(), two_addr.to_std()]);
+        assert!(mux.get(&one_addr).unwrap().is_connected());
+    }
+
+    #[test]
+    fn mux_limits() {
+        use std::collections::VecDeque;
+        use std::sync::Mutex;
+        use std::thread;
+        use transport::Loopback;
+
+        // packets from any source we like, what we send goes nowhere
+        struct Spoofed(Loopback, Mutex<VecDeque<(Vec<u8>, InetAddr)>>);
+        impl Transport for Spoofed {
+            fn sendto(&self, buf: &[u8], _peer: InetAddr) -> result::Result<usize, ICError> {
+                Ok(buf.len())
+            }
+            fn recvfrom(&self, buf: &mut [u8])
+              -> result::Result<Option<(usize, InetAddr)>, ICError> {
+                Ok(self.1.lock().unwrap().pop_front().map(|(pkt, from)| {
+                    buf[..pkt.len()].copy_from_slice(&pkt);
+                    (pkt.len(), from)
+                }))
+            }
+            fn rawfd(&self) -> &RawFd {
+                self.0.rawfd()
+            }
+            fn is_nonblocking(&self) -> result::Result<bool, ICError> {
+                self.0.is_nonblocking()
+            }
+            fn max_payload(&self) -> usize {
+                self.0.max_payload()
+            }
+        }
+
+        let (a, _b) = Loopback::pair().unwrap();
+        let com     = Arc::new(Spoofed(a, Mutex::new(VecDeque::new())));
+        let new_com = com.clone();
+        let mut mux = OdpMux::new(com.clone(), move |peer| Some(ODP::new(new_com.clone(), peer)));
+        mux.set_max_peers(2);
+
+        let from = |port: u16| InetAddr::from_std(&format!("127.0.0.1:{}", port).parse().unwrap());
+        let mut buf = [0; 64];
+        let mut deliver = |mux: &mut OdpMux<Spoofed>, pkt: Vec<u8>, port: u16| {
+            com.1.lock().unwrap().push_back((pkt, from(port)));
+            mux.recv(&mut buf).unwrap();
+            // connections are ordered by age, keep them apart
+            thread::sleep(Duration::from_millis(1));
+        };
+        let peers = |mux: &OdpMux<Spoofed>| {
+            let mut peers: Vec<_> = mux.peers().iter().map(|p| p.port()).collect();
+            peers.sort();
+            peers
+        };
+
+        deliver(&mut mux, forge(TYPE_SYN, 0, &[0; 4]), 1);
+        deliver(&mut mux, forge(TYPE_SYN, 0, &[0; 4]), 2);
+        deliver(&mut mux, forge(TYPE_SND, 0, b"two"),  2);
+        assert_eq!(peers(&mux), vec![1, 2]);
+
+        // full, the new requests take the place of the oldest half open connection
+        deliver(&mut mux, forge(TYPE_SYN, 0, &[0; 4]), 3);
+        assert_eq!(peers(&mux), vec![2, 3]);
+        deliver(&mut mux, forge(TYPE_SYN, 0, &[0; 4]), 4);
+        assert_eq!(peers(&mux), vec![2, 4]);
+
+        // half open for too long
+        mux.set_handshake_timeout(Duration::from_millis(0));
+        let lost = mux.on_timeout(Instant::now()).unwrap();
+        assert_eq!(lost.iter().map(|p| p.port()).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(peers(&mux), vec![2]);
+
+        // full of established connections, requests are ignored
+        mux.set_max_peers(1);
+        deliver(&mut mux, forge(TYPE_SYN, 0, &[0; 4]), 5);
+        assert_eq!(peers(&mux), vec![2]);
+        assert!(mux.get(&from(2)).unwrap().is_connected());
+    }
+
+    // cargo test --release --lib -- --ignored --nocapture ack_bench
+    #[test]
+    #[ignore]
+    fn ack_bench() {
+        const WINDOW: usize = 20_000;
+
+        let com = Arc::new(IcmpCommunicator::with_magic(157, 0x9a).unwrap());
+        let odp = ODP::with_window(com, localhost(), WINDOW).unwrap();
+        let (mut odp, _peer) = accept_forged(odp, 158, 0x9a);
+        let isn = odp.seqnum();
+        for _ in 0..WINDOW {
+            odp.send(b"x").unwrap();
+        }
+
+        // one ack per packet, in order, as they come when nothing is lost
+        let acks: Vec<_> = (0..WINDOW as u64)
+            .map(|i| forge(TYPE_ACK, isn.wrapping_add(i), &[]))
+            .collect();
+        let start = Instant::now();
+        for ack in &acks {
+            odp.handle_packet_(ack, localhost(), &mut []).unwrap();
+        }
+        let secs = start.elapsed().as_secs_f64();
+        assert_eq!(odp.inflight(), 0);
+        println!("{} acks in {:.3}s ({:.0} acks/s)", WINDOW, secs, WINDOW as f64 / secs);
+    }
+
+    #[test]
+    fn thread_safety() {
+        fn is_send<T: Send>() {}
+        fn is_sync<T: Sync>() {}
+        is_send::<ODP>();
+        is_send::<OdpMux>();
+        is_sync::<IcmpCommunicator>();
+    }
+
+    #[test]
+    fn sack() {
+        let com = Arc::new(IcmpCommunicator::with_magic(134, 0x8f).unwrap());
+        let odp = ODP::with_window(com, localhost(), 8).unwrap();
+        let (mut odp, peer) = accept_forged(odp, 135, 0x8f);
+        setsockopt(*peer.rawfd(), sockopt::ReceiveTimeout, &TimeVal::milliseconds(2000)).unwrap();
+
+        // the peer got packets 0, 2 and 4 of 5
+        let isn = odp.seqnum;
+        for i in 0..5 {
+            odp.send(&[i]).unwrap();
+        }
+        let mut ack = forge(TYPE_ACK, isn, &[0; 8]);
+        ack[1] |= FLAG_SACK;
+        LittleEndian::write_u64(&mut ack[PKT_HDR_SIZE..], 0b1010);
+        peer.sendto(&ack, localhost()).unwrap();
+        while odp.ack_wait.len() == 5 {
+            recv_none(&mut odp);
+        }
+        let unacked: Vec<_> = odp.ack_wait.iter().map(|p| p.seqnum.wrapping_sub(isn)).collect();
+        assert_eq!(unacked, vec![1, 3]);
+
+        // we got packets 0, 2 and 3 from the peer
+        for &i in [0, 2, 3].iter() {
+            peer.sendto(&forge(TYPE_SND, i, &[i as u8]), localhost()).unwrap();
+        }
+        recv_some(&mut odp, &mut [0; 64]);
+        let mut agn = forge(TYPE_AGN, 1, &[0; 16]);
+        agn[1] |= FLAG_SACK;
+        LittleEndian::write_u64(&mut agn[10..], 2);
+        LittleEndian::write_u64(&mut agn[18..], 0b11);
+        while odp.reorder.len() < 2 {
+            recv_none(&mut odp);
+        }
+        recv_packet(&peer, &agn);
+    }
 }
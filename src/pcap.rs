@@ -0,0 +1,411 @@
+//! Packet captures in the pcap format, to look at what ODP sends with tcpdump or Wireshark, or to
+//! run it with no network at all. `PcapWriter` is a transport writing the ICMP packets it would
+//! send to a capture instead, with an IPv4 header, and `PcapReader` one receiving the packets of a
+//! capture. Packets are framed as `IcmpCommunicator` does on a raw socket: an echo reply with the
+//! magic and the id as identifier, a sequence number, then a nonce.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+
+extern crate byteorder;
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+extern crate nix;
+
+extern crate icmp_communicator;
+use self::icmp_communicator::{InetAddr, RawFd, ICError};
+
+use transport::{Result, Transport};
+
+// Magic numbers of pcap files, with timestamps in microseconds or nanoseconds
+const PCAP_MAGIC:      u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+
+const PCAP_HDR_SIZE:   usize = 24;
+const RECORD_HDR_SIZE: usize = 16;
+const SNAPLEN:         usize = 65535;
+
+// Link types: what the records start with. Captures made by tcpdump on Linux are mostly Ethernet
+// or "cooked" (SLL), we write raw IP ones.
+const LINKTYPE_ETHERNET:  u32 = 1;
+const LINKTYPE_RAW:       u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4:      u32 = 228;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+const IP_SIZE:    usize = 20;
+const ICMP_SIZE:  usize = 8;
+const NONCE_SIZE: usize = 4;
+
+const IPPROTO_ICMP:      u8 = 1;
+const ICMP_ECHO_REPLY:   u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// A transport writing what is sent to a pcap file rather than to the network. Nothing is ever
+/// received.
+pub struct PcapWriter {
+    out:   Mutex<File>,
+    src:   Ipv4Addr,
+    id:    u8,
+    magic: u8,
+    nonce: [u8; NONCE_SIZE],
+    seq:   AtomicU16,
+    _idle: UnixDatagram, // never readable, the socket of `rawfd`
+    fd:    RawFd,
+}
+
+impl PcapWriter {
+
+    /// Write to a new capture at `path` the packets a communicator with this `id` and `magic`
+    /// would send, from 127.0.0.1.
+    pub fn create<P: AsRef<Path>>(path: P, id: u8, magic: u8) -> Result<PcapWriter> {
+        if id == 0 {
+            return Err(ICError::InvalidId);
+        }
+        let mut out = File::create(path)?;
+        let mut hdr = [0; PCAP_HDR_SIZE];
+        LittleEndian::write_u32(&mut hdr[0..], PCAP_MAGIC);
+        LittleEndian::write_u16(&mut hdr[4..], 2); // version 2.4
+        LittleEndian::write_u16(&mut hdr[6..], 4);
+        LittleEndian::write_u32(&mut hdr[16..], SNAPLEN as u32);
+        LittleEndian::write_u32(&mut hdr[20..], LINKTYPE_RAW);
+        out.write_all(&hdr)?;
+
+        let idle = UnixDatagram::unbound()?;
+        let fd   = idle.as_raw_fd();
+        Ok(PcapWriter {
+            out:   Mutex::new(out),
+            src:   Ipv4Addr::LOCALHOST,
+            id,
+            magic,
+            // not random, so that the same traffic gives the same capture
+            nonce: [id, magic, b'P', b'C'],
+            seq:   AtomicU16::new(0),
+            _idle: idle,
+            fd,
+        })
+    }
+
+    /// Source address of the packets written
+    pub fn with_source(mut self, src: Ipv4Addr) -> PcapWriter {
+        self.src = src;
+        self
+    }
+
+    // The IPv4 packet carrying `buf` to `dst`
+    fn packet_(&self, buf: &[u8], dst: Ipv4Addr) -> Vec<u8> {
+        let total = IP_SIZE + ICMP_SIZE + NONCE_SIZE + buf.len();
+        let mut pkt = vec![0; IP_SIZE];
+        pkt[0] = 0x45; // IPv4, 5 words long header
+        BigEndian::write_u16(&mut pkt[2..], total as u16);
+        pkt[8] = 64;   // TTL
+        pkt[9] = IPPROTO_ICMP;
+        pkt[12..16].copy_from_slice(&self.src.octets());
+        pkt[16..20].copy_from_slice(&dst.octets());
+        let accum = checksum(&pkt);
+        BigEndian::write_u16(&mut pkt[10..], accum);
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        pkt.extend_from_slice(&[ICMP_ECHO_REPLY, 0, 0, 0, self.magic, self.id]);
+        pkt.extend_from_slice(&seq.to_be_bytes());
+        pkt.extend_from_slice(&self.nonce);
+        pkt.extend_from_slice(buf);
+        let accum = checksum(&pkt[IP_SIZE..]);
+        BigEndian::write_u16(&mut pkt[IP_SIZE+2..], accum);
+        pkt
+    }
+}
+
+impl Transport for PcapWriter {
+    fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        let dst = match peer.to_std().ip() {
+            IpAddr::V4(ip) => ip,
+            _ => return Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAFNOSUPPORT))),
+        };
+        let pkt = self.packet_(buf, dst);
+        if pkt.len() > SNAPLEN {
+            return Err(ICError::Nix(nix::Error::Sys(nix::Errno::EMSGSIZE)));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = vec![0; RECORD_HDR_SIZE];
+        LittleEndian::write_u32(&mut record[0..], now.as_secs() as u32);
+        LittleEndian::write_u32(&mut record[4..], now.subsec_micros());
+        LittleEndian::write_u32(&mut record[8..], pkt.len() as u32);
+        LittleEndian::write_u32(&mut record[12..], pkt.len() as u32);
+        record.extend_from_slice(&pkt);
+        self.out.lock().unwrap().write_all(&record)?;
+        Ok(buf.len())
+    }
+
+    fn recvfrom(&self, _buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN)))
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        &self.fd
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn max_payload(&self) -> usize {
+        SNAPLEN - self.overhead()
+    }
+
+    fn overhead(&self) -> usize {
+        IP_SIZE + ICMP_SIZE + NONCE_SIZE
+    }
+}
+
+
+/// A transport receiving the packets of a pcap file carrying the given magic, in order, as fast
+/// as they are read. Once they all were, `recvfrom` fails with EAGAIN. Sending does nothing.
+pub struct PcapReader {
+    pkts:  Mutex<VecDeque<Vec<u8>>>, // IP packets
+    magic: u8,
+    ready: (UnixDatagram, UnixDatagram), // a datagram waits in the second while `pkts` has some
+    fd:    RawFd,
+}
+
+impl PcapReader {
+
+    /// Read the capture at `path`. Its records may be raw IP packets, Ethernet frames or Linux
+    /// "cooked" ones, only IPv4 ICMP is received.
+    pub fn open<P: AsRef<Path>>(path: P, magic: u8) -> Result<PcapReader> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let pkts = records(&data).ok_or_else(|| invalid("not a pcap file, or truncated"))?;
+        let pkts: VecDeque<_> = pkts?;
+
+        let ready = UnixDatagram::pair()?;
+        if !pkts.is_empty() {
+            ready.0.send(&[0])?;
+        }
+        let fd = ready.1.as_raw_fd();
+        Ok(PcapReader { pkts: Mutex::new(pkts), magic, ready, fd })
+    }
+
+    /// Packets left to receive, ours or not
+    pub fn remaining(&self) -> usize {
+        self.pkts.lock().unwrap().len()
+    }
+}
+
+impl Transport for PcapReader {
+    fn sendto(&self, buf: &[u8], _peer: InetAddr) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        let mut pkts = self.pkts.lock().unwrap();
+        let pkt = match pkts.pop_front() {
+            Some(pkt) => pkt,
+            None      => return Err(ICError::Nix(nix::Error::Sys(nix::Errno::EAGAIN))),
+        };
+        if pkts.is_empty() {
+            self.ready.1.recv(&mut [0])?;
+        }
+
+        let (data, src) = match icmp_data(&pkt, self.magic) {
+            Some(msg) => msg,
+            None      => return Ok(None),
+        };
+        let copysize = cmp::min(buf.len(), data.len());
+        buf[..copysize].copy_from_slice(&data[..copysize]);
+        Ok(Some((data.len(), src)))
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        &self.fd
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn max_payload(&self) -> usize {
+        SNAPLEN - self.overhead()
+    }
+
+    fn overhead(&self) -> usize {
+        IP_SIZE + ICMP_SIZE + NONCE_SIZE
+    }
+}
+
+fn invalid(what: &str) -> ICError {
+    ICError::Io(io::Error::new(io::ErrorKind::InvalidData, what))
+}
+
+// The IP packets of the capture `data`, `None` if it is malformed and an error if its link type
+// is not supported
+fn records(data: &[u8]) -> Option<Result<VecDeque<Vec<u8>>>> {
+    let hdr = data.get(..PCAP_HDR_SIZE)?;
+    // written in the byte order of the host that made it
+    let big = match LittleEndian::read_u32(hdr) {
+        PCAP_MAGIC | PCAP_MAGIC_NSEC => false,
+        _ => match BigEndian::read_u32(hdr) {
+            PCAP_MAGIC | PCAP_MAGIC_NSEC => true,
+            _                            => return None,
+        },
+    };
+    let read_u32 = |b: &[u8]| if big { BigEndian::read_u32(b) } else { LittleEndian::read_u32(b) };
+
+    let linktype = read_u32(&hdr[20..]);
+    let mut pkts = VecDeque::new();
+    let mut off  = PCAP_HDR_SIZE;
+    while off < data.len() {
+        let rec = data.get(off..off + RECORD_HDR_SIZE)?;
+        let len = read_u32(&rec[8..]) as usize;
+        let end = off + RECORD_HDR_SIZE + len;
+        let frame = data.get(off + RECORD_HDR_SIZE..end)?;
+        match ip_packet(linktype, frame) {
+            Ok(Some(pkt)) => pkts.push_back(pkt.to_vec()),
+            Ok(None)      => {}
+            Err(e)        => return Some(Err(e)),
+        }
+        off = end;
+    }
+    Some(Ok(pkts))
+}
+
+// The IPv4 packet in the frame of a record, `None` if it holds something else
+fn ip_packet(linktype: u32, frame: &[u8]) -> Result<Option<&[u8]>> {
+    let (ethertype, start) = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => return Ok(Some(frame)),
+        LINKTYPE_ETHERNET  => (12, 14),
+        LINKTYPE_LINUX_SLL => (14, 16),
+        _ => return Err(invalid("unsupported pcap link type")),
+    };
+    match frame.get(ethertype..ethertype+2) {
+        Some(t) if BigEndian::read_u16(t) == ETHERTYPE_IPV4 => Ok(Some(&frame[start..])),
+        _                                                   => Ok(None),
+    }
+}
+
+// The user data of an ICMP packet framed as by a communicator with `magic`, and its source
+fn icmp_data(pkt: &[u8], magic: u8) -> Option<(&[u8], InetAddr)> {
+    if pkt.len() < IP_SIZE || pkt[0] >> 4 != 4 || pkt[9] != IPPROTO_ICMP {
+        return None;
+    }
+    let ip_size = (pkt[0] & 0x0f) as usize * 4;
+    let total   = cmp::min(BigEndian::read_u16(&pkt[2..]) as usize, pkt.len());
+    let icmp    = pkt.get(ip_size..total)?;
+    if icmp.len() < ICMP_SIZE + NONCE_SIZE {
+        return None;
+    }
+    if icmp[0] != ICMP_ECHO_REPLY && icmp[0] != ICMP_ECHO_REQUEST {
+        return None;
+    }
+    if icmp[4] != magic || icmp[5] == 0 || checksum(icmp) != 0 {
+        return None;
+    }
+    let src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+    let src = InetAddr::from_std(&SocketAddr::new(IpAddr::V4(src), 0));
+    Some((&icmp[ICMP_SIZE + NONCE_SIZE..], src))
+}
+
+// Internet checksum (RFC 1071), as `IcmpCommunicator` computes it
+fn checksum(data: &[u8]) -> u16 {
+    let mut accum: u64 = 0;
+    for word in data.chunks(2) {
+        let lo = if word.len() == 2 { word[1] } else { 0 };
+        accum += (word[0] as u64) << 8 | lo as u64;
+    }
+    while (accum >> 16) > 0 {
+        accum = (accum & 0xFFFF) + (accum >> 16);
+    }
+    !accum as u16
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn localhost() -> InetAddr {
+        InetAddr::from_std(&"127.0.0.1:0".parse().unwrap())
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("icmp_tunnel-{}-{}.pcap", name, process::id()))
+    }
+
+    #[test]
+    fn write_and_replay() {
+        let path = temp_path("replay");
+        let dst  = InetAddr::from_std(&"10.0.0.2:0".parse().unwrap());
+        {
+            let w = PcapWriter::create(&path, 7, 0x42).unwrap();
+            assert_eq!(w.sendto(b"hello", dst).unwrap(), 5);
+            assert_eq!(w.sendto(b"", localhost()).unwrap(), 0);
+            let w = w.with_source(Ipv4Addr::new(10, 0, 0, 1));
+            w.sendto(&[3; 1000], dst).unwrap();
+            assert!(w.recvfrom(&mut [0; 16]).is_err());
+        }
+        let data = fs::read(&path).unwrap();
+        // the header, then records whose IP and ICMP checksums are right
+        assert_eq!(LittleEndian::read_u32(&data[20..]), LINKTYPE_RAW);
+        let pkt = &data[PCAP_HDR_SIZE + RECORD_HDR_SIZE..][..IP_SIZE + ICMP_SIZE + NONCE_SIZE + 5];
+        assert_eq!(checksum(&pkt[..IP_SIZE]), 0);
+        assert_eq!(checksum(&pkt[IP_SIZE..]), 0);
+        assert_eq!(&pkt[16..20], &[10, 0, 0, 2]);
+
+        let r = PcapReader::open(&path, 0x42).unwrap();
+        assert_eq!((r.remaining(), r.max_payload()), (3, SNAPLEN - 32));
+        let mut buf = [0; 16];
+        assert!(r.recvfrom(&mut buf).unwrap() == Some((5, localhost())));
+        assert_eq!(&buf[..5], b"hello");
+        assert!(r.recvfrom(&mut buf).unwrap() == Some((0, localhost())));
+        let src = InetAddr::from_std(&"10.0.0.1:0".parse().unwrap());
+        assert!(r.recvfrom(&mut buf).unwrap() == Some((1000, src)));
+        assert!(r.recvfrom(&mut buf).is_err());
+
+        // another magic: other ICMP traffic
+        let r = PcapReader::open(&path, 0x43).unwrap();
+        assert!(r.recvfrom(&mut buf).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn link_types() {
+        let ip = [0x45; IP_SIZE];
+        let mut eth = vec![0; 14];
+        eth[12] = 0x08;
+        eth.extend_from_slice(&ip);
+        assert_eq!(ip_packet(LINKTYPE_ETHERNET, &eth).unwrap(), Some(&ip[..]));
+        eth[12] = 0x86; // IPv6
+        assert_eq!(ip_packet(LINKTYPE_ETHERNET, &eth).unwrap(), None);
+        let mut sll = vec![0; 16];
+        sll[14] = 0x08;
+        sll.extend_from_slice(&ip);
+        assert_eq!(ip_packet(LINKTYPE_LINUX_SLL, &sll).unwrap(), Some(&ip[..]));
+        assert!(ip_packet(LINKTYPE_IPV4, &ip).unwrap() == Some(&ip[..]));
+        assert!(ip_packet(147, &ip).is_err());
+
+        // malformed captures
+        assert!(records(b"not a capture").is_none());
+        let path = temp_path("truncated");
+        PcapWriter::create(&path, 1, 0x42).unwrap().sendto(b"data", localhost()).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records(&data).unwrap().unwrap().len(), 1);
+        data.pop();
+        assert!(records(&data).is_none());
+    }
+}
@@ -1,7 +1,123 @@
 extern crate nix;
 use self::nix::unistd::{setgid, getgid, setuid, getuid};
+#[cfg(all(feature = "caps", target_os = "linux"))]
+use self::nix::unistd::geteuid;
+use self::nix::libc::{self, c_char, gid_t, uid_t};
+use self::nix::Errno;
 
-pub fn drop_privs() {
-    setgid(getgid()).expect("Could not drop privileges");
-    setuid(getuid()).expect("Could not drop privileges");
+use std::ffi::CString;
+#[cfg(all(feature = "caps", target_os = "linux"))]
+use std::fs::File;
+#[cfg(all(feature = "caps", target_os = "linux"))]
+use std::io::{BufRead, BufReader};
+use std::mem;
+use std::ptr;
+
+/// Drop the privileges of a setuid/setgid binary, going back to the real user and group.
+pub fn drop_privs() -> Result<(), nix::Error> {
+    setgid(getgid())?;
+    setuid(getuid())
+}
+
+/// Become `user`, with `group` or else the primary group of `user`, and the supplementary groups
+/// of `user`. Meant for a process started as root once it opened its sockets. Unknown names are
+/// reported as `EINVAL`.
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<(), nix::Error> {
+    let name = CString::new(user).map_err(|_| nix::Error::Sys(Errno::EINVAL))?;
+    let (uid, user_gid) = lookup_user(&name)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None        => user_gid,
+    };
+
+    // the group first, we can't change it anymore once we gave up root
+    Errno::result(unsafe { libc::initgroups(name.as_ptr(), gid) })?;
+    setgid(gid)?;
+    setuid(uid)
+}
+
+/// Whether CAP_NET_RAW is in the effective capabilities of the process, i.e. it can open raw
+/// sockets without being root, e.g. if the binary was granted it with `setcap cap_net_raw+ep`.
+#[cfg(all(feature = "caps", target_os = "linux"))]
+pub fn has_net_raw() -> bool {
+    const CAP_NET_RAW: u32 = 13;
+
+    let status = match File::open("/proc/self/status") {
+        Ok(f)  => BufReader::new(f),
+        Err(_) => return false,
+    };
+    status.lines()
+        .map_while(|line| line.ok())
+        .find(|line| line.starts_with("CapEff:"))
+        .and_then(|line| u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_NET_RAW) != 0)
+}
+
+/// Give up all the capabilities of the process.
+#[cfg(all(feature = "caps", target_os = "linux"))]
+pub fn drop_caps() -> Result<(), nix::Error> {
+    // see capset(2)
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid:     libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CapData {
+        effective:   u32,
+        permitted:   u32,
+        inheritable: u32,
+    }
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapData { effective: 0, permitted: 0, inheritable: 0 }; 2];
+    let res  = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    Errno::result(res).map(drop)
+}
+
+/// Give up what was needed to open the raw socket. A process granted CAP_NET_RAW drops its
+/// capabilities and keeps its user, a setuid one goes back to its real user like `drop_privs`.
+#[cfg(all(feature = "caps", target_os = "linux"))]
+pub fn drop_net_raw() -> Result<(), nix::Error> {
+    if geteuid() != 0 && has_net_raw() {
+        drop_caps()
+    } else {
+        drop_privs()
+    }
+}
+
+// Size of the buffer for the strings of the passwd and group entries
+const ENTRY_BUFSIZE: usize = 16384;
+
+fn lookup_user(name: &CString) -> Result<(uid_t, gid_t), nix::Error> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let mut buf = vec![0 as c_char; ENTRY_BUFSIZE];
+
+    let err = unsafe {
+        libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut res)
+    };
+    match (err, res.is_null()) {
+        (0, false) => Ok((pwd.pw_uid, pwd.pw_gid)),
+        (0, true)  => Err(nix::Error::Sys(Errno::EINVAL)),
+        (e, _)     => Err(nix::Error::Sys(Errno::from_i32(e))),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<gid_t, nix::Error> {
+    let name    = CString::new(group).map_err(|_| nix::Error::Sys(Errno::EINVAL))?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let mut buf = vec![0 as c_char; ENTRY_BUFSIZE];
+
+    let err = unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut res)
+    };
+    match (err, res.is_null()) {
+        (0, false) => Ok(grp.gr_gid),
+        (0, true)  => Err(nix::Error::Sys(Errno::EINVAL)),
+        (e, _)     => Err(nix::Error::Sys(Errno::from_i32(e))),
+    }
 }
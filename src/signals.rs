@@ -0,0 +1,30 @@
+//! Termination requests for the binaries: they close the tunnel on SIGINT or SIGTERM rather
+//! than leaving the peer waiting for the rest of the data.
+
+extern crate nix;
+use self::nix::libc::c_int;
+use self::nix::sys::signal::{sigaction, SigAction, SigHandler, SigSet, Signal, SA_RESETHAND};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGINT and SIGTERM. The first one only sets the flag read by `terminating` and
+/// interrupts the blocking call in progress, if any (`EINTR`), the next one of the same kind
+/// kills the process as usual.
+pub fn catch_termination() -> Result<(), nix::Error> {
+    let action = SigAction::new(SigHandler::Handler(on_signal), SA_RESETHAND, SigSet::empty());
+    for &sig in [Signal::SIGINT, Signal::SIGTERM].iter() {
+        unsafe { sigaction(sig, &action)?; }
+    }
+    Ok(())
+}
+
+/// Whether SIGINT or SIGTERM was received since `catch_termination`
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
@@ -0,0 +1,424 @@
+//! What ODP needs from the link it runs over, so that it can be tested over links which misbehave
+//! on purpose, or without raw sockets. `IcmpCommunicator` is the real one.
+
+use std::cmp;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::result;
+use std::time::{Duration, Instant};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+
+extern crate nix;
+use self::nix::fcntl::{fcntl, FcntlArg, OFlag, O_NONBLOCK};
+
+extern crate icmp_communicator;
+use self::icmp_communicator::{DropReason, IcmpCommunicator, InetAddr, RawFd, RecvOutcome, ICError};
+
+pub type Result<T> = result::Result<T, ICError>;
+
+/// Sends and receives datagrams to and from peers, without any guarantee.
+pub trait Transport {
+
+    /// Send `buf` to `peer`, return how much of it was sent. Datagrams are sent whole: ODP takes
+    /// less than `buf.len()` for a failure, as `ICError::ShortWrite`.
+    fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize>;
+
+    /// Receive a datagram in `buf`, return its size (which may be larger than `buf`, the rest
+    /// is lost) and where it comes from. `None` if something that isn't for us was received.
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>>;
+
+    /// Like `recvfrom`, but also report the ICMP errors about what we sent. Transports which
+    /// can't tell what they drop call it `NotOurs`.
+    fn recvfrom_reason(&self, buf: &mut [u8]) -> Result<RecvOutcome> {
+        Ok(match self.recvfrom(buf)? {
+            Some((n, peer)) => RecvOutcome::Message(n, peer),
+            None            => RecvOutcome::Dropped(DropReason::NotOurs),
+        })
+    }
+
+    /// File descriptor that polls readable when `recvfrom` has something for us.
+    fn rawfd(&self) -> &RawFd;
+
+    /// Whether `recvfrom` fails with EAGAIN rather than blocking.
+    fn is_nonblocking(&self) -> Result<bool>;
+
+    /// Largest datagram `recvfrom` receives whole.
+    fn max_payload(&self) -> usize;
+
+    /// Bytes added to each datagram on the wire.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// How long until `sendto` may send again when a rate limit holds it back.
+    fn pace_delay(&self) -> Duration {
+        Duration::from_millis(0)
+    }
+
+    fn set_path_mtu_discovery(&self, _on: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// MTU of the path to `peer`, if there is such a thing.
+    fn path_mtu(&self, _peer: InetAddr) -> Result<usize> {
+        Err(ICError::Unknown)
+    }
+
+    /// Let the kernel queue up to `size` bytes of received datagrams, if it queues them at all.
+    fn set_recv_bufsize(&self, _size: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for IcmpCommunicator {
+    fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        IcmpCommunicator::sendto(self, buf, peer)
+    }
+
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        IcmpCommunicator::recvfrom(self, buf)
+    }
+
+    fn recvfrom_reason(&self, buf: &mut [u8]) -> Result<RecvOutcome> {
+        IcmpCommunicator::recvfrom_reason(self, buf)
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        IcmpCommunicator::rawfd(self)
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        IcmpCommunicator::is_nonblocking(self)
+    }
+
+    fn max_payload(&self) -> usize {
+        IcmpCommunicator::max_payload(self)
+    }
+
+    fn overhead(&self) -> usize {
+        IcmpCommunicator::overhead(self)
+    }
+
+    fn pace_delay(&self) -> Duration {
+        IcmpCommunicator::pace_delay(self)
+    }
+
+    fn set_path_mtu_discovery(&self, on: bool) -> Result<()> {
+        IcmpCommunicator::set_path_mtu_discovery(self, on)
+    }
+
+    fn path_mtu(&self, peer: InetAddr) -> Result<usize> {
+        IcmpCommunicator::path_mtu(self, peer)
+    }
+
+    fn set_recv_bufsize(&self, size: usize) -> Result<()> {
+        IcmpCommunicator::set_recv_bufsize(self, size)
+    }
+}
+
+
+// Largest datagram a `Loopback` carries
+const LOOPBACK_MAX_PAYLOAD: usize = 65536;
+
+/// One end of an in-memory link to another, in the same process: what is sent at one end is
+/// received at the other, whatever the address given, and comes from 127.0.0.1. No privileges
+/// are needed, unlike with `IcmpCommunicator`.
+pub struct Loopback {
+    sock: UnixDatagram,
+    fd:   RawFd,
+}
+
+impl Loopback {
+
+    /// Both ends of a new link
+    pub fn pair() -> Result<(Loopback, Loopback)> {
+        let (a, b) = UnixDatagram::pair()?;
+        Ok((Loopback::from_sock(a), Loopback::from_sock(b)))
+    }
+
+    fn from_sock(sock: UnixDatagram) -> Loopback {
+        let fd = sock.as_raw_fd();
+        Loopback { sock, fd }
+    }
+
+    /// Address the other end appears to have
+    pub fn peer(&self) -> InetAddr {
+        InetAddr::from_std(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.sock.set_nonblocking(nonblocking)?)
+    }
+}
+
+impl Transport for Loopback {
+    fn sendto(&self, buf: &[u8], _peer: InetAddr) -> Result<usize> {
+        Ok(self.sock.send(buf)?)
+    }
+
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        // the whole datagram, to tell its size as sockets do
+        let mut data = vec![0; LOOPBACK_MAX_PAYLOAD];
+        let n = self.sock.recv(&mut data)?;
+        let copysize = cmp::min(n, buf.len());
+        buf[..copysize].copy_from_slice(&data[..copysize]);
+        Ok(Some((n, self.peer())))
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        &self.fd
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        let flags = fcntl(self.fd, FcntlArg::F_GETFL).map_err(ICError::Nix)?;
+        Ok(OFlag::from_bits_truncate(flags).contains(O_NONBLOCK))
+    }
+
+    fn max_payload(&self) -> usize {
+        LOOPBACK_MAX_PAYLOAD
+    }
+}
+
+
+// Datagram held back by `Lossy`
+type Held = (Vec<u8>, InetAddr);
+
+/// A transport losing, duplicating, reordering and delaying what is sent over another one, each
+/// with a given probability, to test how ODP copes with a bad link. The same seed makes the same
+/// choices for the same sequence of datagrams.
+pub struct Lossy<T> {
+    inner:     Arc<T>,
+    drop:      f64,
+    duplicate: f64,
+    reorder:   f64,
+    delay:     f64,
+    latency:   Range<Duration>,
+    rng:       Mutex<u64>,
+    held:      Mutex<Option<Held>>, // sent after the next datagram
+    delayed:   Mutex<Option<Sender<(Instant, Held)>>>, // to the thread sending them
+}
+
+impl<T: Transport + Send + Sync + 'static> Lossy<T> {
+
+    /// Send over `inner` without any trouble until told otherwise.
+    pub fn new(inner: Arc<T>, seed: u64) -> Lossy<T> {
+        Lossy {
+            inner,
+            drop:      0.0,
+            duplicate: 0.0,
+            reorder:   0.0,
+            delay:     0.0,
+            latency:   Duration::from_millis(0)..Duration::from_millis(0),
+            rng:       Mutex::new(seed | 1), // xorshift never leaves 0
+            held:      Mutex::new(None),
+            delayed:   Mutex::new(None),
+        }
+    }
+
+    /// Lose datagrams with probability `p`.
+    pub fn with_drop(mut self, p: f64) -> Lossy<T> {
+        self.drop = p;
+        self
+    }
+
+    /// Send datagrams twice with probability `p`.
+    pub fn with_duplicate(mut self, p: f64) -> Lossy<T> {
+        self.duplicate = p;
+        self
+    }
+
+    /// Hold datagrams back with probability `p`, until the next one was sent.
+    pub fn with_reorder(mut self, p: f64) -> Lossy<T> {
+        self.reorder = p;
+        self
+    }
+
+    /// Send datagrams after a delay picked in `latency` with probability `p`. Delayed datagrams
+    /// are sent in order from another thread, the others may overtake them.
+    pub fn with_delay(mut self, p: f64, latency: Range<Duration>) -> Lossy<T> {
+        self.delay   = p;
+        self.latency = latency;
+        self
+    }
+
+    pub fn inner(&self) -> &Arc<T> {
+        &self.inner
+    }
+
+    // Uniform in [0, 1), from xorshift64
+    fn random_(&self) -> f64 {
+        let mut x = self.rng.lock().unwrap();
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        (*x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance_(&self, p: f64) -> bool {
+        p > 0.0 && self.random_() < p
+    }
+
+    fn send_(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        if !self.chance_(self.delay) {
+            return self.inner.sendto(buf, peer);
+        }
+        let spread = self.latency.end.saturating_sub(self.latency.start);
+        let delay  = self.latency.start + spread.mul_f64(self.random_());
+
+        let mut delayed = self.delayed.lock().unwrap();
+        if delayed.is_none() {
+            let (tx, rx) = mpsc::channel::<(Instant, Held)>();
+            let inner = self.inner.clone();
+            // ends once we are dropped along with the sender, what it has left is lost
+            thread::spawn(move || {
+                for (due, (buf, peer)) in rx {
+                    let now = Instant::now();
+                    if due > now {
+                        thread::sleep(due - now);
+                    }
+                    let _ = inner.sendto(&buf, peer);
+                }
+            });
+            *delayed = Some(tx);
+        }
+        let due = Instant::now() + delay;
+        if let Some(ref tx) = *delayed {
+            let _ = tx.send((due, (buf.to_vec(), peer)));
+        }
+        Ok(buf.len())
+    }
+}
+
+impl<T: Transport + Send + Sync + 'static> Transport for Lossy<T> {
+    fn sendto(&self, buf: &[u8], peer: InetAddr) -> Result<usize> {
+        if self.chance_(self.drop) {
+            return Ok(buf.len());
+        }
+        let held = self.held.lock().unwrap().take();
+        if held.is_none() && self.chance_(self.reorder) {
+            *self.held.lock().unwrap() = Some((buf.to_vec(), peer));
+            return Ok(buf.len());
+        }
+
+        let n = self.send_(buf, peer)?;
+        if self.chance_(self.duplicate) {
+            self.send_(buf, peer)?;
+        }
+        if let Some((buf, peer)) = held {
+            self.send_(&buf, peer)?;
+        }
+        Ok(n)
+    }
+
+    fn recvfrom(&self, buf: &mut [u8]) -> Result<Option<(usize, InetAddr)>> {
+        self.inner.recvfrom(buf)
+    }
+
+    fn recvfrom_reason(&self, buf: &mut [u8]) -> Result<RecvOutcome> {
+        self.inner.recvfrom_reason(buf)
+    }
+
+    fn rawfd(&self) -> &RawFd {
+        self.inner.rawfd()
+    }
+
+    fn is_nonblocking(&self) -> Result<bool> {
+        self.inner.is_nonblocking()
+    }
+
+    fn max_payload(&self) -> usize {
+        self.inner.max_payload()
+    }
+
+    fn overhead(&self) -> usize {
+        self.inner.overhead()
+    }
+
+    fn pace_delay(&self) -> Duration {
+        self.inner.pace_delay()
+    }
+
+    fn set_path_mtu_discovery(&self, on: bool) -> Result<()> {
+        self.inner.set_path_mtu_discovery(on)
+    }
+
+    fn path_mtu(&self, peer: InetAddr) -> Result<usize> {
+        self.inner.path_mtu(peer)
+    }
+
+    fn set_recv_bufsize(&self, size: usize) -> Result<()> {
+        self.inner.set_recv_bufsize(size)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn localhost() -> InetAddr {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        InetAddr::from_std(&addr)
+    }
+
+    // Messages `rcv` gets within `wait`
+    fn received(rcv: &IcmpCommunicator, wait: Duration) -> Vec<Vec<u8>> {
+        rcv.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + wait;
+        let mut buf  = [0; 64];
+        let mut msgs = Vec::new();
+        while Instant::now() < deadline {
+            if let Ok(Some((n, _))) = rcv.recvfrom(&mut buf) {
+                msgs.push(buf[..n].to_vec());
+            }
+        }
+        msgs
+    }
+
+    #[test]
+    fn loopback() {
+        let (a, b) = Loopback::pair().unwrap();
+        a.sendto(b"from a", a.peer()).unwrap();
+        b.sendto(&[1; 100], b.peer()).unwrap();
+
+        let mut buf = [0; 10];
+        assert!(b.recvfrom(&mut buf).unwrap() == Some((6, localhost())));
+        assert_eq!(&buf[..6], b"from a");
+        // too large for the buffer, the size still tells
+        assert!(a.recvfrom(&mut buf).unwrap() == Some((100, localhost())));
+
+        assert!(!a.is_nonblocking().unwrap());
+        a.set_nonblocking(true).unwrap();
+        assert!(a.is_nonblocking().unwrap());
+        assert!(a.recvfrom(&mut buf).is_err());
+    }
+
+    #[test]
+    fn lossy() {
+        let rcv = IcmpCommunicator::with_magic(166, 0x9e).unwrap();
+        let com = Arc::new(IcmpCommunicator::with_magic(165, 0x9e).unwrap());
+
+        let lossy = Lossy::new(com.clone(), 1).with_drop(1.0);
+        lossy.sendto(b"lost", localhost()).unwrap();
+
+        let lossy = Lossy::new(com.clone(), 1).with_duplicate(1.0);
+        lossy.sendto(b"twice", localhost()).unwrap();
+        assert_eq!(received(&rcv, Duration::from_millis(50)), [b"twice", b"twice"]);
+
+        let lossy = Lossy::new(com.clone(), 1).with_reorder(1.0);
+        lossy.sendto(b"one", localhost()).unwrap();
+        lossy.sendto(b"two", localhost()).unwrap();
+        assert_eq!(received(&rcv, Duration::from_millis(50)), [b"two", b"one"]);
+
+        let latency = Duration::from_millis(100)..Duration::from_millis(100);
+        let lossy   = Lossy::new(com, 1).with_delay(1.0, latency);
+        lossy.sendto(b"late", localhost()).unwrap();
+        assert!(received(&rcv, Duration::from_millis(50)).is_empty());
+        assert_eq!(received(&rcv, Duration::from_millis(150)), [b"late"]);
+    }
+}